@@ -0,0 +1,162 @@
+//! Measures `GET /api/services` latency while services are being deployed.
+//!
+//! Start Longshoreman, make sure the image below is present locally (`docker pull
+//! busybox`), then run:
+//!
+//! ```sh
+//! cargo run --release --example load_test
+//! ```
+//!
+//! It first samples list latency on an idle server, then again while `DEPLOYERS`
+//! clients each create, recreate and delete their own service in a loop. The run fails
+//! if the p95 under load exceeds `MAX_SLOWDOWN` times the idle p95 (with a 50ms floor
+//! so noise on a fast machine doesn't count).
+//!
+//! Environment: `LONGSHOREMAN_TOKEN` (required: a login JWT or an admin API token),
+//! `LONGSHOREMAN_URL` (default `http://localhost:3000`), `DEPLOYERS` (8), `ROUNDS` (3),
+//! `IMAGE` (`busybox:latest`), `MAX_SLOWDOWN` (5).
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn env<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+async fn list_latency(client: &reqwest::Client, url: &str) -> Result<Duration> {
+    let started = Instant::now();
+    client.get(format!("{}/api/services", url)).send().await?.error_for_status()?;
+    Ok(started.elapsed())
+}
+
+async fn deploy(client: reqwest::Client, url: String, image: String, index: usize, rounds: usize) -> Result<()> {
+    let name = format!("load-test-{}", index);
+    let spec = |round: usize| {
+        json!({
+            "name": name,
+            "image": image,
+            "command": ["sleep", "3600"],
+            "env": [format!("ROUND={}", round)],
+        })
+    };
+
+    for round in 0..rounds {
+        let created: Value = client
+            .post(format!("{}/api/services", url))
+            .json(&spec(round))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let id = created["id"].as_str().ok_or("created service has no id")?.to_string();
+
+        client
+            .put(format!("{}/api/services/{}", url, id))
+            .json(&spec(round + 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        client
+            .delete(format!("{}/api/services/{}", url, id))
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn report(label: &str, samples: &mut [Duration]) -> Duration {
+    samples.sort();
+    let p95 = percentile(samples, 0.95);
+    println!(
+        "{:<12} n={:<5} p50={:>8.2?} p95={:>8.2?} p99={:>8.2?} max={:>8.2?}",
+        label,
+        samples.len(),
+        percentile(samples, 0.5),
+        p95,
+        percentile(samples, 0.99),
+        samples.last().copied().unwrap_or_default()
+    );
+    p95
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let url: String = env("LONGSHOREMAN_URL", "http://localhost:3000".to_string());
+    let deployers: usize = env("DEPLOYERS", 8);
+    let rounds: usize = env("ROUNDS", 3);
+    let image: String = env("IMAGE", "busybox:latest".to_string());
+    let max_slowdown: u32 = env("MAX_SLOWDOWN", 5);
+    let token = std::env::var("LONGSHOREMAN_TOKEN").map_err(|_| "LONGSHOREMAN_TOKEN is not set")?;
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token))?);
+    let client = reqwest::Client::builder().default_headers(headers).build()?;
+
+    let mut idle = Vec::new();
+    for _ in 0..200 {
+        idle.push(list_latency(&client, &url).await?);
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let client = client.clone();
+        let url = url.clone();
+        let done = done.clone();
+        tokio::spawn(async move {
+            let mut samples = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                samples.push(list_latency(&client, &url).await?);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(samples)
+        })
+    };
+
+    let started = Instant::now();
+    let deploys: Vec<_> = (0..deployers)
+        .map(|i| tokio::spawn(deploy(client.clone(), url.clone(), image.clone(), i, rounds)))
+        .collect();
+    let mut failures = 0;
+    for deploy in deploys {
+        if let Err(e) = deploy.await? {
+            eprintln!("deploy failed: {}", e);
+            failures += 1;
+        }
+    }
+    done.store(true, Ordering::Relaxed);
+    let mut loaded = sampler.await??;
+
+    println!(
+        "{} deployers x {} rounds in {:.2?} ({} failed)",
+        deployers,
+        rounds,
+        started.elapsed(),
+        failures
+    );
+    let idle_p95 = report("idle", &mut idle);
+    let loaded_p95 = report("deploying", &mut loaded);
+
+    let limit = (idle_p95 * max_slowdown).max(Duration::from_millis(50));
+    if loaded_p95 > limit {
+        return Err(format!("list p95 rose to {:.2?} during deploys (limit {:.2?})", loaded_p95, limit).into());
+    }
+    if failures > 0 {
+        return Err(format!("{} deployers failed", failures).into());
+    }
+    println!("list latency stayed within {:.2?}", limit);
+    Ok(())
+}
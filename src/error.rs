@@ -25,10 +25,27 @@ pub enum AppError {
 
     #[error("Bcrypt error: {0}")]
     Bcrypt(#[from] bcrypt::BcryptError),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
 }
 
 impl gotcha::Responder for AppError {
     fn into_response(self) -> gotcha::axum::response::Response {
-        (gotcha::axum::http::StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        let status = match self {
+            AppError::Forbidden(_) => gotcha::axum::http::StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => gotcha::axum::http::StatusCode::TOO_MANY_REQUESTS,
+            _ => gotcha::axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
     }
 }
@@ -4,23 +4,174 @@ mod service;
 
 use bollard::Docker;
 use error::{AppError};
+use gotcha::axum::extract::DefaultBodyLimit;
+use gotcha::axum::handler::Handler;
 use gotcha::{axum::extract::FromRef, config::BasicConfig, ConfigWrapper, GotchaApp, GotchaContext, GotchaRouter};
-use routes::{auth, services};
+use routes::{apply, audit, auth, builds, certificates, events, hooks, jobs, oidc, operations, secrets, services, tokens};
 use serde::{Deserialize, Serialize};
-use service::{Initializer, JwtManager, ServiceManager, UserManager};
+use service::audit::AuditLog;
+use service::builds::BuildManager;
+use service::certificates::CertificateStore;
+use service::hooks::HookManager;
+use service::jobs::JobManager;
+use service::login_guard::LoginGuard;
+use service::oidc::{OidcConfig, OidcManager};
+use service::tokens::TokenManager;
+use service::mailer::{Mailer, SmtpConfig};
+use service::password_reset::PasswordResets;
+use service::policy::PolicyStore;
+use service::proxy::{ProxyConfig, ProxyServer};
+use service::totp::MfaChallenges;
+use service::secrets::SecretStore;
+use service::tls::{TlsConfig, TlsFront};
+use service::{EnvRedactor, EventBus, Initializer, JsonStorage, JwtManager, OperationRegistry, ServiceManager, StorageBackend, UserManager};
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// Where the config is read from unless `LONGSHOREMAN_CONFIG` names another file.
+const CONFIG_FILE: &str = "longshoreman.toml";
+
+/// The config file, TOML. `host` and `port` are where the API listens over plain HTTP;
+/// every other key is a [`Config`] field. With `tls` set, `host` defaults to and must be
+/// a loopback address, so tokens only cross the network encrypted. The file may be left
+/// out when `LONGSHOREMAN_JWT_SECRET` is set, and that variable wins over `jwt_secret`
+/// in the file:
+///
+/// ```toml
+/// host = "127.0.0.1"
+/// port = 3000
+/// data_dir = "/var/lib/longshoreman"
+/// jwt_secret = "..."
+/// storage = "sqlite"
+/// trusted_proxies = ["10.0.0.2"]
+///
+/// [tls]
+/// listen = "0.0.0.0:3443"
+/// ```
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(flatten)]
+    application: Config,
+}
+
+fn default_port() -> u16 {
+    3000
+}
+
+impl ConfigFile {
+    fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let path = std::env::var("LONGSHOREMAN_CONFIG").unwrap_or_else(|_| CONFIG_FILE.to_string());
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            // Only the default file may be missing; a named one is a mistake.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && std::env::var_os("LONGSHOREMAN_CONFIG").is_none() => {
+                String::new()
+            }
+            Err(e) => return Err(format!("cannot read {}: {}", path, e).into()),
+        };
+        let jwt_secret = std::env::var("LONGSHOREMAN_JWT_SECRET").ok();
+        Self::parse(&contents, jwt_secret).map_err(|e| format!("{}: {}", path, e).into())
+    }
+
+    fn parse(contents: &str, jwt_secret: Option<String>) -> Result<Self, String> {
+        let mut file: Self = toml::from_str(contents).map_err(|e| e.to_string())?;
+        if let Some(secret) = jwt_secret {
+            file.application.jwt_secret = secret;
+        }
+        if file.application.jwt_secret.is_empty() {
+            return Err("no jwt_secret; set it in the file or in LONGSHOREMAN_JWT_SECRET".to_string());
+        }
+        match (&file.host, &file.application.tls) {
+            (None, tls) => file.host = Some(if tls.is_some() { "127.0.0.1" } else { "0.0.0.0" }.to_string()),
+            (Some(host), Some(_)) if !is_loopback(host) => {
+                return Err(format!(
+                    "host {} would serve the API over plain HTTP next to tls; set host to 127.0.0.1",
+                    host
+                ));
+            }
+            _ => {}
+        }
+        Ok(file)
+    }
+
+    fn host(&self) -> String {
+        self.host.clone().unwrap_or_default()
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" || host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
 #[derive(Debug, Deserialize, Clone, Serialize, Default)]
 pub struct Config {
+    #[serde(default = "default_docker_sock")]
     docker_sock: String,
+    #[serde(default = "default_data_dir")]
     data_dir: String,
+    /// Signs login sessions; anyone who knows it can log in as anyone.
+    #[serde(default)]
     jwt_secret: String,
+    #[serde(default)]
+    storage: StorageBackend,
+    /// Env names matching these patterns (`*` wildcards, any case) are masked in API
+    /// responses. A built-in list of password/secret/token names when not set.
+    #[serde(default)]
+    sensitive_env: Option<Vec<String>>,
+    /// Single sign-on with an OpenID Connect provider, next to password logins.
+    #[serde(default)]
+    oidc: Option<OidcConfig>,
+    /// Mail delivery, used for password reset links.
+    #[serde(default)]
+    smtp: Option<SmtpConfig>,
+    /// The password reset page, with `{token}` where the reset token goes.
+    #[serde(default)]
+    password_reset_url: Option<String>,
+    /// The reverse proxy for services with `domains`; not started when not set.
+    #[serde(default)]
+    proxy: Option<ProxyConfig>,
+    /// Proxies besides loopback whose `X-Forwarded-For` names the client, for the audit
+    /// log and login throttling. Other peers are taken as the client themselves.
+    #[serde(default)]
+    trusted_proxies: Vec<IpAddr>,
+    /// Serving the API over HTTPS as well; certificates are managed under
+    /// `/api/certificates`.
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+}
+
+fn default_docker_sock() -> String {
+    "unix:///var/run/docker.sock".to_string()
+}
+
+fn default_data_dir() -> String {
+    "./data".to_string()
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    service_manager: Arc<Mutex<ServiceManager>>,
+    service_manager: Arc<ServiceManager>,
+    operations: Arc<OperationRegistry>,
+    events: Arc<EventBus>,
+    audit: Arc<AuditLog>,
+    secrets: Arc<SecretStore>,
+    certificates: Arc<CertificateStore>,
+    env_redactor: Arc<EnvRedactor>,
+    builds: Arc<BuildManager>,
+    hooks: Arc<HookManager>,
+    jobs: Arc<JobManager>,
+    tokens: Arc<TokenManager>,
+    login_guard: Arc<LoginGuard>,
+    mfa_challenges: Arc<MfaChallenges>,
+    auth_policy: Arc<PolicyStore>,
+    oidc: Option<Arc<OidcManager>>,
+    password_resets: Arc<PasswordResets>,
+    mailer: Option<Arc<Mailer>>,
     user_manager: Arc<Mutex<UserManager>>,
     jwt_manager: Arc<Mutex<JwtManager>>,
 }
@@ -43,12 +194,8 @@ impl GotchaApp for App {
 
     fn config(&self) -> impl std::future::Future<Output = Result<ConfigWrapper<Self::Config>, Box<dyn std::error::Error>>> + Send {
         async move {
-            let config = Config {
-                docker_sock: "unix:///var/run/docker.sock".to_string(),
-                data_dir: "./data".to_string(),
-            jwt_secret: "your-secret-key".to_string(), // In production, use a secure secret
-        };
-        Ok(ConfigWrapper{basic: BasicConfig { host: "0.0.0.0".to_string(), port: 3000 }, application: config})
+            let file = ConfigFile::load()?;
+            Ok(ConfigWrapper{basic: BasicConfig { host: file.host(), port: file.port }, application: file.application})
         }
     }
     fn routes(
@@ -57,12 +204,68 @@ impl GotchaApp for App {
     ) -> GotchaRouter<GotchaContext<AppState, Config>> {
         router
             .post("/api/login", auth::login)
+            .post("/api/login/2fa", auth::login_second_factor)
+            .post("/api/login/2fa/enroll", auth::login_enroll)
+            .get("/api/oidc/login", oidc::oidc_login)
+            .get("/api/oidc/callback", oidc::oidc_callback)
             .post("/api/change-password", auth::change_password)
+            .post("/api/password-reset", auth::reset_password)
+            .post("/api/users/:email/password-reset", auth::issue_password_reset)
+            .post("/api/2fa/enroll", auth::start_totp_enrollment)
+            .post("/api/2fa/confirm", auth::confirm_totp)
+            .post("/api/2fa/disable", auth::disable_totp)
+            .post("/api/2fa/recovery-codes", auth::regenerate_recovery_codes)
+            .get("/api/auth/policy", auth::get_auth_policy)
+            .put("/api/auth/policy", auth::set_auth_policy)
+            .get("/api/auth/lockouts", auth::list_lockouts)
+            .delete("/api/auth/lockouts/:email", auth::unlock_account)
+            .get("/api/tokens", tokens::list_tokens)
+            .post("/api/tokens", tokens::create_token)
+            .delete("/api/tokens/:id", tokens::revoke_token)
             .get("/api/services", services::list_services)
             .post("/api/services", services::create_service)
             .get("/api/services/:id", services::get_service)
+            .get("/api/services/:id/reveal", services::reveal_env)
             .put("/api/services/:id", services::update_service)
             .delete("/api/services/:id", services::delete_service)
+            .post("/api/services/:id/start", services::start_service)
+            .post("/api/services/:id/stop", services::stop_service)
+            .post("/api/services/:id/scale", services::scale_service)
+            .post("/api/services/start-all", services::start_all)
+            .post("/api/services/stop-all", services::stop_all)
+            .post("/api/apply", apply::apply)
+            .get("/api/discover", services::discover)
+            .post("/api/services/adopt", services::adopt_service)
+            .get("/api/recovery", services::discrepancies)
+            .post("/api/recovery", services::recover)
+            .get("/api/events", events::events)
+            .get("/api/audit", audit::list_audit)
+            .get("/api/secrets", secrets::list_secrets)
+            .put("/api/secrets/:name", secrets::put_secret)
+            .delete("/api/secrets/:name", secrets::delete_secret)
+            .get("/api/certificates", certificates::list_certificates)
+            .post("/api/certificates", certificates::upload_certificate)
+            .delete("/api/certificates/:name", certificates::delete_certificate)
+            .get("/api/services/:id/hooks", hooks::list_hooks)
+            .post("/api/services/:id/hooks", hooks::create_hook)
+            .delete("/api/services/:id/hooks/:hook_id", hooks::delete_hook)
+            .get("/api/services/:id/hook-invocations", hooks::list_invocations)
+            .post("/api/hooks/:token", hooks::invoke_hook)
+            .get("/api/jobs", jobs::list_jobs)
+            .post("/api/jobs", jobs::create_job)
+            .get("/api/jobs/:id", jobs::get_job)
+            .put("/api/jobs/:id", jobs::update_job)
+            .delete("/api/jobs/:id", jobs::delete_job)
+            .post("/api/jobs/:id/run", jobs::run_job)
+            .get("/api/jobs/:id/runs", jobs::list_runs)
+            .get("/api/jobs/:id/runs/:run_id", jobs::get_run)
+            .get("/api/builds", builds::list_builds)
+            .post("/api/builds", builds::create_build.layer(DefaultBodyLimit::max(builds::MAX_CONTEXT_SIZE)))
+            .get("/api/builds/:id", builds::get_build)
+            .get("/api/operations", operations::list_operations)
+            .get("/api/operations/:id", operations::get_operation)
+            .get("/api/operations/:id/events", operations::operation_events)
+            .post("/api/operations/:id/cancel", operations::cancel_operation)
     }
 
     async fn state(
@@ -73,19 +276,91 @@ impl GotchaApp for App {
             &config.application.docker_sock,
             120,
             bollard::API_DEFAULT_VERSION,
-        )
-        .unwrap();
-        let service_manager = ServiceManager::new(
-            docker,
-            &format!("{}/services.json", config.application.data_dir),
-        )
-        .unwrap();
-        let user_manager =
-            UserManager::new(&format!("{}/users.json", config.application.data_dir)).unwrap();
+        )?;
+        let storage = service::storage::open(config.application.storage, &config.application.data_dir).await?;
+        let events = Arc::new(EventBus::default());
+        let secrets = Arc::new(SecretStore::open(&config.application.data_dir).await?);
+        let service_manager =
+            Arc::new(ServiceManager::new(docker.clone(), storage.clone(), events.clone(), secrets.clone()).await?);
+        let builds =
+            Arc::new(BuildManager::open(&config.application.data_dir, docker.clone(), service_manager.clone()).await?);
+        let jobs =
+            Arc::new(JobManager::open(&config.application.data_dir, docker.clone(), service_manager.clone()).await?);
+        let hooks = Arc::new(HookManager::open(&config.application.data_dir, service_manager.clone(), secrets.clone()).await?);
+        match service_manager.discrepancies().await {
+            Ok(discrepancies) => {
+                for discrepancy in &discrepancies {
+                    tracing::warn!("services.json disagrees with Docker: {}", discrepancy);
+                }
+                if !discrepancies.is_empty() {
+                    tracing::warn!("run `longshoreman recover` or POST /api/recovery to rebuild services.json from container labels");
+                }
+            }
+            Err(e) => tracing::warn!("could not compare services.json with Docker: {}", e),
+        }
+        tokio::spawn({
+            let service_manager = service_manager.clone();
+            async move { service_manager.watch_docker_events().await }
+        });
+        tokio::spawn({
+            let service_manager = service_manager.clone();
+            async move {
+                if let Err(e) = service_manager.resume().await {
+                    tracing::warn!("could not start the services that were running: {}", e);
+                }
+            }
+        });
+        tokio::spawn(jobs.clone().run_scheduler());
+        if let Some(proxy) = config.application.proxy.clone() {
+            let proxy = Arc::new(ProxyServer::new(proxy, service_manager.clone(), docker, events.clone()));
+            tokio::spawn(async move {
+                if let Err(e) = proxy.run().await {
+                    tracing::error!("reverse proxy stopped: {}", e);
+                }
+            });
+        }
+        let tls = config.application.tls.as_ref();
+        let certificates = Arc::new(
+            CertificateStore::open(
+                &config.application.data_dir,
+                secrets.clone(),
+                tls.map(|tls| tls.self_signed_names.clone()).unwrap_or_default(),
+                tls.map_or(30, |tls| tls.expiry_warning_days),
+            )
+            .await?,
+        );
+        tokio::spawn(certificates.clone().run_maintenance());
+        if let Some(tls) = tls {
+            let front = Arc::new(TlsFront::new(tls, certificates.clone(), &config.basic.host, config.basic.port)?);
+            tokio::spawn(async move {
+                if let Err(e) = front.run().await {
+                    tracing::error!("TLS listener stopped: {}", e);
+                }
+            });
+        }
+        let user_manager = UserManager::new(storage);
         let jwt_manager = JwtManager::new(config.application.jwt_secret.as_bytes());
 
         let it: Result<Self::State, Box<dyn std::error::Error>> = Ok(AppState {
-            service_manager: Arc::new(Mutex::new(service_manager)),
+            service_manager,
+            operations: Arc::new(OperationRegistry::default()),
+            events,
+            audit: Arc::new(AuditLog::open(&config.application.data_dir)),
+            secrets,
+            certificates,
+            env_redactor: Arc::new(EnvRedactor::new(config.application.sensitive_env.clone())),
+            builds,
+            hooks,
+            jobs,
+            tokens: Arc::new(TokenManager::open(&config.application.data_dir).await?),
+            login_guard: Arc::new(LoginGuard::default()),
+            mfa_challenges: Arc::new(MfaChallenges::default()),
+            auth_policy: Arc::new(PolicyStore::open(&config.application.data_dir).await?),
+            oidc: config.application.oidc.clone().map(OidcManager::new).transpose()?.map(Arc::new),
+            password_resets: Arc::new(
+                PasswordResets::open(&config.application.data_dir, config.application.password_reset_url.clone()).await?,
+            ),
+            mailer: config.application.smtp.as_ref().map(Mailer::new).transpose()?.map(Arc::new),
             user_manager: Arc::new(Mutex::new(user_manager)),
             jwt_manager: Arc::new(Mutex::new(jwt_manager)),
         });
@@ -96,12 +371,103 @@ impl GotchaApp for App {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
    
-    // Initialize data directory and files
-    let initializer = Initializer::new("./data");
+    // Initialize the configured data directory and files before anything reads them
+    let config = ConfigFile::load()?;
+    let initializer = Initializer::new(&config.application.data_dir);
     initializer.init()?;
 
     let app = App {};
+    if std::env::args().nth(1).as_deref() == Some("recover") {
+        return recover(&app).await;
+    }
     app.run().await?;
 
     Ok(())
 }
+
+/// `longshoreman recover`: rebuilds services.json from container labels, even when the
+/// file itself is unreadable.
+async fn recover(app: &App) -> Result<(), Box<dyn std::error::Error>> {
+    let config = app.config().await?;
+    let docker = Docker::connect_with_unix(
+        &config.application.docker_sock,
+        120,
+        bollard::API_DEFAULT_VERSION,
+    )?;
+    let data_dir = &config.application.data_dir;
+    let storage = match service::storage::open(config.application.storage, data_dir).await {
+        Ok(storage) => storage,
+        Err(e) if config.application.storage == StorageBackend::Json => {
+            println!("services.json is unreadable ({}), rebuilding it from scratch", e);
+            Arc::new(JsonStorage::open_discarding_services(data_dir).await?)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let secrets = Arc::new(SecretStore::open(data_dir).await?);
+    let service_manager = ServiceManager::new(docker, storage, Arc::new(EventBus::default()), secrets).await?;
+
+    let report = service_manager.recover(false).await?;
+    for discrepancy in &report.discrepancies {
+        println!("fixed: {}", discrepancy);
+    }
+    println!("recovered {} services", report.services.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_file_takes_the_defaults_and_the_secret_from_the_environment() {
+        let file = ConfigFile::parse("", Some("secret".to_string())).unwrap();
+        assert_eq!((file.host().as_str(), file.port), ("0.0.0.0", 3000));
+        assert_eq!(file.application.data_dir, "./data");
+        assert_eq!(file.application.jwt_secret, "secret");
+        assert_eq!(file.application.storage, StorageBackend::Json);
+        assert!(file.application.tls.is_none());
+    }
+
+    #[test]
+    fn keys_are_read_from_the_file() {
+        let contents = r#"
+            host = "::1"
+            port = 8080
+            data_dir = "/var/lib/longshoreman"
+            jwt_secret = "from file"
+            storage = "sqlite"
+            trusted_proxies = ["10.0.0.2"]
+
+            [tls]
+            listen = "0.0.0.0:8443"
+        "#;
+        let file = ConfigFile::parse(contents, None).unwrap();
+        assert_eq!((file.host().as_str(), file.port), ("::1", 8080));
+        assert_eq!(file.application.data_dir, "/var/lib/longshoreman");
+        assert_eq!(file.application.jwt_secret, "from file");
+        assert_eq!(file.application.storage, StorageBackend::Sqlite);
+        assert_eq!(file.application.trusted_proxies, vec!["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(file.application.tls.unwrap().listen, "0.0.0.0:8443");
+
+        let overridden = ConfigFile::parse(contents, Some("from env".to_string())).unwrap();
+        assert_eq!(overridden.application.jwt_secret, "from env");
+    }
+
+    #[test]
+    fn a_jwt_secret_is_required() {
+        assert!(ConfigFile::parse("", None).is_err());
+        assert!(ConfigFile::parse("jwt_secret = \"\"", None).is_err());
+        assert!(ConfigFile::parse("jwt_secret = \"x\"\nunknown_type = [", None).is_err());
+    }
+
+    #[test]
+    fn plain_http_stays_on_loopback_next_to_tls() {
+        let with_tls = |host: &str| ConfigFile::parse(&format!("jwt_secret = \"x\"\n{}\n[tls]", host), None);
+        assert_eq!(with_tls("").unwrap().host(), "127.0.0.1");
+        assert!(with_tls("host = \"localhost\"").is_ok());
+        assert!(with_tls("host = \"[::1]\"").is_ok());
+        assert!(with_tls("host = \"0.0.0.0\"").is_err());
+        assert!(with_tls("host = \"10.0.0.5\"").is_err());
+        assert!(ConfigFile::parse("jwt_secret = \"x\"\nhost = \"10.0.0.5\"", None).is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+use gotcha::axum::extract::Query;
+use gotcha::axum::http::{header, HeaderMap};
+use gotcha::axum::response::{IntoResponse, Response};
+use gotcha::{Json, State};
+use serde::{Deserialize, Serialize};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::routes::operations::{self, AsyncQuery};
+use crate::service::{DesiredState, Plan};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct ApplyQuery {
+    /// Execute the plan. Without it the plan is only computed and returned.
+    #[serde(default)]
+    pub confirm: bool,
+    /// Delete managed services that are not part of the desired state.
+    #[serde(default)]
+    pub prune: bool,
+    /// Overrides the body format (`json`, `yaml` or `toml`) instead of `Content-Type`.
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApplyResponse {
+    pub dry_run: bool,
+    pub changed: bool,
+    pub plan: Plan,
+}
+
+pub async fn apply(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    query: Query<ApplyQuery>,
+    async_query: Query<AsyncQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, AppError> {
+    let format = query
+        .format
+        .as_deref()
+        .or_else(|| headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()));
+    auth_user.require_admin()?;
+    let desired = DesiredState::parse(format, &body)?;
+
+    if !query.confirm {
+        let plan = app.service_manager.plan(&desired, query.prune).await?;
+        return Ok(Json(ApplyResponse {
+            dry_run: true,
+            changed: plan.has_changes(),
+            plan: app.env_redactor.plan(plan),
+        })
+        .into_response());
+    }
+
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let prune = query.prune;
+    let event = audit.event("apply", None, Some(&desired));
+    operations::run(&app, &async_query, event, async move {
+        let plan = service_manager.apply(&desired, prune).await?;
+        Ok(ApplyResponse {
+            dry_run: false,
+            changed: plan.has_changes(),
+            plan: redactor.plan(plan),
+        })
+    })
+    .await
+}
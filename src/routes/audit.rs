@@ -0,0 +1,141 @@
+use gotcha::axum::extract::{ConnectInfo, FromRequestParts, Query};
+use gotcha::axum::http::request::Parts;
+use gotcha::{async_trait, GotchaContext, Json, State};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use crate::error::AppError;
+use crate::routes::auth::AuthUser;
+use crate::service::audit::{self, AuditEntry, AuditEvent, AuditQuery};
+use crate::{AppState, Config};
+
+/// Who is making a request and from where, for the audit log. Never rejects: requests
+/// without a valid token are recorded without an actor.
+pub struct AuditContext {
+    pub actor: Option<String>,
+    pub source_ip: Option<String>,
+}
+
+impl AuditContext {
+    pub fn event(&self, action: &str, target: Option<String>, request: Option<&impl Serialize>) -> AuditEvent {
+        AuditEvent {
+            actor: self.actor.clone(),
+            source_ip: self.source_ip.clone(),
+            action: action.to_string(),
+            target,
+            request: request.and_then(audit::redact),
+        }
+    }
+}
+
+/// The client address: the peer address if the server records it. Only a peer that is a
+/// trusted proxy (loopback, such as the TLS listener, or one of `trusted`) may name
+/// another client in `X-Forwarded-For`; the hops it lists are walked back to the first
+/// one that is not a trusted proxy itself, since anything before it could be forged.
+fn client_ip(parts: &Parts, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| ip.is_loopback() || trusted.contains(ip);
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical())?;
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+    let hops: Vec<&str> = parts
+        .headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) if is_trusted(&ip.to_canonical()) => client = ip.to_canonical(),
+            Ok(ip) => return Some(ip.to_canonical()),
+            // What a trusted proxy was sent by someone it could not name is no evidence.
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+
+#[async_trait]
+impl FromRequestParts<GotchaContext<AppState, Config>> for AuditContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &GotchaContext<AppState, Config>) -> Result<Self, Self::Rejection> {
+        let actor = AuthUser::from_request_parts(parts, state).await.ok().map(|user| user.email);
+        Ok(AuditContext {
+            actor,
+            source_ip: client_ip(parts, &state.config.application.trusted_proxies).map(|ip| ip.to_string()),
+        })
+    }
+}
+
+pub async fn list_audit(app: State<AppState>, auth_user: AuthUser, query: Query<AuditQuery>) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.audit.query(&query)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gotcha::axum::http::Request;
+
+    fn parts(peer: &str, forwarded_for: Option<&str>) -> Parts {
+        let mut request = Request::builder();
+        if let Some(value) = forwarded_for {
+            request = request.header("X-Forwarded-For", value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        parts.extensions.insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        parts
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_name_another_client() {
+        assert_eq!(client_ip(&parts("203.0.113.7:5000", Some("198.51.100.1")), &[]), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn loopback_peers_are_trusted_to_forward() {
+        assert_eq!(client_ip(&parts("127.0.0.1:5000", Some("198.51.100.1")), &[]), ip("198.51.100.1"));
+        assert_eq!(client_ip(&parts("127.0.0.1:5000", None), &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn forged_leading_hops_are_skipped() {
+        let trusted = [ip("10.0.0.2").unwrap()];
+        let parts = parts("10.0.0.2:5000", Some("1.2.3.4, 198.51.100.1"));
+        assert_eq!(client_ip(&parts, &trusted), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn chains_of_trusted_proxies_are_walked_back() {
+        let trusted = [ip("10.0.0.2").unwrap(), ip("10.0.0.3").unwrap()];
+        let parts = parts("10.0.0.2:5000", Some("198.51.100.1, 10.0.0.3"));
+        assert_eq!(client_ip(&parts, &trusted), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn unparsable_hops_end_the_walk() {
+        let parts = parts("127.0.0.1:5000", Some("198.51.100.1, unknown"));
+        assert_eq!(client_ip(&parts, &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn nothing_is_known_without_the_peer_address() {
+        let (parts, _) = Request::builder()
+            .header("X-Forwarded-For", "198.51.100.1")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(client_ip(&parts, &[]), None);
+    }
+}
@@ -1,24 +1,309 @@
 use anyhow::Result;
-use gotcha::{async_trait, GotchaContext, State};
+use gotcha::{async_trait, GotchaContext, Path, State};
 use gotcha::axum::http::StatusCode;
 use gotcha::{api, Json, axum::extract::FromRequestParts, axum::http::request::Parts};
 use crate::error::AppError;
+use crate::routes::audit::AuditContext;
 use crate::service::{LoginRequest, LoginResponse, UserManager, ChangePasswordRequest, JwtManager, Claims, Token};
+use crate::service::{MfaTokenRequest, SecondFactorRequest, TotpCodeRequest};
+use crate::service::login_guard::Lockout;
+use crate::service::tokens::{Scope, TOKEN_PREFIX};
+use crate::service::password_reset::{IssueResetRequest, IssuedReset, ResetPasswordRequest};
+use crate::service::policy::AuthPolicy;
+use crate::service::totp::{ChallengeKind, Enrollment};
 use crate::{App, AppState, Config};
 
-pub async fn login(app: State<AppState>, payload: Json<LoginRequest>) -> Result<Json<LoginResponse>, AppError> {
-    let user_manager = app.user_manager.lock().await;
-    if user_manager.verify_user(&payload.email, &payload.password)? {
+pub async fn login(app: State<AppState>, audit: AuditContext, payload: Json<LoginRequest>) -> Result<Json<LoginResponse>, AppError> {
+    let audit = AuditContext {
+        actor: Some(payload.email.clone()),
+        ..audit
+    };
+    let mut lockout = None;
+    let result = async {
+        app.login_guard.check(audit.source_ip.as_deref(), &payload.email)?;
+        let user_manager = app.user_manager.lock().await;
+        if !user_manager.verify_user(&payload.email, &payload.password).await? {
+            lockout = app.login_guard.record_failure(&payload.email);
+            return Err(AppError::Auth("Invalid credentials".to_string()));
+        }
+        // The failures are only forgotten once the second factor is in as well.
+        if user_manager.has_totp(&payload.email).await? {
+            return Ok(second_step(&app, &payload.email, ChallengeKind::Verify));
+        }
+        if app.auth_policy.get().await.require_totp {
+            return Ok(second_step(&app, &payload.email, ChallengeKind::Enroll));
+        }
+        app.login_guard.record_success(&payload.email);
         let jwt_manager = app.jwt_manager.lock().await;
         let token = jwt_manager.create_token(&payload.email)?;
-        Ok(Json(LoginResponse { token }))
-    } else {
-        Err(AppError::Auth("Invalid credentials".to_string()))
+        Ok(LoginResponse {
+            token: Some(token),
+            ..Default::default()
+        })
+    }
+    .await;
+
+    app.audit.record(audit.event("login", Some(payload.email.clone()), None::<&()>), &result);
+    record_lockout(&app, &audit, lockout);
+    Ok(Json(result?))
+}
+
+fn second_step(app: &AppState, email: &str, kind: ChallengeKind) -> LoginResponse {
+    LoginResponse {
+        mfa_token: Some(app.mfa_challenges.issue(email, kind)),
+        mfa: Some(kind),
+        ..Default::default()
+    }
+}
+
+fn record_lockout(app: &AppState, audit: &AuditContext, lockout: Option<Lockout>) {
+    if let Some(lockout) = lockout {
+        tracing::warn!("locked {} until {} after {} failed logins", lockout.email, lockout.locked_until, lockout.failures);
+        let event = audit.event("account_locked", Some(lockout.email.clone()), Some(&lockout));
+        app.audit.record(event, &Ok::<_, AppError>(()));
+    }
+}
+
+/// The second login step: a code from the authenticator or a recovery code, or for a
+/// login that had to enroll, the code confirming the new authenticator. Wrong codes
+/// count towards the same lockout as wrong passwords.
+pub async fn login_second_factor(
+    app: State<AppState>,
+    audit: AuditContext,
+    payload: Json<SecondFactorRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let (email, kind) = app.mfa_challenges.get(&payload.mfa_token)?;
+    let audit = AuditContext {
+        actor: Some(email.clone()),
+        ..audit
+    };
+    let mut lockout = None;
+    let result = async {
+        app.login_guard.check(audit.source_ip.as_deref(), &email)?;
+        let user_manager = app.user_manager.lock().await;
+        let verified = match kind {
+            ChallengeKind::Verify => user_manager.verify_second_factor(&email, &payload.code).await?.then_some(None),
+            ChallengeKind::Enroll => match user_manager.confirm_totp(&email, &payload.code).await {
+                Ok(codes) => Some(Some(codes)),
+                Err(AppError::Auth(_)) => None,
+                Err(e) => return Err(e),
+            },
+        };
+        let Some(recovery_codes) = verified else {
+            app.mfa_challenges.fail(&payload.mfa_token);
+            lockout = app.login_guard.record_failure(&email);
+            return Err(AppError::Auth("Invalid authentication code".to_string()));
+        };
+
+        app.mfa_challenges.complete(&payload.mfa_token);
+        app.login_guard.record_success(&email);
+        let jwt_manager = app.jwt_manager.lock().await;
+        let token = jwt_manager.create_token(&email)?;
+        Ok(LoginResponse {
+            token: Some(token),
+            recovery_codes,
+            ..Default::default()
+        })
     }
+    .await;
+
+    app.audit.record(audit.event("login_2fa", Some(email.clone()), None::<&()>), &result);
+    record_lockout(&app, &audit, lockout);
+    Ok(Json(result?))
+}
+
+/// Sets up an authenticator during a login the policy made enroll. The code it shows
+/// then goes to `/api/login/2fa`.
+pub async fn login_enroll(
+    app: State<AppState>,
+    audit: AuditContext,
+    payload: Json<MfaTokenRequest>,
+) -> Result<Json<Enrollment>, AppError> {
+    let (email, kind) = app.mfa_challenges.get(&payload.mfa_token)?;
+    if kind != ChallengeKind::Enroll {
+        return Err(AppError::Auth("This login does not need an authenticator set up".to_string()));
+    }
+    let audit = AuditContext {
+        actor: Some(email.clone()),
+        ..audit
+    };
+    let user_manager = app.user_manager.lock().await;
+    let result = user_manager.start_totp_enrollment(&email).await;
+    app.audit.record(audit.event("enroll_totp", Some(email), None::<&()>), &result);
+    Ok(Json(result?))
+}
+
+/// Starts setting up an authenticator for the signed-in user; `/api/2fa/confirm` turns
+/// it on.
+pub async fn start_totp_enrollment(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+) -> Result<Json<Enrollment>, AppError> {
+    auth_user.require_session()?;
+    let user_manager = app.user_manager.lock().await;
+    let result = user_manager.start_totp_enrollment(&auth_user.email).await;
+    app.audit.record(audit.event("enroll_totp", Some(auth_user.email.clone()), None::<&()>), &result);
+    Ok(Json(result?))
+}
+
+/// Returns the recovery codes; they are not shown again.
+pub async fn confirm_totp(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<TotpCodeRequest>,
+) -> Result<Json<Vec<String>>, AppError> {
+    auth_user.require_session()?;
+    let user_manager = app.user_manager.lock().await;
+    let result = user_manager.confirm_totp(&auth_user.email, &payload.code).await;
+    let event = audit.event("confirm_totp", Some(auth_user.email.clone()), None::<&()>);
+    app.audit.record(event, &result);
+    Ok(Json(result?))
+}
+
+pub async fn disable_totp(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<TotpCodeRequest>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_session()?;
+    let result = async {
+        if app.auth_policy.get().await.require_totp {
+            return Err(AppError::Forbidden("Two-factor authentication is required for all users".to_string()));
+        }
+        let user_manager = app.user_manager.lock().await;
+        user_manager.disable_totp(&auth_user.email, &payload.code).await
+    }
+    .await;
+    app.audit.record(audit.event("disable_totp", Some(auth_user.email.clone()), None::<&()>), &result);
+    result?;
+    Ok(Json("Two-factor authentication disabled".to_string()))
+}
+
+/// Replaces all recovery codes with new ones, returned this once.
+pub async fn regenerate_recovery_codes(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<TotpCodeRequest>,
+) -> Result<Json<Vec<String>>, AppError> {
+    auth_user.require_session()?;
+    let user_manager = app.user_manager.lock().await;
+    let result = user_manager.regenerate_recovery_codes(&auth_user.email, &payload.code).await;
+    let event = audit.event("regenerate_recovery_codes", Some(auth_user.email.clone()), None::<&()>);
+    app.audit.record(event, &result);
+    Ok(Json(result?))
+}
+
+pub async fn get_auth_policy(app: State<AppState>, auth_user: AuthUser) -> Result<Json<AuthPolicy>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.auth_policy.get().await))
+}
+
+/// Requiring 2FA leaves existing sessions alone; users without an authenticator are
+/// made to set one up at their next login.
+pub async fn set_auth_policy(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<AuthPolicy>,
+) -> Result<Json<AuthPolicy>, AppError> {
+    auth_user.require_admin()?;
+    let event = audit.event("set_auth_policy", None, Some(&payload.0));
+    let result = app.auth_policy.set(payload.0).await;
+    app.audit.record(event, &result);
+    Ok(Json(result?))
+}
+
+/// Accounts currently locked out after failed logins.
+pub async fn list_lockouts(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<Lockout>>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.login_guard.lockouts()))
+}
+
+pub async fn unlock_account(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_admin()?;
+    let email = paths.0.0;
+    let result = app.login_guard.unlock(&email);
+    app.audit.record(audit.event("unlock_account", Some(email), None::<&()>), &result);
+    result?;
+    Ok(Json("Account unlocked successfully".to_string()))
 }
 
 pub struct AuthUser {
     pub email: String,
+    /// A password login may do anything; an API token only what its scopes allow.
+    pub scopes: Vec<Scope>,
+    /// Signed in with a session token from a login, rather than an API token.
+    pub session: bool,
+}
+
+impl AuthUser {
+    /// For changes to the caller's own account, which need the user to have signed in
+    /// themselves whatever their scopes: an API token acting for them is not enough.
+    pub fn require_session(&self) -> Result<(), AppError> {
+        if !self.session {
+            return Err(AppError::Forbidden("This needs a login session, not an API token".to_string()));
+        }
+        Ok(())
+    }
+
+    fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn require_admin(&self) -> Result<(), AppError> {
+        if !self.is_admin() {
+            return Err(AppError::Forbidden("This needs the admin scope".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Every scope includes reading services.
+    pub fn require_read(&self) -> Result<(), AppError> {
+        if self.scopes.is_empty() {
+            return Err(AppError::Forbidden("This needs the read scope".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Allowed when a deploy scope lists any of `names` (a service's id and name).
+    pub fn require_deploy(&self, names: &[&str]) -> Result<(), AppError> {
+        let allowed = self.is_admin()
+            || self.scopes.iter().any(|scope| match scope {
+                Scope::Deploy(services) => services.iter().any(|s| names.contains(&s.as_str())),
+                _ => false,
+            });
+        if !allowed {
+            return Err(AppError::Forbidden(format!("This needs a deploy scope for {}", names.join("/"))));
+        }
+        Ok(())
+    }
+
+    /// Allowed when a secrets scope lists every one of `names`. A spec referring to a
+    /// secret lets its container read it, so this is checked before any is added.
+    pub fn require_secrets<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> Result<(), AppError> {
+        if self.is_admin() {
+            return Ok(());
+        }
+        for name in names {
+            let granted = self.scopes.iter().any(|scope| match scope {
+                Scope::Secrets(secrets) => secrets.contains(name),
+                _ => false,
+            });
+            if !granted {
+                return Err(AppError::Forbidden(format!("Referring to secret {} needs a secrets scope for it", name)));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -36,30 +321,183 @@ impl FromRequestParts<GotchaContext<AppState, Config>> for AuthUser {
         }
 
         let token = &auth_header[7..];
+        if token.starts_with(TOKEN_PREFIX) {
+            let token = state
+                .state
+                .tokens
+                .authenticate(token)
+                .await
+                .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or expired token"))?;
+            return Ok(AuthUser {
+                email: token.owner,
+                scopes: token.scopes,
+                session: false,
+            });
+        }
+
         let jwt_manager = state.state.jwt_manager.lock().await;
         let claims = jwt_manager.verify_token(token).map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token"))?;
 
         Ok(AuthUser {
             email: claims.sub,
+            scopes: claims.scopes.unwrap_or_else(|| vec![Scope::Admin]),
+            session: true,
         })
     }
 }
 
+/// Checks `user` for a deploy scope covering the stored service `id`, which a scope may
+/// name by id or by name.
+pub async fn require_deploy_of(user: &AuthUser, app: &AppState, id: &str) -> Result<(), AppError> {
+    let service = app.service_manager.get_service(id).await?;
+    user.require_deploy(&[&service.id, &service.name])
+}
+
 pub async fn change_password(
     app: State<AppState>  ,
     auth_user: AuthUser,
+    audit: AuditContext,
     payload: Json<ChangePasswordRequest>,
 ) -> Result<Json<String>, AppError> {
-    let mut user_manager = app.user_manager.lock().await;
-    user_manager.change_password(
+    auth_user.require_session()?;
+    let policy = app.auth_policy.get().await;
+    let user_manager = app.user_manager.lock().await;
+    let result = user_manager.change_password(
         &auth_user.email,
         &payload.old_password,
         &payload.new_password,
-    )?;
+        &policy,
+    ).await;
+    let event = audit.event("change_password", Some(auth_user.email.clone()), None::<&()>);
+    app.audit.record(event, &result);
+    result?;
 
     // Generate new server ID to invalidate all existing tokens
     let mut jwt_manager = app.jwt_manager.lock().await;
     jwt_manager.regenerate_server_id();
 
     Ok(Json("Password changed successfully".to_string()))
-} 
\ No newline at end of file
+}
+
+/// Issues a one-time reset token for a user who cannot sign in. It is returned, with a
+/// link when a reset page is configured, or mailed to the user instead.
+pub async fn issue_password_reset(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+    payload: Json<IssueResetRequest>,
+) -> Result<Json<IssuedReset>, AppError> {
+    auth_user.require_admin()?;
+    let email = paths.0.0;
+    let result = async {
+        app.user_manager.lock().await.require_password_user(&email).await?;
+        let mailer = match (payload.send_email, &app.mailer) {
+            (true, Some(mailer)) => Some(mailer),
+            (true, None) => return Err(AppError::Service("Mail delivery is not configured".to_string())),
+            (false, _) => None,
+        };
+        let (token, expires_at) = app.password_resets.issue(&email, &auth_user.email).await?;
+        let link = app.password_resets.link(&token);
+        let Some(mailer) = mailer else {
+            return Ok(IssuedReset {
+                email: email.clone(),
+                expires_at,
+                emailed: false,
+                token: Some(token),
+                link,
+            });
+        };
+
+        let body = format!(
+            "A password reset was requested for your Longshoreman account {}.\n\n{}\n\nIt can be used once, until {}.\n",
+            email,
+            link.unwrap_or_else(|| format!("Reset token: {}", token)),
+            expires_at.to_rfc3339()
+        );
+        mailer.send(&email, "Reset your Longshoreman password", body).await?;
+        Ok(IssuedReset {
+            email: email.clone(),
+            expires_at,
+            emailed: true,
+            token: None,
+            link: None,
+        })
+    }
+    .await;
+    app.audit.record(audit.event("issue_password_reset", Some(email.clone()), Some(&payload.0)), &result);
+    Ok(Json(result?))
+}
+
+/// Sets a new password with a reset token. Signs out all sessions and lifts a lockout.
+pub async fn reset_password(
+    app: State<AppState>,
+    audit: AuditContext,
+    payload: Json<ResetPasswordRequest>,
+) -> Result<Json<String>, AppError> {
+    let result = async {
+        let policy = app.auth_policy.get().await;
+        let email = app.password_resets.peek(&payload.token).await?;
+        let user_manager = app.user_manager.lock().await;
+        // The token is only used up once the new password has been accepted.
+        let password_hash = user_manager.check_reset_password(&email, &payload.new_password, &policy).await?;
+        if app.password_resets.redeem(&payload.token).await? != email {
+            return Err(AppError::Auth("Invalid or expired reset token".to_string()));
+        }
+        user_manager.set_password_hash(&email, password_hash).await?;
+        Ok(email)
+    }
+    .await;
+    let audit = AuditContext {
+        actor: result.as_ref().ok().cloned(),
+        ..audit
+    };
+    app.audit.record(audit.event("reset_password", result.as_ref().ok().cloned(), None::<&()>), &result);
+    let email = result?;
+
+    app.login_guard.record_success(&email);
+    app.jwt_manager.lock().await.regenerate_server_id();
+    Ok(Json("Password reset successfully".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(scopes: Vec<Scope>, session: bool) -> AuthUser {
+        AuthUser {
+            email: "a@example.com".to_string(),
+            scopes,
+            session,
+        }
+    }
+
+    #[test]
+    fn deploy_scopes_only_cover_the_named_services() {
+        let deployer = user(vec![Scope::Deploy(vec!["web".to_string()])], false);
+        assert!(deployer.require_deploy(&["web-id", "web"]).is_ok());
+        assert!(deployer.require_deploy(&["db-id", "db"]).is_err());
+        assert!(deployer.require_read().is_ok());
+        assert!(deployer.require_admin().is_err());
+
+        let admin = user(vec![Scope::Admin], false);
+        assert!(admin.require_deploy(&["db-id", "db"]).is_ok());
+        assert!(user(vec![Scope::Read], false).require_deploy(&["web"]).is_err());
+        assert!(user(Vec::new(), false).require_read().is_err());
+    }
+
+    #[test]
+    fn secret_references_need_every_secret_granted() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let granted = user(vec![Scope::Secrets(names(&["db"]))], false);
+        assert!(granted.require_secrets(&names(&["db"])).is_ok());
+        assert!(granted.require_secrets(&names(&["db", "api"])).is_err());
+        assert!(user(vec![Scope::Admin], false).require_secrets(&names(&["api"])).is_ok());
+    }
+
+    #[test]
+    fn account_changes_need_a_login_session() {
+        assert!(user(vec![Scope::Admin], false).require_session().is_err());
+        assert!(user(vec![Scope::Read], true).require_session().is_ok());
+    }
+}
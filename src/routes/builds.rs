@@ -0,0 +1,61 @@
+use gotcha::axum::body::Bytes;
+use gotcha::axum::extract::Query;
+use gotcha::axum::http::{header, HeaderMap};
+use gotcha::axum::response::Response;
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::routes::operations::{self, AsyncQuery};
+use crate::service::builds::{Build, BuildRequest};
+use crate::AppState;
+
+/// Carries the [`BuildRequest`] as JSON when the body is a tar of the build context.
+const OPTIONS_HEADER: &str = "x-build-options";
+/// The largest build context that can be uploaded. It is held in memory while the
+/// build runs.
+pub const MAX_CONTEXT_SIZE: usize = 512 * 1024 * 1024;
+
+/// `POST /api/builds`. Either a JSON [`BuildRequest`] with a `context_path` on the
+/// host, or a (optionally gzipped) tar of the build context with the options in the
+/// `X-Build-Options` header. The build output is streamed as the operation's log.
+pub async fn create_build(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    query: Query<AsyncQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    auth_user.require_admin()?;
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"));
+    let (request, context) = if is_json {
+        (serde_json::from_slice::<BuildRequest>(&body)?, None)
+    } else {
+        let request = match headers.get(OPTIONS_HEADER) {
+            Some(options) => serde_json::from_slice(options.as_bytes())?,
+            None => BuildRequest::default(),
+        };
+        (request, Some(body.to_vec()))
+    };
+
+    let builds = app.builds.clone();
+    let target = request.deploy.clone().or_else(|| request.tags.first().cloned());
+    let event = audit.event("build", target, Some(&request));
+    operations::run(&app, &query, event, async move {
+        builds.build(request, context).await
+    }).await
+}
+
+pub async fn list_builds(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<Build>>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(app.builds.list().await))
+}
+
+pub async fn get_build(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Build>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(app.builds.get(&paths.0.0).await?))
+}
@@ -0,0 +1,43 @@
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::service::certificates::{CertificateInfo, UploadCertificateRequest};
+use crate::AppState;
+
+pub async fn list_certificates(
+    app: State<AppState>,
+    auth_user: AuthUser,
+) -> Result<Json<Vec<CertificateInfo>>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.certificates.list()))
+}
+
+/// Adds a certificate or replaces the one with the same name. TLS connections use it
+/// from the next handshake on.
+pub async fn upload_certificate(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<UploadCertificateRequest>,
+) -> Result<Json<CertificateInfo>, AppError> {
+    auth_user.require_admin()?;
+    let result = app.certificates.upload(&payload).await;
+    let fingerprint = result.as_ref().ok().map(|info| &info.fingerprint);
+    app.audit.record(audit.event("upload_certificate", Some(payload.name.clone()), fingerprint), &result);
+    Ok(Json(result?))
+}
+
+pub async fn delete_certificate(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_admin()?;
+    let name = paths.0.0;
+    let result = app.certificates.delete(&name).await;
+    app.audit.record(audit.event("delete_certificate", Some(name), None::<&()>), &result);
+    result?;
+    Ok(Json("Certificate deleted successfully".to_string()))
+}
@@ -0,0 +1,48 @@
+use futures::stream::{self, Stream, StreamExt};
+use gotcha::axum::http::HeaderMap;
+use gotcha::axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use gotcha::State;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+use crate::error::AppError;
+use crate::routes::auth::AuthUser;
+use crate::service::Event;
+use crate::AppState;
+
+fn to_sse(event: &Event) -> SseEvent {
+    SseEvent::default()
+        .id(event.id.to_string())
+        .event(event.kind.name())
+        .json_data(event)
+        .unwrap_or_else(|_| SseEvent::default().comment("unserializable event"))
+}
+
+/// Tells the client it missed events and should reload whatever it displays.
+fn reset() -> SseEvent {
+    SseEvent::default().event("reset").data("events were missed, reload state")
+}
+
+/// `GET /api/events`: service and container events as server-sent events. Browsers
+/// reconnecting with `Last-Event-ID` get what they missed from the buffer, or a
+/// `reset` event if it no longer goes back that far.
+pub async fn events(app: State<AppState>, auth_user: AuthUser, headers: HeaderMap) -> Result<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>, AppError> {
+    auth_user.require_read()?;
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let subscription = app.events.subscribe(last_event_id);
+
+    let missed = stream::iter(subscription.missed.then(reset));
+    let replay = stream::iter(subscription.replay.iter().map(to_sse).collect::<Vec<_>>());
+    let live = stream::unfold(subscription.live, |mut live| async move {
+        let event = match live.recv().await {
+            Ok(event) => to_sse(&event),
+            Err(RecvError::Lagged(_)) => reset(),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, live))
+    });
+
+    Ok(Sse::new(missed.chain(replay).chain(live).map(Ok)).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,80 @@
+use gotcha::axum::body::Bytes;
+use gotcha::axum::extract::Query;
+use gotcha::axum::http::HeaderMap;
+use gotcha::axum::response::Response;
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::{require_deploy_of, AuthUser};
+use crate::routes::operations::{self, AsyncQuery};
+use crate::service::hooks::{CreateWebhookRequest, CreatedWebhook, Invocation, Webhook};
+use crate::AppState;
+
+/// HMAC-SHA256 of the body, as sent by GitHub and most CI systems.
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+
+pub async fn create_hook(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+    payload: Json<CreateWebhookRequest>,
+) -> Result<Json<CreatedWebhook>, AppError> {
+    let service_id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &service_id).await?;
+    let result = app.hooks.create(&service_id, payload.0).await;
+    app.audit.record(audit.event("create_webhook", Some(service_id), None::<&()>), &result);
+    Ok(Json(result?))
+}
+
+pub async fn list_hooks(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Vec<Webhook>>, AppError> {
+    require_deploy_of(&auth_user, &app, &paths.0.0).await?;
+    Ok(Json(app.hooks.list(&paths.0.0).await))
+}
+
+pub async fn delete_hook(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String, String)>,
+) -> Result<Json<String>, AppError> {
+    let (service_id, hook_id) = paths.0;
+    require_deploy_of(&auth_user, &app, &service_id).await?;
+    let result = app.hooks.delete(&service_id, &hook_id).await;
+    app.audit.record(audit.event("delete_webhook", Some(service_id), None::<&()>), &result);
+    result?;
+    Ok(Json("Webhook deleted successfully".to_string()))
+}
+
+pub async fn list_invocations(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Vec<Invocation>>, AppError> {
+    require_deploy_of(&auth_user, &app, &paths.0.0).await?;
+    Ok(Json(app.hooks.invocations(&paths.0.0).await))
+}
+
+/// `POST /api/hooks/:token`, called by CI. The token in the URL is the credential;
+/// the body is an optional `{"tag": "..."}`.
+pub async fn invoke_hook(
+    app: State<AppState>,
+    audit: AuditContext,
+    query: Query<AsyncQuery>,
+    paths: Path<(String,)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let signature = headers.get(SIGNATURE_HEADER).and_then(|v| v.to_str().ok());
+    let trigger = app
+        .hooks
+        .authorize(&paths.0.0, signature, &body, audit.source_ip.clone())
+        .await?;
+
+    let hooks = app.hooks.clone();
+    let redactor = app.env_redactor.clone();
+    let event = AuditContext {
+        actor: Some(format!("webhook:{}", trigger.hook_id)),
+        ..audit
+    }
+    .event("webhook_deploy", Some(trigger.service_id.clone()), Some(&trigger.image));
+    operations::run(&app, &query, event, async move {
+        hooks.deploy(trigger).await.map(|s| redactor.service(s))
+    }).await
+}
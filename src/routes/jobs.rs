@@ -0,0 +1,105 @@
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::service::jobs::{JobInfo, JobRun, JobSpec, RunTrigger};
+use crate::service::secrets;
+use crate::AppState;
+
+fn masked(app: &AppState, mut info: JobInfo) -> JobInfo {
+    info.job = app.env_redactor.job(info.job);
+    info
+}
+
+pub async fn list_jobs(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<JobInfo>>, AppError> {
+    auth_user.require_read()?;
+    let jobs = app.jobs.list().await;
+    Ok(Json(jobs.into_iter().map(|job| masked(&app, job)).collect()))
+}
+
+pub async fn get_job(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<JobInfo>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(masked(&app, app.jobs.get(&paths.0.0).await?)))
+}
+
+pub async fn create_job(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<JobSpec>,
+) -> Result<Json<JobInfo>, AppError> {
+    auth_user.require_admin()?;
+    auth_user.require_secrets(&secrets::references_in(payload.env.as_deref(), payload.secrets.as_deref()))?;
+    let event = audit.event("create_job", Some(payload.name.clone()), Some(&payload.0));
+    let result = app.jobs.create(payload.0).await;
+    app.audit.record(event, &result);
+    let job = result?;
+    Ok(Json(masked(&app, app.jobs.get(&job.id).await?)))
+}
+
+/// Env values sent back masked keep their current values.
+pub async fn update_job(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+    payload: Json<JobSpec>,
+) -> Result<Json<JobInfo>, AppError> {
+    auth_user.require_admin()?;
+    auth_user.require_secrets(&secrets::references_in(payload.env.as_deref(), payload.secrets.as_deref()))?;
+    let id = paths.0.0;
+    let event = audit.event("update_job", Some(id.clone()), Some(&payload.0));
+    let result = app.jobs.update(&id, payload.0).await;
+    app.audit.record(event, &result);
+    let job = result?;
+    Ok(Json(masked(&app, app.jobs.get(&job.id).await?)))
+}
+
+pub async fn delete_job(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_admin()?;
+    let id = paths.0.0;
+    let result = app.jobs.delete(&id).await;
+    app.audit.record(audit.event("delete_job", Some(id), None::<&()>), &result);
+    result?;
+    Ok(Json("Job deleted successfully".to_string()))
+}
+
+/// Starts a run now. A deploy scope for the job, or for the service it runs from, is
+/// enough. The run goes on in the background; poll it under `/runs`.
+pub async fn run_job(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<JobRun>, AppError> {
+    let id = paths.0.0;
+    let job = app.jobs.get(&id).await?.job;
+    let mut names = vec![job.id.as_str(), job.spec.name.as_str()];
+    names.extend(job.spec.service.as_deref());
+    auth_user.require_deploy(&names)?;
+
+    let result = app.jobs.trigger(&job.id, RunTrigger::Manual, Some(auth_user.email.clone())).await;
+    app.audit.record(audit.event("run_job", Some(job.spec.name.clone()), None::<&()>), &result);
+    Ok(Json(result?))
+}
+
+/// Newest first.
+pub async fn list_runs(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Vec<JobRun>>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(app.jobs.runs(&paths.0.0).await?))
+}
+
+pub async fn get_run(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    paths: Path<(String, String)>,
+) -> Result<Json<JobRun>, AppError> {
+    auth_user.require_read()?;
+    let (id, run_id) = paths.0;
+    Ok(Json(app.jobs.run(&id, &run_id).await?))
+}
@@ -1,2 +1,13 @@
+pub mod apply;
+pub mod audit;
 pub mod auth;
+pub mod builds;
+pub mod certificates;
+pub mod events;
+pub mod hooks;
+pub mod jobs;
+pub mod oidc;
+pub mod operations;
+pub mod secrets;
 pub mod services;
+pub mod tokens;
@@ -0,0 +1,74 @@
+use gotcha::axum::extract::Query;
+use gotcha::axum::response::{IntoResponse, Redirect, Response};
+use gotcha::{Json, State};
+use serde::Deserialize;
+use std::sync::Arc;
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::service::oidc::OidcManager;
+use crate::service::LoginResponse;
+use crate::AppState;
+
+/// What the identity provider sends the browser back with.
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+fn oidc(app: &AppState) -> Result<&Arc<OidcManager>, AppError> {
+    app.oidc
+        .as_ref()
+        .ok_or_else(|| AppError::Service("Single sign-on is not configured".to_string()))
+}
+
+/// Sends the browser to the identity provider.
+pub async fn oidc_login(app: State<AppState>) -> Result<Redirect, AppError> {
+    let url = oidc(&app)?.authorization_url().await?;
+    Ok(Redirect::to(&url))
+}
+
+/// Where the identity provider returns to. Signs the user in (creating them if
+/// allowed) and hands out a session token limited to the scopes of their groups.
+pub async fn oidc_callback(
+    app: State<AppState>,
+    audit: AuditContext,
+    query: Query<CallbackQuery>,
+) -> Result<Response, AppError> {
+    let oidc = oidc(&app)?;
+    let result = async {
+        if let Some(error) = &query.error {
+            return Err(AppError::Auth(format!(
+                "The identity provider refused the login: {} {}",
+                error,
+                query.error_description.as_deref().unwrap_or_default()
+            )));
+        }
+        let (Some(code), Some(state)) = (&query.code, &query.state) else {
+            return Err(AppError::Auth("The callback is missing code or state".to_string()));
+        };
+        let identity = oidc.complete(code, state).await?;
+        let email = app.user_manager.lock().await.sso_login(&identity, oidc.auto_provision()).await?;
+        let token = app.jwt_manager.lock().await.create_scoped_token(&email, Some(identity.scopes.clone()))?;
+        Ok((email, identity, token))
+    }
+    .await;
+
+    let actor = result.as_ref().ok().map(|(email, _, _)| email.clone());
+    let target = result.as_ref().ok().map(|(_, identity, _)| identity.subject.clone());
+    let groups = result.as_ref().ok().map(|(_, identity, _)| &identity.groups);
+    let audit = AuditContext { actor, ..audit };
+    app.audit.record(audit.event("oidc_login", target, groups), &result);
+    let (_, _, token) = result?;
+
+    match oidc.post_login_redirect() {
+        Some(url) => Ok(Redirect::to(&format!("{}#token={}", url, token)).into_response()),
+        None => Ok(Json(LoginResponse {
+            token: Some(token),
+            ..Default::default()
+        })
+        .into_response()),
+    }
+}
@@ -0,0 +1,84 @@
+use futures::stream::{self, Stream};
+use gotcha::axum::http::{header, StatusCode};
+use gotcha::axum::response::sse::{Event, KeepAlive, Sse};
+use gotcha::axum::response::{IntoResponse, Response};
+use gotcha::{Json, Path, State};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use crate::error::AppError;
+use crate::routes::auth::AuthUser;
+use crate::service::audit::AuditEvent;
+use crate::service::{Operation, OperationStatus};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct AsyncQuery {
+    /// Answer `202 Accepted` with the operation right away instead of waiting for it.
+    #[serde(default, rename = "async")]
+    pub run_async: bool,
+}
+
+/// Runs `work` as an operation and records `event` in the audit log once it is done.
+/// Without `?async=true` the response is the same as it always was, but the work still
+/// finishes (and is audited) if the client disconnects.
+pub async fn run<T, F>(app: &AppState, query: &AsyncQuery, event: AuditEvent, work: F) -> Result<Response, AppError>
+where
+    T: Serialize + Send + 'static,
+    F: Future<Output = Result<T, AppError>> + Send + 'static,
+{
+    let audit = app.audit.clone();
+    let kind = event.action.clone();
+    let target = event.target.clone();
+    let (operation, handle) = app.operations.spawn(&kind, target, async move {
+        let result = work.await;
+        audit.record(event, &result);
+        result
+    });
+    if query.run_async {
+        let location = format!("/api/operations/{}", operation.id);
+        return Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(operation)).into_response());
+    }
+
+    let result = handle
+        .await
+        .map_err(|e| AppError::Service(format!("Operation {} panicked: {}", operation.id, e)))??;
+    Ok(Json(result).into_response())
+}
+
+pub async fn list_operations(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<Operation>>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(app.operations.list()))
+}
+
+pub async fn get_operation(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Operation>, AppError> {
+    auth_user.require_read()?;
+    Ok(Json(app.operations.get(&paths.0.0)?))
+}
+
+pub async fn cancel_operation(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Operation>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.operations.cancel(&paths.0.0)?))
+}
+
+/// Server-sent events: the operation as it is now, then again after every change. The
+/// stream ends once it has finished.
+pub async fn operation_events(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    paths: Path<(String,)>,
+) -> Result<Sse<impl Stream<Item = Result<Event, gotcha::axum::Error>>>, AppError> {
+    auth_user.require_read()?;
+    let updates = app.operations.subscribe(&paths.0.0)?;
+
+    let events = stream::unfold((updates, true, false), |(mut updates, first, finished)| async move {
+        if finished || (!first && updates.changed().await.is_err()) {
+            return None;
+        }
+        let operation = updates.borrow_and_update().clone();
+        let finished = operation.status != OperationStatus::Running;
+        let event = Event::default().event("operation").json_data(&operation);
+        Some((event, (updates, false, finished)))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
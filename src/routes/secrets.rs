@@ -0,0 +1,52 @@
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::service::secrets::{PutSecretRequest, SecretInfo};
+use crate::AppState;
+
+pub async fn list_secrets(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<SecretInfo>>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.secrets.list().await))
+}
+
+pub async fn put_secret(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+    payload: Json<PutSecretRequest>,
+) -> Result<Json<SecretInfo>, AppError> {
+    auth_user.require_admin()?;
+    let name = paths.0.0;
+    let result = app.secrets.put(&name, &payload.value).await;
+    app.audit.record(audit.event("put_secret", Some(name), None::<&()>), &result);
+    Ok(Json(result?))
+}
+
+/// Refuses while any service still refers to the secret.
+pub async fn delete_secret(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_admin()?;
+    let name = paths.0.0;
+    let result = async {
+        let mut users = app.service_manager.services_using_secret(&name).await?;
+        users.extend(app.jobs.jobs_using_secret(&name).await.into_iter().map(|job| format!("job {}", job)));
+        if !users.is_empty() {
+            return Err(AppError::Service(format!(
+                "Secret {} is used by {}",
+                name,
+                users.join(", ")
+            )));
+        }
+        app.secrets.delete(&name).await
+    }
+    .await;
+    app.audit.record(audit.event("delete_secret", Some(name), None::<&()>), &result);
+    result?;
+    Ok(Json("Secret deleted successfully".to_string()))
+}
@@ -1,35 +1,163 @@
 use anyhow::Result;
-use gotcha::{debug_handler, Json, Path, State};
+use gotcha::axum::extract::Query;
+use gotcha::axum::response::Response;
+use gotcha::{debug_handler, GotchaContext, Json, Path, State};
+use serde::Deserialize;
 use crate::error::AppError;
-use crate::service::{CreateServiceRequest, Service, ServiceManager};
-use crate::{AppState};
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::{require_deploy_of, AuthUser};
+use crate::routes::operations::{self, AsyncQuery};
+use crate::service::secrets;
+use crate::service::{AdoptRequest, CreateServiceRequest, Discrepancy, DiscoveredContainer, RecoveryReport, ScaleRequest, Service};
+use crate::{AppState, Config};
 
-pub async fn list_services(app: State<AppState>) -> Result<Json<Vec<Service>>, AppError> {
-    let service_manager = app.service_manager.lock().await;
-    let services = service_manager.list_services().await?;
-    Ok(Json(services))
+pub async fn list_services(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<Service>>, AppError> {
+    auth_user.require_read()?;
+    let services = app.service_manager.list_services().await?;
+    Ok(Json(app.env_redactor.services(services)))
 }
 
-pub async fn create_service(app: State<AppState>, payload: Json<CreateServiceRequest>) -> Result<Json<Service>, AppError> {
-    let mut service_manager = app.service_manager.lock().await;
-    let service = service_manager.create_service(payload.0).await?;
-    Ok(Json(service))
+pub async fn create_service(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, query: Query<AsyncQuery>, payload: Json<CreateServiceRequest>) -> Result<Response, AppError> {
+    auth_user.require_deploy(&[&payload.name])?;
+    auth_user.require_secrets(&secrets::references(&payload))?;
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let event = audit.event("create_service", Some(payload.name.clone()), Some(&payload.0));
+    operations::run(&app, &query, event, async move {
+        service_manager.create_service(payload.0).await.map(|s| redactor.service(s))
+    }).await
 }
 
-pub async fn get_service(app: State<AppState>, paths: Path<(String,)>) -> Result<Json<Service>, AppError> {
-    let service_manager = app.service_manager.lock().await;
-    let service = service_manager.get_service(&paths.0.0).await?;
-    Ok(Json(service))
+pub async fn get_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>) -> Result<Json<Service>, AppError> {
+    auth_user.require_read()?;
+    let service = app.service_manager.get_service(&paths.0.0).await?;
+    Ok(Json(app.env_redactor.service(service)))
 }
 
-pub async fn update_service(app: State<AppState>, paths: Path<(String,)> , payload: Json<CreateServiceRequest>) -> Result<Json<Service>, AppError> {
-    let mut service_manager = app.service_manager.lock().await;
-    let service = service_manager.update_service(&paths.0.0, payload.0).await?;
-    Ok(Json(service))
+/// The service's env with nothing masked. Admin only, and every use is audited.
+pub async fn reveal_env(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, paths: Path<(String,)>) -> Result<Json<Vec<String>>, AppError> {
+    auth_user.require_admin()?;
+    let id = paths.0.0;
+    let result = app.service_manager.get_service(&id).await;
+    app.audit.record(audit.event("reveal_env", Some(id), None::<&()>), &result);
+    Ok(Json(result?.env.unwrap_or_default()))
+}
+
+pub async fn update_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>, audit: AuditContext, query: Query<AsyncQuery>, payload: Json<CreateServiceRequest>) -> Result<Response, AppError> {
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &id).await?;
+    let current = app.service_manager.get_service(&id).await?;
+    // Renaming moves the service under a name the scope has to cover as well.
+    if current.name != payload.name {
+        auth_user.require_deploy(&[&payload.name])?;
+    }
+    // References the service already has were allowed when they were added.
+    let existing = secrets::references(&current.spec());
+    auth_user.require_secrets(secrets::references(&payload).difference(&existing))?;
+    let event = audit.event("update_service", Some(id.clone()), Some(&payload.0));
+    operations::run(&app, &query, event, async move {
+        service_manager.update_service(&id, payload.0).await.map(|s| redactor.service(s))
+    }).await
+}
+#[debug_handler(state = GotchaContext<AppState, Config>)]
+pub async fn delete_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>, audit: AuditContext, query: Query<AsyncQuery>) -> Result<Response, AppError> {
+    let service_manager = app.service_manager.clone();
+    let id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &id).await?;
+    let event = audit.event("delete_service", Some(id.clone()), None::<&()>);
+    operations::run(&app, &query, event, async move {
+        service_manager.delete_service(&id).await?;
+        Ok("Service deleted successfully".to_string())
+    }).await
+}
+
+pub async fn start_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>, audit: AuditContext, query: Query<AsyncQuery>) -> Result<Response, AppError> {
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &id).await?;
+    let event = audit.event("start_service", Some(id.clone()), None::<&()>);
+    operations::run(&app, &query, event, async move {
+        service_manager.start_service(&id).await.map(|s| redactor.service(s))
+    }).await
+}
+
+pub async fn stop_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>, audit: AuditContext, query: Query<AsyncQuery>) -> Result<Response, AppError> {
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &id).await?;
+    let event = audit.event("stop_service", Some(id.clone()), None::<&()>);
+    operations::run(&app, &query, event, async move {
+        service_manager.stop_service(&id).await.map(|s| redactor.service(s))
+    }).await
+}
+
+/// Adds or removes replicas; the ones that stay keep running untouched.
+pub async fn scale_service(app: State<AppState>, auth_user: AuthUser, paths: Path<(String,)>, audit: AuditContext, query: Query<AsyncQuery>, payload: Json<ScaleRequest>) -> Result<Response, AppError> {
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let id = paths.0.0;
+    require_deploy_of(&auth_user, &app, &id).await?;
+    let event = audit.event("scale_service", Some(id.clone()), Some(&payload.0));
+    operations::run(&app, &query, event, async move {
+        service_manager.scale_service(&id, payload.replicas).await.map(|s| redactor.service(s))
+    }).await
+}
+
+pub async fn start_all(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, query: Query<AsyncQuery>) -> Result<Response, AppError> {
+    auth_user.require_admin()?;
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let event = audit.event("start_all", None, None::<&()>);
+    operations::run(&app, &query, event, async move {
+        service_manager.start_all().await.map(|s| redactor.services(s))
+    }).await
+}
+
+pub async fn stop_all(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, query: Query<AsyncQuery>) -> Result<Response, AppError> {
+    auth_user.require_admin()?;
+    let service_manager = app.service_manager.clone();
+    let redactor = app.env_redactor.clone();
+    let event = audit.event("stop_all", None, None::<&()>);
+    operations::run(&app, &query, event, async move {
+        service_manager.stop_all().await.map(|s| redactor.services(s))
+    }).await
+}
+
+pub async fn discover(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<DiscoveredContainer>>, AppError> {
+    auth_user.require_read()?;
+    let containers = app.service_manager.discover().await?;
+    Ok(Json(containers))
+}
+
+pub async fn adopt_service(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, payload: Json<AdoptRequest>) -> Result<Json<Service>, AppError> {
+    auth_user.require_admin()?;
+    let event = audit.event("adopt_service", Some(payload.container.clone()), Some(&payload.0));
+    let result = app.service_manager.adopt(payload.0).await;
+    app.audit.record(event, &result);
+    Ok(Json(app.env_redactor.service(result?)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecoverQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+pub async fn discrepancies(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<Discrepancy>>, AppError> {
+    auth_user.require_read()?;
+    let discrepancies = app.service_manager.discrepancies().await?;
+    Ok(Json(discrepancies))
+}
+
+pub async fn recover(app: State<AppState>, auth_user: AuthUser, audit: AuditContext, query: Query<RecoverQuery>) -> Result<Json<RecoveryReport>, AppError> {
+    auth_user.require_admin()?;
+    let result = app.service_manager.recover(query.dry_run).await;
+    if !query.dry_run {
+        app.audit.record(audit.event("recover", None, None::<&()>), &result);
+    }
+    Ok(Json(app.env_redactor.report(result?)))
 }
-#[debug_handler]
-pub async fn delete_service(app: State<AppState>, paths: Path<(String,)>) -> Result<Json<String>, AppError> {
-    let mut service_manager = app.service_manager.lock().await;
-    service_manager.delete_service(&paths.0.0).await?;
-    Ok(Json("Service deleted successfully".to_string()))
-} 
\ No newline at end of file
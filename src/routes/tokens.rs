@@ -0,0 +1,39 @@
+use gotcha::{Json, Path, State};
+use crate::error::AppError;
+use crate::routes::audit::AuditContext;
+use crate::routes::auth::AuthUser;
+use crate::service::tokens::{ApiToken, CreateTokenRequest, CreatedToken};
+use crate::AppState;
+
+pub async fn list_tokens(app: State<AppState>, auth_user: AuthUser) -> Result<Json<Vec<ApiToken>>, AppError> {
+    auth_user.require_admin()?;
+    Ok(Json(app.tokens.list().await))
+}
+
+/// The token is in the response and nowhere else; only its hash is kept.
+pub async fn create_token(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    payload: Json<CreateTokenRequest>,
+) -> Result<Json<CreatedToken>, AppError> {
+    auth_user.require_admin()?;
+    let event = audit.event("create_token", Some(payload.name.clone()), Some(&payload.0));
+    let result = app.tokens.create(&auth_user.email, payload.0).await;
+    app.audit.record(event, &result);
+    Ok(Json(result?))
+}
+
+pub async fn revoke_token(
+    app: State<AppState>,
+    auth_user: AuthUser,
+    audit: AuditContext,
+    paths: Path<(String,)>,
+) -> Result<Json<String>, AppError> {
+    auth_user.require_admin()?;
+    let id = paths.0.0;
+    let result = app.tokens.revoke(&id).await;
+    app.audit.record(audit.event("revoke_token", Some(id), None::<&()>), &result);
+    result?;
+    Ok(Json("Token revoked successfully".to_string()))
+}
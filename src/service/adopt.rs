@@ -0,0 +1,148 @@
+use bollard::models::{
+    ContainerConfig, ContainerInspectResponse, HealthConfig, MountPointTypeEnum, RestartPolicyNameEnum,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::service::labels;
+use crate::service::models::{CreateServiceRequest, HealthCheck, MountSpec, PortMapping, RestartPolicy};
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DiscoveredContainer {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub state: String,
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct AdoptRequest {
+    /// Container id or name.
+    pub container: String,
+    /// Service name to register under; defaults to the container name.
+    pub name: Option<String>,
+}
+
+/// Reconstructs the spec a container was created from.
+///
+/// Docker reports the effective configuration, which includes whatever the image
+/// defines, so `image` (the image's own config) is used to strip the defaults back out.
+/// Otherwise every adopted service would pin `PATH` and friends forever.
+pub fn spec_from_inspect(
+    name: &str,
+    container: &ContainerInspectResponse,
+    image: Option<&ContainerConfig>,
+) -> CreateServiceRequest {
+    let config = container.config.clone().unwrap_or_default();
+    let host_config = container.host_config.clone().unwrap_or_default();
+    let image_config = image.cloned().unwrap_or_default();
+
+    let command = config.cmd.filter(|cmd| Some(cmd) != image_config.cmd.as_ref());
+
+    let image_env = image_config.env.unwrap_or_default();
+    let env: Vec<String> = config
+        .env
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|e| !image_env.contains(e))
+        .collect();
+
+    let image_labels = image_config.labels.unwrap_or_default();
+    let mut labels: HashMap<String, String> = config
+        .labels
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(k, v)| image_labels.get(k) != Some(v))
+        .collect();
+    labels::strip(&mut labels);
+
+    let mut ports = Vec::new();
+    for (container_port, bindings) in host_config.port_bindings.unwrap_or_default() {
+        let (port, protocol) = container_port
+            .split_once('/')
+            .unwrap_or((container_port.as_str(), "tcp"));
+        let Ok(port) = port.parse::<u16>() else {
+            continue;
+        };
+        for binding in bindings.unwrap_or_default() {
+            if let Some(Ok(host_port)) = binding.host_port.as_deref().map(str::parse::<u16>) {
+                ports.push(PortMapping {
+                    host_port,
+                    container_port: port,
+                    protocol: (protocol != "tcp").then(|| protocol.to_string()),
+                });
+            }
+        }
+    }
+    ports.sort_by_key(|p| (p.container_port, p.host_port));
+
+    let mounts: Vec<MountSpec> = container
+        .mounts
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|m| {
+            let source = match m.typ {
+                Some(MountPointTypeEnum::VOLUME) => m.name?,
+                Some(MountPointTypeEnum::BIND) => m.source?,
+                // tmpfs and friends have nothing to point back to
+                _ => return None,
+            };
+            Some(MountSpec {
+                source,
+                target: m.destination?,
+                read_only: !m.rw.unwrap_or(true),
+            })
+        })
+        .collect();
+
+    let restart_policy = host_config.restart_policy.and_then(|p| match p.name? {
+        RestartPolicyNameEnum::ALWAYS => Some(RestartPolicy::Always),
+        RestartPolicyNameEnum::UNLESS_STOPPED => Some(RestartPolicy::UnlessStopped),
+        RestartPolicyNameEnum::ON_FAILURE => Some(RestartPolicy::OnFailure {
+            max_retries: p.maximum_retry_count.filter(|n| *n > 0),
+        }),
+        RestartPolicyNameEnum::NO | RestartPolicyNameEnum::EMPTY => None,
+    });
+
+    let healthcheck = config
+        .healthcheck
+        .filter(|h| Some(h) != image_config.healthcheck.as_ref())
+        .and_then(health_check_from);
+
+    CreateServiceRequest {
+        name: name.to_string(),
+        image: config.image.unwrap_or_default(),
+        command,
+        env: (!env.is_empty()).then_some(env),
+        ports: (!ports.is_empty()).then_some(ports),
+        healthcheck,
+        depends_on: None,
+        mounts: (!mounts.is_empty()).then_some(mounts),
+        restart_policy,
+        labels: (!labels.is_empty()).then_some(labels),
+        stack: None,
+        secrets: None,
+        sensitive_env: None,
+        replicas: None,
+        domains: None,
+        routes: None,
+    }
+}
+
+fn health_check_from(config: HealthConfig) -> Option<HealthCheck> {
+    let test = config.test.filter(|t| !t.is_empty() && t[0] != "NONE")?;
+    let secs = |nanos: Option<i64>| {
+        nanos
+            .filter(|n| *n > 0)
+            .map(|n| Duration::from_nanos(n as u64).as_secs())
+    };
+    Some(HealthCheck {
+        test,
+        interval_secs: secs(config.interval),
+        timeout_secs: secs(config.timeout),
+        retries: config.retries.filter(|r| *r > 0).map(|r| r as u32),
+    })
+}
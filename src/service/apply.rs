@@ -0,0 +1,313 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::service::dependency::{self, DependencyGraph};
+use crate::service::models::{CreateServiceRequest, Service};
+use crate::service::redact;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Spec fields that can change without recreating the containers: `depends_on` and
+/// `routes` only matter to Longshoreman, and `replicas` adds or removes containers but
+/// leaves the rest alone.
+const IN_PLACE_FIELDS: &[&str] = &["depends_on", "replicas", "routes"];
+
+/// The complete set of services a host should be running.
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct DesiredState {
+    pub services: Vec<CreateServiceRequest>,
+}
+
+impl DesiredState {
+    /// Parses a desired state document; `format` is a content type or a bare
+    /// `json`/`yaml`/`toml`, defaulting to JSON.
+    pub fn parse(format: Option<&str>, body: &str) -> Result<Self> {
+        let format = format.unwrap_or("json").to_ascii_lowercase();
+        if format.contains("yaml") || format.contains("yml") {
+            serde_yaml::from_str(body)
+                .map_err(|e| AppError::Service(format!("Invalid YAML desired state: {}", e)))
+        } else if format.contains("toml") {
+            toml::from_str(body)
+                .map_err(|e| AppError::Service(format!("Invalid TOML desired state: {}", e)))
+        } else {
+            Ok(serde_json::from_str(body)?)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Create,
+    /// Only Longshoreman metadata or the replica count changes; existing containers are
+    /// kept.
+    Update,
+    /// The containers have to be replaced.
+    Recreate,
+    Delete,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FieldDiff {
+    pub field: String,
+    pub current: Value,
+    pub desired: Value,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PlanStep {
+    pub service: String,
+    pub action: PlanAction,
+    /// Id of the existing service, if there is one.
+    pub id: Option<String>,
+    pub diffs: Vec<FieldDiff>,
+    #[serde(skip)]
+    pub desired: Option<CreateServiceRequest>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+    /// Existing services missing from the desired state that are left alone because
+    /// pruning was not requested.
+    pub unmanaged: Vec<String>,
+}
+
+impl Plan {
+    pub fn has_changes(&self) -> bool {
+        self.steps.iter().any(|s| s.action != PlanAction::Unchanged)
+    }
+}
+
+/// Compares `current` with `desired` and works out what has to happen to get there.
+///
+/// Steps are ordered for execution: creates and changes in dependency order of the
+/// resulting state, then deletes with dependents before their dependencies.
+pub fn compute_plan(current: &[Service], desired: &DesiredState, prune: bool) -> Result<Plan> {
+    for (i, spec) in desired.services.iter().enumerate() {
+        if desired.services[..i].iter().any(|s| s.name == spec.name) {
+            return Err(AppError::Service(format!(
+                "Service {} appears more than once in the desired state",
+                spec.name
+            )));
+        }
+    }
+
+    let unlisted: Vec<&Service> = current
+        .iter()
+        .filter(|s| !desired.services.iter().any(|d| d.name == s.name))
+        .collect();
+
+    // The state we would end up in has to be a valid dependency graph on its own.
+    let mut resulting: DependencyGraph = desired
+        .services
+        .iter()
+        .map(|s| (s.name.clone(), dependency::dependency_names(s.depends_on.as_deref())))
+        .collect();
+    if !prune {
+        for service in &unlisted {
+            resulting.insert(
+                service.name.clone(),
+                dependency::dependency_names(service.depends_on.as_deref()),
+            );
+        }
+    }
+    let order = dependency::start_order(&resulting)?;
+
+    let mut steps = Vec::new();
+    for name in &order {
+        let Some(spec) = desired.services.iter().find(|s| &s.name == name) else {
+            continue;
+        };
+        let existing = current.iter().find(|s| &s.name == name);
+        // A spec read back from the API has its secret env values masked.
+        let mut spec = spec.clone();
+        redact::unmask(&mut spec, existing.map(Service::spec).as_ref())?;
+        let step = match existing {
+            None => PlanStep {
+                service: name.clone(),
+                action: PlanAction::Create,
+                id: None,
+                diffs: diff(None, &spec)?,
+                desired: Some(spec),
+            },
+            Some(existing) => {
+                let diffs = diff(Some(&existing.spec()), &spec)?;
+                let action = if diffs.is_empty() {
+                    PlanAction::Unchanged
+                } else if diffs.iter().all(|d| IN_PLACE_FIELDS.contains(&d.field.as_str())) {
+                    PlanAction::Update
+                } else {
+                    PlanAction::Recreate
+                };
+                PlanStep {
+                    service: name.clone(),
+                    action,
+                    id: Some(existing.id.clone()),
+                    diffs,
+                    desired: Some(spec),
+                }
+            }
+        };
+        steps.push(step);
+    }
+
+    let mut unmanaged = Vec::new();
+    if prune {
+        let current_order = dependency::start_order(&dependency::graph_of(current))?;
+        for name in current_order.iter().rev() {
+            if let Some(service) = unlisted.iter().find(|s| &s.name == name) {
+                steps.push(PlanStep {
+                    service: service.name.clone(),
+                    action: PlanAction::Delete,
+                    id: Some(service.id.clone()),
+                    diffs: Vec::new(),
+                    desired: None,
+                });
+            }
+        }
+    } else {
+        unmanaged = unlisted.iter().map(|s| s.name.clone()).collect();
+    }
+
+    Ok(Plan { steps, unmanaged })
+}
+
+/// Field-level differences between two specs; `current` of `None` diffs against nothing.
+fn diff(current: Option<&CreateServiceRequest>, desired: &CreateServiceRequest) -> Result<Vec<FieldDiff>> {
+    let current = match current {
+        Some(spec) => serde_json::to_value(spec)?,
+        None => Value::Object(Default::default()),
+    };
+    let desired = serde_json::to_value(desired)?;
+
+    let (Value::Object(current), Value::Object(desired)) = (current, desired) else {
+        return Ok(Vec::new());
+    };
+
+    let mut diffs = Vec::new();
+    for (field, desired_value) in desired {
+        let current_value = current.get(&field).cloned().unwrap_or(Value::Null);
+        if current_value != desired_value {
+            diffs.push(FieldDiff {
+                field,
+                current: current_value,
+                desired: desired_value,
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn service(name: &str, image: &str, extra: Value) -> Service {
+        let mut service = json!({
+            "id": format!("{}-id", name),
+            "container_id": "c",
+            "containers": [],
+            "revision": 1,
+            "name": name,
+            "image": image,
+            "status": "running",
+        });
+        service.as_object_mut().unwrap().extend(extra.as_object().cloned().unwrap_or_default());
+        serde_json::from_value(service).unwrap()
+    }
+
+    fn desired(services: Value) -> DesiredState {
+        serde_json::from_value(json!({ "services": services })).unwrap()
+    }
+
+    fn actions(plan: &Plan) -> Vec<(&str, PlanAction)> {
+        plan.steps.iter().map(|s| (s.service.as_str(), s.action)).collect()
+    }
+
+    #[test]
+    fn changes_are_classified() {
+        let current = [
+            service("db", "postgres:15", json!({})),
+            service("app", "app:1", json!({"replicas": 1})),
+            service("web", "nginx:1", json!({})),
+        ];
+        let desired = desired(json!([
+            {"name": "db", "image": "postgres:16"},
+            {"name": "app", "image": "app:1", "replicas": 3},
+            {"name": "web", "image": "nginx:1"},
+            {"name": "cache", "image": "redis:7"},
+        ]));
+        let plan = compute_plan(&current, &desired, false).unwrap();
+        assert_eq!(
+            actions(&plan),
+            [
+                ("app", PlanAction::Update),
+                ("cache", PlanAction::Create),
+                ("db", PlanAction::Recreate),
+                ("web", PlanAction::Unchanged),
+            ]
+        );
+        assert_eq!(plan.steps[2].diffs[0].field, "image");
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn creates_follow_dependency_order() {
+        let desired = desired(json!([
+            {"name": "app", "image": "app:1", "depends_on": [{"service": "db"}]},
+            {"name": "db", "image": "postgres:16"},
+        ]));
+        let plan = compute_plan(&[], &desired, false).unwrap();
+        assert_eq!(actions(&plan), [("db", PlanAction::Create), ("app", PlanAction::Create)]);
+    }
+
+    #[test]
+    fn unlisted_services_are_deleted_dependents_first_only_when_pruning() {
+        let current = [
+            service("db", "postgres:16", json!({})),
+            service("app", "app:1", json!({"depends_on": [{"service": "db"}]})),
+            service("web", "nginx:1", json!({})),
+        ];
+        let desired = desired(json!([{"name": "web", "image": "nginx:1"}]));
+
+        let kept = compute_plan(&current, &desired, false).unwrap();
+        assert_eq!(kept.unmanaged, ["db", "app"]);
+        assert!(!kept.has_changes());
+
+        let pruned = compute_plan(&current, &desired, true).unwrap();
+        assert_eq!(
+            actions(&pruned),
+            [("web", PlanAction::Unchanged), ("app", PlanAction::Delete), ("db", PlanAction::Delete)]
+        );
+    }
+
+    #[test]
+    fn invalid_desired_states_are_refused() {
+        let twice = desired(json!([{"name": "a", "image": "x"}, {"name": "a", "image": "y"}]));
+        assert!(compute_plan(&[], &twice, false).is_err());
+
+        let cycle = desired(json!([
+            {"name": "a", "image": "x", "depends_on": [{"service": "b"}]},
+            {"name": "b", "image": "x", "depends_on": [{"service": "a"}]},
+        ]));
+        assert!(compute_plan(&[], &cycle, false).is_err());
+
+        // Dropping a dependency a kept service still needs breaks the graph.
+        let current = [service("db", "postgres:16", json!({}))];
+        let orphaned = desired(json!([{"name": "app", "image": "x", "depends_on": [{"service": "db"}]}]));
+        assert!(compute_plan(&current, &orphaned, true).is_err());
+        assert!(compute_plan(&current, &orphaned, false).is_ok());
+    }
+
+    #[test]
+    fn masked_env_keeps_the_current_value() {
+        let current = [service("app", "app:1", json!({"env": ["PASSWORD=hunter2"]}))];
+        let desired = desired(json!([{"name": "app", "image": "app:1", "env": [format!("PASSWORD={}", redact::MASK)]}]));
+        let plan = compute_plan(&current, &desired, false).unwrap();
+        assert_eq!(actions(&plan), [("app", PlanAction::Unchanged)]);
+    }
+}
@@ -0,0 +1,206 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// `audit.log` is rotated once it grows past this size.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated files kept as `audit.log.1` (newest) to `audit.log.<n>`.
+const ROTATED_FILES: usize = 9;
+const DEFAULT_LIMIT: usize = 100;
+
+/// Keys whose values never end up in the log, matched case-insensitively as substrings.
+const SENSITIVE_KEYS: &[&str] = &["password", "secret", "token", "key", "credential"];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The authenticated user, or for logins the account being logged into.
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    /// The request body with anything secret-looking redacted.
+    pub request: Option<Value>,
+    pub source_ip: Option<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+/// What is being done and by whom; the outcome is added by [`AuditLog::record`].
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub actor: Option<String>,
+    pub source_ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub request: Option<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// At most this many entries, the most recent ones. Defaults to 100.
+    pub limit: Option<usize>,
+}
+
+/// Append-only log of mutating actions, one JSON object per line in
+/// `<data_dir>/audit.log`.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    // Serializes appends and rotation.
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn open(data_dir: &str) -> Self {
+        Self {
+            path: Path::new(data_dir).join("audit.log"),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Appends `event` with the outcome of `result`. A failure to write is logged but
+    /// not returned: the action itself has already happened.
+    pub fn record<T>(&self, event: AuditEvent, result: &Result<T>) {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            actor: event.actor,
+            action: event.action,
+            target: event.target,
+            request: event.request,
+            source_ip: event.source_ip,
+            outcome: if result.is_ok() { Outcome::Success } else { Outcome::Failure },
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        if let Err(e) = tokio::task::block_in_place(|| self.append(&entry)) {
+            tracing::error!("failed to write audit entry for {}: {}", entry.action, e);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        if fs::metadata(&self.path).map(|m| m.len() >= MAX_FILE_SIZE).unwrap_or(false) {
+            self.rotate()?;
+        }
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let _ = fs::remove_file(self.rotated(ROTATED_FILES));
+        for n in (1..ROTATED_FILES).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        Ok(())
+    }
+
+    /// Entries matching `query`, oldest first, including rotated files.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+        tokio::task::block_in_place(|| {
+            let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+            let files = (1..=ROTATED_FILES).rev().map(|n| self.rotated(n)).chain([self.path.clone()]);
+
+            let mut entries = Vec::new();
+            for path in files {
+                let file = match File::open(&path) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    // A torn last line from a crash mid-append is skipped, not fatal.
+                    let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                        continue;
+                    };
+                    if query.matches(&entry) {
+                        entries.push(entry);
+                    }
+                }
+            }
+
+            let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+            let skip = entries.len().saturating_sub(limit);
+            Ok(entries.split_off(skip))
+        })
+    }
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor.as_ref().is_none_or(|a| entry.actor.as_ref() == Some(a))
+            && self.target.as_ref().is_none_or(|t| entry.target.as_ref() == Some(t))
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at <= until)
+    }
+}
+
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SENSITIVE_KEYS.iter().any(|s| key.contains(s))
+}
+
+/// Serializes `request` for the audit log, replacing the values of secret-looking
+/// fields and `NAME=value` environment entries with a marker.
+pub fn redact(request: &impl Serialize) -> Option<Value> {
+    let mut value = serde_json::to_value(request).ok()?;
+    redact_value(&mut value);
+    Some(value)
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive(key) && !value.is_null() {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        Value::String(s) => {
+            if let Some((name, _)) = s.split_once('=') {
+                if is_sensitive(name) {
+                    *s = format!("{}={}", name, REDACTED);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -5,6 +5,7 @@ use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation}
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::error::AppError;
+use crate::service::tokens::Scope;
 
 type Result<T> = std::result::Result<T, AppError>;
 
@@ -13,6 +14,9 @@ pub struct Claims {
     pub sub: String,        // email
     pub exp: i64,          // expiration time
     pub server_id: String, // server UUID
+    /// What the session may do; everything when not set, as for password logins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,6 +46,12 @@ impl JwtManager {
     }
 
     pub fn create_token(&self, email: &str) -> Result<String> {
+        self.create_scoped_token(email, None)
+    }
+
+    /// A token limited to `scopes`, for users whose rights come from elsewhere (such as
+    /// their identity provider's groups).
+    pub fn create_scoped_token(&self, email: &str, scopes: Option<Vec<Scope>>) -> Result<String> {
         let expiration = Utc::now()
             .checked_add_signed(Duration::hours(24))
             .expect("valid timestamp")
@@ -51,6 +61,7 @@ impl JwtManager {
             sub: email.to_string(),
             exp: expiration,
             server_id: self.server_id.clone(),
+            scopes,
         };
 
         let token = encode(
@@ -0,0 +1,389 @@
+use bollard::image::{BuildImageOptions, TagImageOptions};
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::manager::ServiceManager;
+use crate::service::operations;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Builds kept in the history; the oldest are dropped first.
+const MAX_HISTORY: usize = 100;
+/// Log lines kept per build in the history. The full log is in the build's operation.
+const MAX_LOG_LINES: usize = 500;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildRequest {
+    /// Directory on the host to build from, such as a git working copy. Without it the
+    /// request body has to be a tar of the build context.
+    pub context_path: Option<String>,
+    /// Path of the Dockerfile inside the context; `Dockerfile` when not given.
+    pub dockerfile: Option<String>,
+    /// Names to tag the image with, e.g. `myapp:1.2`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub build_args: HashMap<String, String>,
+    /// Stage of a multi-stage Dockerfile to stop at.
+    pub target: Option<String>,
+    #[serde(default)]
+    pub no_cache: bool,
+    /// Always pull a newer version of the base images.
+    #[serde(default)]
+    pub pull: bool,
+    /// Service to redeploy with the new image once the build succeeds.
+    pub deploy: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A build as kept in the history. Build args are left out, they often hold tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Build {
+    pub id: String,
+    pub status: BuildStatus,
+    /// The host path built from, or `upload` for an uploaded context.
+    pub context: String,
+    pub dockerfile: Option<String>,
+    pub tags: Vec<String>,
+    pub target: Option<String>,
+    pub image_id: Option<String>,
+    pub deploy: Option<String>,
+    /// Revision of the deployed service, once the deploy went through.
+    pub deployed_revision: Option<u64>,
+    pub error: Option<String>,
+    /// The last lines of the build output.
+    pub logs: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Builds images with the Docker daemon and remembers how each build went in
+/// `<data_dir>/builds.json`.
+#[derive(Debug)]
+pub struct BuildManager {
+    docker: Docker,
+    service_manager: Arc<ServiceManager>,
+    history: FsStruct<Vec<Build>>,
+}
+
+impl BuildManager {
+    pub async fn open(data_dir: &str, docker: Docker, service_manager: Arc<ServiceManager>) -> Result<Self> {
+        let history: FsStruct<Vec<Build>> = FsStruct::open(format!("{}/builds.json", data_dir)).await?;
+        // Builds still running when Longshoreman went down will never finish.
+        history
+            .transaction(|builds| {
+                for build in builds.iter_mut().filter(|b| b.status == BuildStatus::Running) {
+                    build.status = BuildStatus::Failed;
+                    build.error = Some("Interrupted by a restart".to_string());
+                }
+                Ok(())
+            })
+            .await?;
+        Ok(Self {
+            docker,
+            service_manager,
+            history,
+        })
+    }
+
+    /// The history, newest first.
+    pub async fn list(&self) -> Vec<Build> {
+        let mut builds = self.history.snapshot().await;
+        builds.reverse();
+        builds
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Build> {
+        self.history
+            .snapshot()
+            .await
+            .into_iter()
+            .find(|b| b.id == id)
+            .ok_or_else(|| AppError::Service("Build not found".to_string()))
+    }
+
+    /// Builds from `context` (a tar archive), or from `request.context_path` when no
+    /// archive was uploaded, then deploys the image if asked to.
+    pub async fn build(&self, request: BuildRequest, context: Option<Vec<u8>>) -> Result<Build> {
+        let (context, label) = match (context, &request.context_path) {
+            (Some(context), None) => (context, "upload".to_string()),
+            (None, Some(path)) => (archive_dir(PathBuf::from(path)).await?, path.clone()),
+            (Some(_), Some(_)) => {
+                return Err(AppError::Service(
+                    "Give either a context_path or an uploaded context, not both".to_string(),
+                ))
+            }
+            (None, None) => {
+                return Err(AppError::Service(
+                    "A build needs a context_path or a tar of the build context".to_string(),
+                ))
+            }
+        };
+
+        let mut build = Build {
+            id: Uuid::new_v4().to_string(),
+            status: BuildStatus::Running,
+            context: label,
+            dockerfile: request.dockerfile.clone(),
+            tags: request.tags.clone(),
+            target: request.target.clone(),
+            image_id: None,
+            deploy: request.deploy.clone(),
+            deployed_revision: None,
+            error: None,
+            logs: Vec::new(),
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+        self.save(&build).await?;
+
+        let result = self.run(&request, context, &mut build).await;
+        build.finished_at = Some(Utc::now());
+        match &result {
+            Ok(()) => build.status = BuildStatus::Succeeded,
+            Err(e) => {
+                build.status = BuildStatus::Failed;
+                build.error = Some(e.to_string());
+            }
+        }
+        self.save(&build).await?;
+        result.map(|()| build)
+    }
+
+    async fn run(&self, request: &BuildRequest, context: Vec<u8>, build: &mut Build) -> Result<()> {
+        operations::step(format!("building {}", build.tags.first().map_or("image", String::as_str)));
+        let mut dockerfile = request.dockerfile.clone().unwrap_or_else(|| "Dockerfile".to_string());
+        let mut context = context;
+        if let Some(target) = &request.target {
+            (context, dockerfile) = with_target(context, &dockerfile, target)?;
+        }
+        let options = BuildImageOptions {
+            dockerfile,
+            t: request.tags.first().cloned().unwrap_or_default(),
+            buildargs: request.build_args.clone(),
+            nocache: request.no_cache,
+            pull: request.pull,
+            rm: true,
+            ..Default::default()
+        };
+
+        let mut output = self.docker.build_image(options, None, Some(context.into()));
+        while let Some(info) = output.try_next().await? {
+            operations::checkpoint()?;
+            if let Some(error) = info.error {
+                return Err(AppError::Service(format!("Build failed: {}", error.trim())));
+            }
+            if let Some(id) = info.aux.and_then(|aux| aux.id) {
+                build.image_id = Some(id);
+            }
+            for line in info.stream.iter().chain(info.status.iter()).flat_map(|s| s.lines()) {
+                let line = line.trim_end();
+                if line.is_empty() {
+                    continue;
+                }
+                operations::log(line);
+                if build.logs.len() == MAX_LOG_LINES {
+                    build.logs.remove(0);
+                }
+                build.logs.push(line.to_string());
+            }
+        }
+        let image_id = build
+            .image_id
+            .clone()
+            .ok_or_else(|| AppError::Service("Docker did not report the built image".to_string()))?;
+
+        for tag in request.tags.iter().skip(1) {
+            let (repo, tag) = split_tag(tag);
+            self.docker
+                .tag_image(&image_id, Some(TagImageOptions { repo, tag }))
+                .await?;
+        }
+
+        if let Some(name) = &request.deploy {
+            operations::step(format!("deploying {}", name));
+            // The first tag reads better in the service spec than a bare image id.
+            let image = request.tags.first().unwrap_or(&image_id);
+            let service = self.service_manager.deploy_image(name, image).await?;
+            build.deployed_revision = Some(service.revision);
+        }
+        Ok(())
+    }
+
+    async fn save(&self, build: &Build) -> Result<()> {
+        let build = build.clone();
+        self.history
+            .transaction(|builds| {
+                match builds.iter_mut().find(|b| b.id == build.id) {
+                    Some(existing) => *existing = build,
+                    None => {
+                        builds.push(build);
+                        let excess = builds.len().saturating_sub(MAX_HISTORY);
+                        builds.drain(..excess);
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// `repo:tag` as Docker's tag endpoint wants it, `latest` when there is no tag.
+fn split_tag(reference: &str) -> (&str, &str) {
+    match reference.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+        _ => (reference, "latest"),
+    }
+}
+
+/// Name of the generated Dockerfile that stops at the target stage.
+const TARGET_DOCKERFILE: &str = ".longshoreman-target.Dockerfile";
+
+/// The Docker client library has no `target` option, so the Dockerfile is cut off
+/// after the target stage and added to the context under [`TARGET_DOCKERFILE`]. Later
+/// stages are never built, as with `docker build --target`. Only works on an
+/// uncompressed tar.
+fn with_target(context: Vec<u8>, dockerfile: &str, target: &str) -> Result<(Vec<u8>, String)> {
+    if context.starts_with(&[0x1f, 0x8b]) {
+        return Err(AppError::Service(
+            "Building a target stage needs an uncompressed tar as the build context".to_string(),
+        ));
+    }
+    let wanted = normalize(Path::new(dockerfile));
+
+    let mut original = None;
+    let mut rebuilt = tar::Builder::new(Vec::new());
+    for entry in tar::Archive::new(context.as_slice()).entries()? {
+        let mut entry = entry?;
+        if normalize(&entry.path()?) == wanted {
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut contents)?;
+            // Reading the entry consumed it, so it is put back from the copy.
+            let header = entry.header().clone();
+            rebuilt.append(&header, contents.as_bytes())?;
+            original = Some(contents);
+        } else {
+            let header = entry.header().clone();
+            rebuilt.append(&header, &mut entry)?;
+        }
+    }
+    let original = original
+        .ok_or_else(|| AppError::Service(format!("The build context has no {}", dockerfile)))?;
+
+    let truncated = truncate_at_stage(&original, target)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(truncated.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    rebuilt.append_data(&mut header, TARGET_DOCKERFILE, truncated.as_bytes())?;
+    Ok((rebuilt.into_inner()?, TARGET_DOCKERFILE.to_string()))
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect()
+}
+
+/// `dockerfile` up to the end of the stage named `target` (`FROM image AS target`).
+fn truncate_at_stage(dockerfile: &str, target: &str) -> Result<String> {
+    let mut kept = Vec::new();
+    let mut in_target = false;
+    for line in dockerfile.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first().is_some_and(|w| w.eq_ignore_ascii_case("FROM")) {
+            if in_target {
+                return Ok(kept.join("\n"));
+            }
+            in_target = words.len() >= 2
+                && words[words.len() - 2].eq_ignore_ascii_case("AS")
+                && words[words.len() - 1].eq_ignore_ascii_case(target);
+        }
+        kept.push(line);
+    }
+    if !in_target {
+        return Err(AppError::Service(format!("The Dockerfile has no stage named {}", target)));
+    }
+    Ok(kept.join("\n"))
+}
+
+/// A tar of everything under `dir` except the `.git` directory at its root. Unlike
+/// the docker CLI this does not read `.dockerignore`.
+async fn archive_dir(dir: PathBuf) -> Result<Vec<u8>> {
+    if !dir.is_absolute() || !dir.is_dir() {
+        return Err(AppError::Service(format!(
+            "Build context {} is not an absolute path to a directory",
+            dir.display()
+        )));
+    }
+    tokio::task::spawn_blocking(move || {
+        let mut archive = tar::Builder::new(Vec::new());
+        archive.follow_symlinks(false);
+        append_dir(&mut archive, &dir, Path::new(""))?;
+        Ok(archive.into_inner()?)
+    })
+    .await
+    .map_err(|e| AppError::Service(format!("Packing the build context panicked: {}", e)))?
+}
+
+fn append_dir(archive: &mut tar::Builder<Vec<u8>>, root: &Path, relative: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if path == Path::new(".git") {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            archive.append_dir(&path, entry.path())?;
+            append_dir(archive, root, &path)?;
+        } else {
+            archive.append_path_with_name(entry.path(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCKERFILE: &str = "FROM rust:1 AS build\nRUN cargo build\nFROM debian AS runtime\nCOPY --from=build /app /app\nFROM runtime AS debug\nRUN apt-get install gdb";
+
+    #[test]
+    fn keeps_everything_up_to_the_target_stage() {
+        let truncated = truncate_at_stage(DOCKERFILE, "runtime").unwrap();
+        assert_eq!(truncated, "FROM rust:1 AS build\nRUN cargo build\nFROM debian AS runtime\nCOPY --from=build /app /app");
+    }
+
+    #[test]
+    fn the_last_stage_is_kept_whole() {
+        assert_eq!(truncate_at_stage(DOCKERFILE, "debug").unwrap(), DOCKERFILE);
+    }
+
+    #[test]
+    fn stage_names_and_keywords_ignore_case() {
+        let dockerfile = "from alpine as Base\nRUN true\nfrom base";
+        assert_eq!(truncate_at_stage(dockerfile, "base").unwrap(), "from alpine as Base\nRUN true");
+    }
+
+    #[test]
+    fn unknown_stages_are_refused() {
+        assert!(truncate_at_stage(DOCKERFILE, "test").is_err());
+        assert!(truncate_at_stage("FROM alpine\nRUN true", "alpine").is_err());
+    }
+}
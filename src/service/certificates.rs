@@ -0,0 +1,526 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, TimeZone, Utc};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use x509_parser::extensions::GeneralName;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::secrets::SecretStore;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// The name the generated certificate is stored under.
+const SELF_SIGNED: &str = "self-signed";
+const SELF_SIGNED_VALIDITY_DAYS: i64 = 365;
+/// How often expiry is checked and the self-signed certificate renewed.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredCertificate {
+    name: String,
+    /// PEM, the leaf first.
+    certificate: String,
+    /// The private key, encrypted with the secrets' master key.
+    key_nonce: String,
+    key_ciphertext: String,
+    #[serde(default)]
+    self_signed: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateStatus {
+    Valid,
+    /// Expires within the configured warning period.
+    Expiring,
+    Expired,
+}
+
+/// What the API shows of a certificate: never the private key.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub name: String,
+    /// The DNS names it is valid for, from its subject alternative names, else its
+    /// common name. `*.example.com` covers one label below `example.com`.
+    pub domains: Vec<String>,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub self_signed: bool,
+    /// SHA-256 of the leaf certificate, hex.
+    pub fingerprint: String,
+    pub status: CertificateStatus,
+    pub days_left: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadCertificateRequest {
+    pub name: String,
+    /// PEM, the leaf first, followed by any intermediates.
+    pub certificate: String,
+    /// PEM; PKCS#8, PKCS#1 or SEC1.
+    pub private_key: String,
+}
+
+/// A stored certificate, ready to be handed to TLS handshakes.
+struct Loaded {
+    name: String,
+    domains: Vec<String>,
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    self_signed: bool,
+    fingerprint: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    key: Arc<CertifiedKey>,
+}
+
+impl Loaded {
+    fn info(&self, warning_days: i64) -> CertificateInfo {
+        let now = Utc::now();
+        let days_left = (self.not_after - now).num_days();
+        let status = if self.not_after <= now {
+            CertificateStatus::Expired
+        } else if days_left < warning_days {
+            CertificateStatus::Expiring
+        } else {
+            CertificateStatus::Valid
+        };
+        CertificateInfo {
+            name: self.name.clone(),
+            domains: self.domains.clone(),
+            not_before: self.not_before,
+            not_after: self.not_after,
+            self_signed: self.self_signed,
+            fingerprint: self.fingerprint.clone(),
+            status,
+            days_left,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+
+    /// Preferred among certificates for the same name: unexpired, then not self-signed,
+    /// then the one valid the longest.
+    fn rank(&self) -> (bool, bool, DateTime<Utc>) {
+        (self.not_after > Utc::now(), !self.self_signed, self.not_after)
+    }
+}
+
+/// TLS certificates in `<data_dir>/certificates.json`, their private keys encrypted like
+/// secrets.
+///
+/// The parsed certificates are kept next to the file and swapped whenever it changes,
+/// so handshakes use an upload or deletion right away, without a restart. Each
+/// handshake gets the certificate for the server name the client asked for (SNI).
+pub struct CertificateStore {
+    secrets: Arc<SecretStore>,
+    certificates: FsStruct<Vec<StoredCertificate>>,
+    loaded: RwLock<Arc<Vec<Loaded>>>,
+    /// Held while `loaded` is rebuilt, so an older snapshot can't replace a newer one.
+    reloading: tokio::sync::Mutex<()>,
+    /// Put into a generated certificate when there is no other; none is generated
+    /// when empty.
+    self_signed_names: Vec<String>,
+    warning_days: i64,
+}
+
+impl fmt::Debug for CertificateStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateStore").field("certificates", &self.certificates).finish()
+    }
+}
+
+impl CertificateStore {
+    pub async fn open(
+        data_dir: &str,
+        secrets: Arc<SecretStore>,
+        self_signed_names: Vec<String>,
+        warning_days: i64,
+    ) -> Result<Self> {
+        let store = Self {
+            secrets,
+            certificates: FsStruct::open(format!("{}/certificates.json", data_dir)).await?,
+            loaded: RwLock::new(Arc::new(Vec::new())),
+            reloading: tokio::sync::Mutex::new(()),
+            self_signed_names,
+            warning_days,
+        };
+        store.reload().await;
+        store.ensure_self_signed().await?;
+        Ok(store)
+    }
+
+    pub fn list(&self) -> Vec<CertificateInfo> {
+        self.current().iter().map(|c| c.info(self.warning_days)).collect()
+    }
+
+    /// Adds the certificate, or replaces the one with the same name.
+    pub async fn upload(&self, request: &UploadCertificateRequest) -> Result<CertificateInfo> {
+        validate_name(&request.name)?;
+        let (_, details) = parse(&request.certificate, &request.private_key)?;
+        if details.not_after <= Utc::now() {
+            return Err(AppError::Service(format!(
+                "Certificate {} expired on {}",
+                request.name, details.not_after
+            )));
+        }
+        self.put(&request.name, &request.certificate, &request.private_key, false).await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        self.certificates
+            .transaction(|certificates| {
+                let before = certificates.len();
+                certificates.retain(|c| c.name != name);
+                if certificates.len() == before {
+                    return Err(AppError::Service(format!("Certificate {} not found", name)));
+                }
+                Ok(())
+            })
+            .await?;
+        self.reload().await;
+        Ok(())
+    }
+
+    /// Checks expiry for as long as the server runs: warns about certificates that
+    /// expire soon or have, and generates or renews the self-signed one.
+    pub async fn run_maintenance(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.ensure_self_signed().await {
+                tracing::warn!("could not renew the self-signed certificate: {}", e);
+            }
+            for certificate in self.list() {
+                match certificate.status {
+                    CertificateStatus::Expired => tracing::warn!(
+                        "certificate {} for {} expired on {}",
+                        certificate.name,
+                        certificate.domains.join(", "),
+                        certificate.not_after
+                    ),
+                    CertificateStatus::Expiring => tracing::warn!(
+                        "certificate {} for {} expires in {} days, on {}",
+                        certificate.name,
+                        certificate.domains.join(", "),
+                        certificate.days_left,
+                        certificate.not_after
+                    ),
+                    CertificateStatus::Valid => {}
+                }
+            }
+            tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+        }
+    }
+
+    /// Generates a self-signed certificate when there is no certificate at all, and
+    /// renews the generated one before it expires.
+    async fn ensure_self_signed(&self) -> Result<()> {
+        if self.self_signed_names.is_empty() {
+            return Ok(());
+        }
+        let current = self.current();
+        let due = match current.iter().find(|c| c.self_signed) {
+            Some(generated) => (generated.not_after - Utc::now()).num_days() < self.warning_days,
+            None => current.is_empty(),
+        };
+        if !due {
+            return Ok(());
+        }
+
+        let (certificate, private_key) = self_signed(&self.self_signed_names)?;
+        let info = self.put(SELF_SIGNED, &certificate, &private_key, true).await?;
+        tracing::info!(
+            "generated a self-signed certificate for {}, valid until {}",
+            info.domains.join(", "),
+            info.not_after
+        );
+        Ok(())
+    }
+
+    async fn put(&self, name: &str, certificate: &str, private_key: &str, self_signed: bool) -> Result<CertificateInfo> {
+        let (key_nonce, key_ciphertext) = self.secrets.seal(&key_context(name), private_key)?;
+        let now = Utc::now();
+        let mut stored = StoredCertificate {
+            name: name.to_string(),
+            certificate: certificate.to_string(),
+            key_nonce,
+            key_ciphertext,
+            self_signed,
+            created_at: now,
+            updated_at: now,
+        };
+        self.certificates
+            .transaction(|certificates| {
+                match certificates.iter_mut().find(|c| c.name == name) {
+                    Some(existing) => {
+                        stored.created_at = existing.created_at;
+                        *existing = stored.clone();
+                    }
+                    None => certificates.push(stored.clone()),
+                }
+                Ok(())
+            })
+            .await?;
+        self.reload().await;
+        self.current()
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.info(self.warning_days))
+            .ok_or_else(|| AppError::Storage(format!("Certificate {} could not be loaded", name)))
+    }
+
+    /// Replaces what handshakes use with the certificates on disk, so a change is only
+    /// served once it has been written. One that cannot be read is left out rather than
+    /// taking the others down with it.
+    async fn reload(&self) {
+        let _reloading = self.reloading.lock().await;
+        let certificates = self.certificates.snapshot().await;
+        let mut loaded = Vec::new();
+        for stored in certificates.iter() {
+            let key = self.secrets.unseal(&key_context(&stored.name), &stored.key_nonce, &stored.key_ciphertext);
+            match key.and_then(|key| parse(&stored.certificate, &key)) {
+                Ok((key, details)) => loaded.push(Loaded {
+                    name: stored.name.clone(),
+                    domains: details.domains,
+                    not_before: details.not_before,
+                    not_after: details.not_after,
+                    self_signed: stored.self_signed,
+                    fingerprint: details.fingerprint,
+                    created_at: stored.created_at,
+                    updated_at: stored.updated_at,
+                    key,
+                }),
+                Err(e) => tracing::warn!("certificate {} is not used: {}", stored.name, e),
+            }
+        }
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+    }
+
+    fn current(&self) -> Arc<Vec<Loaded>> {
+        self.loaded.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    /// The best certificate for the requested name, exact names before wildcards. A
+    /// client that sends no name, or one nothing covers, gets the best one overall.
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let current = self.current();
+        choose(&current, client_hello.server_name()).map(|c| c.key.clone())
+    }
+}
+
+/// The certificate [`CertificateStore::resolve`] hands out for the server name `name`.
+fn choose<'a>(certificates: &'a [Loaded], name: Option<&str>) -> Option<&'a Loaded> {
+    let best = |matches: &dyn Fn(&Loaded) -> bool| {
+        certificates.iter().filter(|c| matches(c)).max_by_key(|c| c.rank())
+    };
+    let name = name.map(|n| n.trim_end_matches('.').to_ascii_lowercase());
+    name.and_then(|name| {
+        best(&|c| c.domains.contains(&name))
+            .or_else(|| best(&|c| c.domains.iter().any(|d| wildcard_covers(d, &name))))
+    })
+    .or_else(|| best(&|_| true))
+}
+
+/// The additional data the private key of `name` is encrypted with, which keeps it
+/// apart from secrets and other certificates.
+fn key_context(name: &str) -> String {
+    format!("certificate:{}", name)
+}
+
+/// Whether `pattern` is a wildcard covering `name`: `*.example.com` covers
+/// `www.example.com`, not `example.com` or `a.b.example.com`.
+fn wildcard_covers(pattern: &str, name: &str) -> bool {
+    match (pattern.strip_prefix("*."), name.split_once('.')) {
+        (Some(parent), Some((label, rest))) => !label.is_empty() && rest == parent,
+        _ => false,
+    }
+}
+
+struct Details {
+    domains: Vec<String>,
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    fingerprint: String,
+}
+
+/// Reads a PEM chain and key, checking that the key belongs to the leaf certificate.
+fn parse(certificate: &str, private_key: &str) -> Result<(Arc<CertifiedKey>, Details)> {
+    let invalid = |message: String| AppError::Service(message);
+    let chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut certificate.as_bytes())
+        .collect::<std::io::Result<_>>()
+        .map_err(|e| invalid(format!("Invalid certificate PEM: {}", e)))?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| invalid("No certificate found in the PEM".to_string()))?;
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut private_key.as_bytes())
+        .map_err(|e| invalid(format!("Invalid private key PEM: {}", e)))?
+        .ok_or_else(|| invalid("No private key found in the PEM".to_string()))?;
+
+    let (_, parsed) = x509_parser::parse_x509_certificate(leaf)
+        .map_err(|e| invalid(format!("Invalid certificate: {}", e)))?;
+    let timestamp = |seconds: i64| {
+        Utc.timestamp_opt(seconds, 0)
+            .single()
+            .ok_or_else(|| invalid("Certificate validity is out of range".to_string()))
+    };
+    let not_before = timestamp(parsed.validity().not_before.timestamp())?;
+    let not_after = timestamp(parsed.validity().not_after.timestamp())?;
+    let mut domains: Vec<String> = match parsed.subject_alternative_name() {
+        Ok(Some(names)) => names
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(dns.to_ascii_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if domains.is_empty() {
+        domains.extend(
+            parsed
+                .subject()
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok())
+                .map(str::to_ascii_lowercase),
+        );
+    }
+    let fingerprint = hex::encode(Sha256::digest(leaf.as_ref()));
+
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| invalid(format!("Unsupported private key: {}", e)))?;
+    let certified = CertifiedKey::new(chain, signing_key);
+    certified
+        .keys_match()
+        .map_err(|_| invalid("The private key does not belong to the certificate".to_string()))?;
+
+    Ok((
+        Arc::new(certified),
+        Details {
+            domains,
+            not_before,
+            not_after,
+            fingerprint,
+        },
+    ))
+}
+
+/// A certificate and key, PEM, for `names`, signed by the key itself. Clients do not
+/// trust it unless told to; it is meant for development.
+fn self_signed(names: &[String]) -> Result<(String, String)> {
+    let failed = |e: rcgen::Error| AppError::Service(format!("Failed to generate a self-signed certificate: {}", e));
+    let mut params = CertificateParams::new(names.to_vec()).map_err(failed)?;
+    let mut subject = DistinguishedName::new();
+    subject.push(DnType::CommonName, names[0].clone());
+    params.distinguished_name = subject;
+    let date = |at: DateTime<Utc>| rcgen::date_time_ymd(at.year(), at.month() as u8, at.day() as u8);
+    let now = Utc::now();
+    // A day early, so clocks that are a little behind accept it too.
+    params.not_before = date(now - ChronoDuration::days(1));
+    params.not_after = date(now + ChronoDuration::days(SELF_SIGNED_VALIDITY_DAYS));
+
+    let key = KeyPair::generate().map_err(failed)?;
+    let certificate = params.self_signed(&key).map_err(failed)?;
+    Ok((certificate.pem(), key.serialize_pem()))
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 128
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(AppError::Service(format!(
+            "Invalid certificate name {:?}: use letters, digits, '_', '-' and '.'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    /// A certificate for `domains`, valid until `not_after`.
+    fn loaded(name: &str, domains: &[&str], self_signed: bool, not_after: DateTime<Utc>) -> Loaded {
+        let (certificate, key) = super::self_signed(&names(&["example.com"])).unwrap();
+        let (key, details) = parse(&certificate, &key).unwrap();
+        Loaded {
+            name: name.to_string(),
+            domains: names(domains),
+            not_before: details.not_before,
+            not_after,
+            self_signed,
+            fingerprint: details.fingerprint,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            key,
+        }
+    }
+
+    #[test]
+    fn wildcards_cover_one_label() {
+        assert!(wildcard_covers("*.example.com", "www.example.com"));
+        assert!(!wildcard_covers("*.example.com", "example.com"));
+        assert!(!wildcard_covers("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_covers("*.example.com", ".example.com"));
+        assert!(!wildcard_covers("www.example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn keys_must_belong_to_the_certificate() {
+        let (certificate, key) = self_signed(&names(&["example.com", "www.example.com"])).unwrap();
+        let (_, other_key) = self_signed(&names(&["example.com"])).unwrap();
+        let (_, details) = parse(&certificate, &key).unwrap();
+        assert_eq!(details.domains, ["example.com", "www.example.com"]);
+        assert!(parse(&certificate, &other_key).is_err());
+        assert!(parse("not a certificate", &key).is_err());
+        assert!(parse(&certificate, "not a key").is_err());
+    }
+
+    #[test]
+    fn the_common_name_is_used_without_alternative_names() {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "Legacy.Example.com");
+        let key = KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key).unwrap();
+        let (_, details) = parse(&certificate.pem(), &key.serialize_pem()).unwrap();
+        assert_eq!(details.domains, ["legacy.example.com"]);
+    }
+
+    #[test]
+    fn handshakes_get_the_best_certificate_for_their_name() {
+        let soon = Utc::now() + ChronoDuration::days(10);
+        let later = Utc::now() + ChronoDuration::days(300);
+        let certificates = [
+            loaded("wildcard", &["*.example.com"], false, later),
+            loaded("www-old", &["www.example.com"], false, soon),
+            loaded("www-new", &["www.example.com"], false, later),
+            loaded("www-self", &["www.example.com"], true, later + ChronoDuration::days(1)),
+            loaded("www-expired", &["www.example.com"], false, Utc::now() - ChronoDuration::days(1)),
+        ];
+        let chosen = |name| choose(&certificates, name).map(|c| c.name.as_str());
+        assert_eq!(chosen(Some("WWW.example.com.")), Some("www-new"));
+        assert_eq!(chosen(Some("shop.example.com")), Some("wildcard"));
+        assert_eq!(chosen(Some("other.org")), Some("www-new"));
+        assert_eq!(chosen(None), Some("www-new"));
+        assert_eq!(choose(&[], Some("example.com")).map(|c| c.name.clone()), None);
+    }
+}
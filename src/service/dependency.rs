@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::error::AppError;
+use crate::service::models::{Dependency, Service};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Service name -> names of the services it depends on.
+pub type DependencyGraph = BTreeMap<String, Vec<String>>;
+
+pub fn graph_of(services: &[Service]) -> DependencyGraph {
+    services
+        .iter()
+        .map(|s| (s.name.clone(), dependency_names(s.depends_on.as_deref())))
+        .collect()
+}
+
+pub fn dependency_names(depends_on: Option<&[Dependency]>) -> Vec<String> {
+    depends_on
+        .unwrap_or_default()
+        .iter()
+        .map(|d| d.service.clone())
+        .collect()
+}
+
+/// Returns the service names ordered so that every service comes after all of its
+/// dependencies. Fails on unknown dependencies and on cycles.
+pub fn start_order(graph: &DependencyGraph) -> Result<Vec<String>> {
+    for (name, deps) in graph {
+        for dep in deps {
+            if dep == name {
+                return Err(AppError::Service(format!("Service {} depends on itself", name)));
+            }
+            if !graph.contains_key(dep) {
+                return Err(AppError::Service(format!(
+                    "Service {} depends on unknown service {}",
+                    name, dep
+                )));
+            }
+        }
+    }
+
+    // Kahn's algorithm; BTreeMap keeps the order stable between runs.
+    let mut remaining: BTreeMap<&str, BTreeSet<&str>> = graph
+        .iter()
+        .map(|(name, deps)| (name.as_str(), deps.iter().map(String::as_str).collect()))
+        .collect();
+    let mut order = Vec::with_capacity(graph.len());
+
+    while !remaining.is_empty() {
+        let ready: Vec<&str> = remaining
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+
+        if ready.is_empty() {
+            let cycle: Vec<&str> = remaining.keys().copied().collect();
+            return Err(AppError::Service(format!(
+                "Dependency cycle detected between services: {}",
+                cycle.join(", ")
+            )));
+        }
+
+        for name in ready {
+            remaining.remove(name);
+            for deps in remaining.values_mut() {
+                deps.remove(name);
+            }
+            order.push(name.to_string());
+        }
+    }
+
+    Ok(order)
+}
+
+/// Checks that `graph` stays acyclic with every dependency resolvable.
+pub fn validate(graph: &DependencyGraph) -> Result<()> {
+    start_order(graph).map(|_| ())
+}
+
+/// Names of the services that directly depend on `name`.
+pub fn dependents_of<'a>(graph: &'a DependencyGraph, name: &str) -> Vec<&'a str> {
+    graph
+        .iter()
+        .filter(|(_, deps)| deps.iter().any(|d| d == name))
+        .map(|(dependent, _)| dependent.as_str())
+        .collect()
+}
+
+/// `name` and everything it transitively depends on, in start order.
+pub fn closure_order(graph: &DependencyGraph, name: &str) -> Result<Vec<String>> {
+    let mut wanted = BTreeSet::new();
+    let mut stack = vec![name.to_string()];
+    while let Some(current) = stack.pop() {
+        if wanted.insert(current.clone()) {
+            if let Some(deps) = graph.get(&current) {
+                stack.extend(deps.iter().cloned());
+            }
+        }
+    }
+
+    Ok(start_order(graph)?
+        .into_iter()
+        .filter(|n| wanted.contains(n))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &[&str])]) -> DependencyGraph {
+        edges
+            .iter()
+            .map(|(name, deps)| (name.to_string(), deps.iter().map(|d| d.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_start_first() {
+        let graph = graph(&[("app", &["db", "cache"]), ("cache", &[]), ("db", &[]), ("worker", &["app"])]);
+        assert_eq!(start_order(&graph).unwrap(), ["cache", "db", "app", "worker"]);
+    }
+
+    #[test]
+    fn cycles_are_refused() {
+        let cycle = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &[])]);
+        let error = start_order(&cycle).unwrap_err().to_string();
+        assert!(error.contains("a, b, c"), "{}", error);
+        assert!(validate(&graph(&[("a", &["a"])])).is_err());
+    }
+
+    #[test]
+    fn unknown_dependencies_are_refused() {
+        assert!(validate(&graph(&[("app", &["db"])])).is_err());
+    }
+
+    #[test]
+    fn closure_only_holds_what_is_needed() {
+        let graph = graph(&[("app", &["db"]), ("db", &[]), ("other", &[]), ("worker", &["app"])]);
+        assert_eq!(closure_order(&graph, "app").unwrap(), ["db", "app"]);
+        assert_eq!(dependents_of(&graph, "db"), ["app"]);
+    }
+}
@@ -0,0 +1,202 @@
+use bollard::models::EventMessage;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+use crate::service::models::Service;
+
+/// How many past events are kept for clients resuming with `Last-Event-ID`.
+const BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    ServiceCreated { service_id: String, name: String },
+    ServiceUpdated { service_id: String, name: String, revision: u64 },
+    ServiceScaled { service_id: String, name: String, replicas: u32 },
+    ServiceDeleted { service_id: String, name: String },
+    ContainerStarted { service_id: String, name: String, container_id: String },
+    ContainerDied { service_id: String, name: String, container_id: String, exit_code: Option<i64> },
+    ContainerOom { service_id: String, name: String, container_id: String },
+    HealthChanged { service_id: String, name: String, container_id: String, status: String },
+    ImagePulled { image: String },
+}
+
+impl EventKind {
+    /// The SSE event name; the same as the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::ServiceCreated { .. } => "service_created",
+            EventKind::ServiceUpdated { .. } => "service_updated",
+            EventKind::ServiceScaled { .. } => "service_scaled",
+            EventKind::ServiceDeleted { .. } => "service_deleted",
+            EventKind::ContainerStarted { .. } => "container_started",
+            EventKind::ContainerDied { .. } => "container_died",
+            EventKind::ContainerOom { .. } => "container_oom",
+            EventKind::HealthChanged { .. } => "health_changed",
+            EventKind::ImagePulled { .. } => "image_pulled",
+        }
+    }
+
+    /// The event for a Docker container event concerning one of the replicas of
+    /// `service`, if it is one clients care about.
+    pub fn from_docker(message: &EventMessage, service: &Service) -> Option<Self> {
+        let service_id = service.id.clone();
+        let name = service.name.clone();
+        let container_id = message.actor.as_ref()?.id.clone()?;
+        let action = message.action.as_deref()?;
+
+        Some(match action {
+            "start" => EventKind::ContainerStarted { service_id, name, container_id },
+            "die" => {
+                let exit_code = message
+                    .actor
+                    .as_ref()
+                    .and_then(|a| a.attributes.as_ref())
+                    .and_then(|a| a.get("exitCode"))
+                    .and_then(|c| c.parse().ok());
+                EventKind::ContainerDied { service_id, name, container_id, exit_code }
+            }
+            "oom" => EventKind::ContainerOom { service_id, name, container_id },
+            // Docker reports health as e.g. "health_status: healthy".
+            _ => {
+                let status = action.strip_prefix("health_status:")?.trim().to_string();
+                EventKind::HealthChanged { service_id, name, container_id, status }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Increases by one per event. Restarts from 1 when Longshoreman does.
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+pub struct Subscription {
+    /// Buffered events after the one the client last saw.
+    pub replay: Vec<Event>,
+    /// The client asked to resume from an event that is no longer buffered, so it
+    /// missed some and should reload its state.
+    pub missed: bool,
+    pub live: broadcast::Receiver<Event>,
+}
+
+/// Fans events out to every `/api/events` stream and keeps the most recent ones for
+/// resuming.
+#[derive(Debug)]
+pub struct EventBus {
+    buffer: Mutex<VecDeque<Event>>,
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            buffer: Mutex::new(VecDeque::with_capacity(BUFFER_SIZE)),
+            sender: broadcast::channel(BUFFER_SIZE).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, kind: EventKind) {
+        let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        let event = Event {
+            id: buffer.back().map_or(1, |e| e.id + 1),
+            at: Utc::now(),
+            kind,
+        };
+        if buffer.len() == BUFFER_SIZE {
+            buffer.pop_front();
+        }
+        buffer.push_back(event.clone());
+        // Sent under the lock so ids reach subscribers in order. No subscribers is fine.
+        let _ = self.sender.send(event);
+    }
+
+    /// Events after `last_event_id` (if given) followed by everything published from
+    /// now on, without gaps or duplicates between the two.
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Subscription {
+        let buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        let live = self.sender.subscribe();
+
+        let Some(last) = last_event_id else {
+            return Subscription { replay: Vec::new(), missed: false, live };
+        };
+        let newest = buffer.back().map_or(0, |e| e.id);
+        let oldest = buffer.front().map_or(newest + 1, |e| e.id);
+        // An id from the future means Longshoreman restarted since the client connected.
+        let missed = last > newest || last + 1 < oldest;
+        let replay = if missed {
+            Vec::new()
+        } else {
+            buffer.iter().filter(|e| e.id > last).cloned().collect()
+        };
+        Subscription { replay, missed, live }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulled(n: usize) -> EventKind {
+        EventKind::ImagePulled { image: format!("image:{}", n) }
+    }
+
+    fn ids(events: &[Event]) -> Vec<u64> {
+        events.iter().map(|e| e.id).collect()
+    }
+
+    #[tokio::test]
+    async fn resuming_replays_newer_events_then_goes_live() {
+        let bus = EventBus::default();
+        for n in 0..5 {
+            bus.publish(pulled(n));
+        }
+        let mut subscription = bus.subscribe(Some(3));
+        assert!(!subscription.missed);
+        assert_eq!(ids(&subscription.replay), [4, 5]);
+
+        bus.publish(pulled(5));
+        assert_eq!(subscription.live.recv().await.unwrap().id, 6);
+
+        let fresh = bus.subscribe(None);
+        assert!(fresh.replay.is_empty() && !fresh.missed);
+        let caught_up = bus.subscribe(Some(6));
+        assert!(caught_up.replay.is_empty() && !caught_up.missed);
+    }
+
+    #[test]
+    fn ids_no_longer_buffered_are_reported_missed() {
+        let bus = EventBus::default();
+        for n in 0..BUFFER_SIZE + 10 {
+            bus.publish(pulled(n));
+        }
+        // Events 1 to 10 have been dropped; resuming after 10 still has everything.
+        let gap = bus.subscribe(Some(9));
+        assert!(gap.missed && gap.replay.is_empty());
+        let resumed = bus.subscribe(Some(10));
+        assert!(!resumed.missed);
+        assert_eq!(resumed.replay.len(), BUFFER_SIZE);
+        assert_eq!(resumed.replay[0].id, 11);
+    }
+
+    #[test]
+    fn ids_from_before_a_restart_are_reported_missed() {
+        let bus = EventBus::default();
+        let empty = bus.subscribe(Some(500));
+        assert!(empty.missed && empty.replay.is_empty());
+
+        bus.publish(pulled(0));
+        bus.publish(pulled(1));
+        let ahead = bus.subscribe(Some(500));
+        assert!(ahead.missed && ahead.replay.is_empty());
+    }
+}
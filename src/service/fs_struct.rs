@@ -1,58 +1,262 @@
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::fs;
-use tokio::fs::File;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 
-struct FsStruct<T: Serialize + DeserializeOwned> {
-    path: String,
-    data: Arc<Mutex<T>>,
+use crate::error::AppError;
+use crate::service::persist;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How long the writer waits for more commits before writing a batch to disk.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+struct WriteRequest<T> {
+    seq: u64,
+    value: T,
+    contents: String,
+    done: oneshot::Sender<std::result::Result<(), String>>,
+}
+
+struct Shared<T> {
+    /// What is on disk, and what readers see.
+    committed: RwLock<T>,
+    /// `committed` with the changes still being written, which new changes build on.
+    staged: Mutex<T>,
+}
+
+/// A value persisted as a JSON file.
+///
+/// Reads go to the in-memory copy. Changes go through [`FsStruct::transaction`], which
+/// applies them to a copy and, if the closure succeeds, waits until the new state is on
+/// disk before readers see it. Commits arriving within the debounce window share one
+/// write.
+pub struct FsStruct<T> {
+    path: PathBuf,
+    shared: Arc<Shared<T>>,
+    seq: AtomicU64,
+    writer: mpsc::UnboundedSender<WriteRequest<T>>,
+}
+
+impl<T> std::fmt::Debug for FsStruct<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FsStruct {{ path: {} }}", self.path.display())
+    }
 }
 
-unsafe impl<T: Serialize + DeserializeOwned + Send> Send for FsStruct<T> {}
+impl<T> FsStruct<T>
+where
+    T: Serialize + DeserializeOwned + Default + Clone + Send + Sync + 'static,
+{
+    /// Loads `path` (falling back to its backups, see [`persist::load_json`]), or starts
+    /// from `T::default()` when there is nothing on disk yet.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_debounce(path, DEFAULT_DEBOUNCE).await
+    }
 
+    pub async fn open_with_debounce(path: impl Into<PathBuf>, debounce: Duration) -> Result<Self> {
+        let path = path.into();
+        let load_path = path.clone();
+        let data: T = tokio::task::spawn_blocking(move || persist::load_json(&load_path))
+            .await
+            .map_err(|e| AppError::Service(format!("Loading {} panicked: {}", path.display(), e)))??
+            .unwrap_or_default();
 
-impl<T: Serialize + DeserializeOwned> FsStruct<T> {
-    pub async fn new(path: String) -> Self {
-        let data = tokio::fs::read_to_string(&path).await.unwrap_or_else(|_| "{}".to_string());
-        let data: T = serde_json::from_str(&data).expect("Failed to parse JSON");
-        Self { 
+        Ok(Self::with_value(path, data, debounce))
+    }
+
+    /// A store holding `data` that will overwrite `path` on the first commit.
+    pub fn with_value(path: impl Into<PathBuf>, data: T, debounce: Duration) -> Self {
+        let path = path.into();
+        let shared = Arc::new(Shared {
+            committed: RwLock::new(data.clone()),
+            staged: Mutex::new(data),
+        });
+        let (writer, requests) = mpsc::unbounded_channel();
+        tokio::spawn(write_loop(path.clone(), shared.clone(), requests, debounce));
+
+        Self {
             path,
-            data: Arc::new(Mutex::new(data))
+            shared,
+            seq: AtomicU64::new(0),
+            writer,
         }
     }
-    pub fn lock(&self) -> impl std::ops::DerefMut<Target = T> + '_ {
-        struct GuardWithSave<'a, T: Serialize + DeserializeOwned> {
-            guard: std::sync::MutexGuard<'a, T>,
-            fs: &'a FsStruct<T>,
-        }
 
-        impl<'a, T: Serialize + DeserializeOwned> Drop for GuardWithSave<'a, T> {
-            fn drop(&mut self) {
-                if let Ok(json) = serde_json::to_string_pretty(&*self.guard) {
-                    let _ = fs::write(&self.fs.path, json);
-                }
+    /// A copy of the current value.
+    pub async fn snapshot(&self) -> T {
+        self.shared.committed.read().await.clone()
+    }
+
+    /// Runs `change` against a copy of the value and commits it.
+    ///
+    /// If `change` fails nothing is modified. Once it succeeds the new value is written,
+    /// and the returned future resolves when readers can see it. If the write fails the
+    /// change is dropped, together with any others made on top of it meanwhile, and the
+    /// error is reported to each of them.
+    pub async fn transaction<R>(&self, change: impl FnOnce(&mut T) -> Result<R>) -> Result<R> {
+        let (result, written) = {
+            let mut staged = self.shared.staged.lock().await;
+            let mut draft = staged.clone();
+            let result = change(&mut draft)?;
+            let contents = serde_json::to_string_pretty(&draft)?;
+
+            // Queue the write before releasing the lock so the writer sees commits in order.
+            let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let (done, written) = oneshot::channel();
+            self.writer
+                .send(WriteRequest { seq, value: draft.clone(), contents, done })
+                .map_err(|_| AppError::Service(format!("Writer for {} has stopped", self.path.display())))?;
+            *staged = draft;
+            (result, written)
+        };
+
+        written
+            .await
+            .map_err(|_| AppError::Service(format!("Writer for {} has stopped", self.path.display())))?
+            .map_err(|e| AppError::Service(format!("Failed to write {}: {}", self.path.display(), e)))?;
+
+        Ok(result)
+    }
+}
+
+/// Collects commits for `debounce`, writes the newest state once and tells every
+/// committer in the batch how it went. Pending commits are flushed when the store is
+/// dropped; failures at that point can only be logged.
+async fn write_loop<T: Clone>(
+    path: PathBuf,
+    shared: Arc<Shared<T>>,
+    mut requests: mpsc::UnboundedReceiver<WriteRequest<T>>,
+    debounce: Duration,
+) {
+    while let Some(first) = requests.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(debounce);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                next = requests.recv() => match next {
+                    Some(request) => batch.push(request),
+                    None => break,
+                },
             }
         }
 
-        impl<'a, T: Serialize + DeserializeOwned> std::ops::Deref for GuardWithSave<'a, T> {
-            type Target = T;
-            fn deref(&self) -> &T {
-                &self.guard
+        // Sequence numbers are taken under the staging lock, so the highest one holds
+        // every change in the batch.
+        let newest = (0..batch.len()).max_by_key(|&i| batch[i].seq).unwrap_or_default();
+        let contents = std::mem::take(&mut batch[newest].contents);
+        let write_path = path.clone();
+        let result = tokio::task::spawn_blocking(move || persist::write_atomic(&write_path, contents.as_bytes()))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map_err(|e| e.to_string()));
+
+        match &result {
+            Ok(()) => *shared.committed.write().await = batch[newest].value.clone(),
+            Err(e) => {
+                tracing::error!("failed to write {}: {}", path.display(), e);
+                // Commits queued since were made on top of the lost ones and go with them.
+                let mut staged = shared.staged.lock().await;
+                while let Ok(request) = requests.try_recv() {
+                    batch.push(request);
+                }
+                *staged = shared.committed.read().await.clone();
             }
         }
+        for request in batch {
+            let _ = request.done.send(result.clone());
+        }
+    }
+}
 
-        impl<'a, T: Serialize + DeserializeOwned> std::ops::DerefMut for GuardWithSave<'a, T> {
-            fn deref_mut(&mut self) -> &mut T {
-                &mut self.guard
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(value: u32) -> impl FnOnce(&mut Vec<u32>) -> Result<()> {
+        move |values| {
+            values.push(value);
+            Ok(())
         }
+    }
+
+    fn scratch_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("fs-struct-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn commits_are_written_and_then_visible() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("values.json");
+        let store: FsStruct<Vec<u32>> = FsStruct::with_value(&path, Vec::new(), Duration::from_millis(1));
+
+        store.transaction(push(1)).await.unwrap();
+        assert_eq!(store.snapshot().await, vec![1]);
+        let on_disk: Vec<u32> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk, vec![1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failed_change_modifies_nothing() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let store: FsStruct<Vec<u32>> = FsStruct::with_value(dir.join("values.json"), vec![1], Duration::from_millis(1));
+
+        let result: Result<()> = store
+            .transaction(|values| {
+                values.push(2);
+                Err(AppError::Service("no".to_string()))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(store.snapshot().await, vec![1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_is_dropped_from_memory() {
+        let dir = scratch_dir();
+        let path = dir.join("values.json");
+        let store: FsStruct<Vec<u32>> = FsStruct::with_value(&path, Vec::new(), Duration::from_millis(1));
+
+        // The directory does not exist yet, so the write fails.
+        assert!(store.transaction(push(1)).await.is_err());
+        assert_eq!(store.snapshot().await, Vec::<u32>::new());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        store.transaction(push(2)).await.unwrap();
+        assert_eq!(store.snapshot().await, vec![2]);
+        let on_disk: Vec<u32> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk, vec![2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_commits_all_land() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let store: Arc<FsStruct<Vec<u32>>> =
+            Arc::new(FsStruct::with_value(dir.join("values.json"), Vec::new(), Duration::from_millis(20)));
 
-        GuardWithSave {
-            guard: self.data.lock().unwrap(),
-            fs: self,
+        let commits: Vec<_> = (0..10)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move { store.transaction(push(i)).await })
+            })
+            .collect();
+        for commit in commits {
+            commit.await.unwrap().unwrap();
         }
+        let mut values = store.snapshot().await;
+        values.sort();
+        assert_eq!(values, (0..10).collect::<Vec<u32>>());
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,464 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::manager::ServiceManager;
+use crate::service::models::Service;
+use crate::service::secrets::SecretStore;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Invocations a single hook may make per [`RATE_WINDOW`]; more are refused.
+const RATE_LIMIT: usize = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Invocations kept per service in the history.
+const MAX_INVOCATIONS: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredHook {
+    id: String,
+    service_id: String,
+    /// SHA-256 of the token in the hook URL; the token itself is only shown once.
+    token_hash: String,
+    /// Key for the HMAC-SHA256 signature of the payload, encrypted with the secrets'
+    /// master key.
+    #[serde(default)]
+    secret_nonce: String,
+    #[serde(default)]
+    secret_ciphertext: String,
+    /// The key as hooks created before it was encrypted kept it; encrypted on open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    require_signature: bool,
+    created_at: DateTime<Utc>,
+}
+
+/// A deploy hook as listed by the API, without its token or secret.
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub service_id: String,
+    pub require_signature: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&StoredHook> for Webhook {
+    fn from(hook: &StoredHook) -> Self {
+        Self {
+            id: hook.id.clone(),
+            service_id: hook.service_id.clone(),
+            require_signature: hook.require_signature,
+            created_at: hook.created_at,
+        }
+    }
+}
+
+/// Returned once, when the hook is created.
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub hook: Webhook,
+    /// Path to POST to, `/api/hooks/<token>`.
+    pub url: String,
+    /// Signs payloads as `X-Hub-Signature-256: sha256=<hex HMAC-SHA256 of the body>`.
+    pub secret: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CreateWebhookRequest {
+    /// Refuse invocations whose payload is not signed with the hook's secret.
+    #[serde(default)]
+    pub require_signature: bool,
+}
+
+/// What CI posts. Without a tag the service's current image is pulled again.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HookPayload {
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvocationOutcome {
+    Succeeded,
+    Failed,
+    /// Bad signature or payload; nothing was deployed.
+    Rejected,
+    RateLimited,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invocation {
+    pub hook_id: String,
+    pub service_id: String,
+    pub at: DateTime<Utc>,
+    pub source_ip: Option<String>,
+    /// The image deployed, or that would have been.
+    pub image: Option<String>,
+    pub outcome: InvocationOutcome,
+    pub error: Option<String>,
+    /// Revision of the service after a successful deploy.
+    pub revision: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct HookFile {
+    hooks: Vec<StoredHook>,
+    invocations: Vec<Invocation>,
+}
+
+/// An invocation that passed the checks, ready for [`HookManager::deploy`].
+#[derive(Debug)]
+pub struct Trigger {
+    pub hook_id: String,
+    pub service_id: String,
+    pub image: String,
+    source_ip: Option<String>,
+}
+
+/// Calls of one hook within the rate window.
+#[derive(Debug, Default)]
+struct RecentCalls {
+    at: VecDeque<Instant>,
+    /// Whether calls are being refused; only the first refusal is recorded.
+    refusing: bool,
+}
+
+/// Deploy hooks for CI, kept in `<data_dir>/hooks.json` with their recent invocations.
+#[derive(Debug)]
+pub struct HookManager {
+    service_manager: Arc<ServiceManager>,
+    secrets: Arc<SecretStore>,
+    file: FsStruct<HookFile>,
+    recent: Mutex<HashMap<String, RecentCalls>>,
+}
+
+impl HookManager {
+    pub async fn open(data_dir: &str, service_manager: Arc<ServiceManager>, secrets: Arc<SecretStore>) -> Result<Self> {
+        let manager = Self {
+            service_manager,
+            secrets,
+            file: FsStruct::open(format!("{}/hooks.json", data_dir)).await?,
+            recent: Mutex::new(HashMap::new()),
+        };
+        manager.seal_plaintext_secrets().await?;
+        Ok(manager)
+    }
+
+    /// Encrypts the keys of hooks stored before they were encrypted.
+    async fn seal_plaintext_secrets(&self) -> Result<()> {
+        if !self.file.snapshot().await.hooks.iter().any(|h| h.secret.is_some()) {
+            return Ok(());
+        }
+        self.file
+            .transaction(|file| {
+                for hook in file.hooks.iter_mut() {
+                    if let Some(secret) = hook.secret.take() {
+                        (hook.secret_nonce, hook.secret_ciphertext) = self.secrets.seal(&secret_context(&hook.id), &secret)?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    pub async fn create(&self, service_id: &str, request: CreateWebhookRequest) -> Result<CreatedWebhook> {
+        self.service_manager.get_service(service_id).await?;
+        let token = random_hex();
+        let secret = random_hex();
+        let id = Uuid::new_v4().to_string();
+        let (secret_nonce, secret_ciphertext) = self.secrets.seal(&secret_context(&id), &secret)?;
+        let hook = StoredHook {
+            id,
+            service_id: service_id.to_string(),
+            token_hash: hash(&token),
+            secret_nonce,
+            secret_ciphertext,
+            secret: None,
+            require_signature: request.require_signature,
+            created_at: Utc::now(),
+        };
+        let stored = hook.clone();
+        self.file
+            .transaction(|file| {
+                file.hooks.push(stored);
+                Ok(())
+            })
+            .await?;
+        Ok(CreatedWebhook {
+            hook: Webhook::from(&hook),
+            url: format!("/api/hooks/{}", token),
+            secret,
+        })
+    }
+
+    pub async fn list(&self, service_id: &str) -> Vec<Webhook> {
+        let file = self.file.snapshot().await;
+        file.hooks.iter().filter(|h| h.service_id == service_id).map(Webhook::from).collect()
+    }
+
+    pub async fn delete(&self, service_id: &str, hook_id: &str) -> Result<()> {
+        self.file
+            .transaction(|file| {
+                let before = file.hooks.len();
+                file.hooks.retain(|h| !(h.id == hook_id && h.service_id == service_id));
+                if file.hooks.len() == before {
+                    return Err(AppError::Service("Webhook not found".to_string()));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// The service's invocations, newest first.
+    pub async fn invocations(&self, service_id: &str) -> Vec<Invocation> {
+        let file = self.file.snapshot().await;
+        file.invocations.iter().rev().filter(|i| i.service_id == service_id).cloned().collect()
+    }
+
+    /// Checks an incoming call of the hook with `token`: the rate limit first, then the
+    /// signature over `body` and the payload. Refusals of a known hook are recorded,
+    /// except for calls past the rate limit after the first.
+    pub async fn authorize(
+        &self,
+        token: &str,
+        signature: Option<&str>,
+        body: &[u8],
+        source_ip: Option<String>,
+    ) -> Result<Trigger> {
+        let token_hash = hash(token);
+        let hook = self
+            .file
+            .snapshot()
+            .await
+            .hooks
+            .into_iter()
+            .find(|h| h.token_hash == token_hash)
+            .ok_or_else(|| AppError::Auth("Unknown webhook".to_string()))?;
+
+        let mut invocation = Invocation {
+            hook_id: hook.id.clone(),
+            service_id: hook.service_id.clone(),
+            at: Utc::now(),
+            source_ip: source_ip.clone(),
+            image: None,
+            outcome: InvocationOutcome::Rejected,
+            error: None,
+            revision: None,
+        };
+        if let Err((e, first)) = self.take_rate_slot(&hook.id) {
+            if first {
+                invocation.outcome = InvocationOutcome::RateLimited;
+                invocation.error = Some(e.to_string());
+                self.record(invocation).await;
+            }
+            return Err(e);
+        }
+        let image = match self.check(&hook, signature, body).await {
+            Ok(image) => image,
+            Err(e) => {
+                invocation.error = Some(e.to_string());
+                self.record(invocation).await;
+                return Err(e);
+            }
+        };
+
+        Ok(Trigger {
+            hook_id: hook.id,
+            service_id: hook.service_id,
+            image,
+            source_ip,
+        })
+    }
+
+    /// The image to deploy if the call is acceptable.
+    async fn check(&self, hook: &StoredHook, signature: Option<&str>, body: &[u8]) -> Result<String> {
+        match signature {
+            Some(signature) => {
+                let secret = self
+                    .secrets
+                    .unseal(&secret_context(&hook.id), &hook.secret_nonce, &hook.secret_ciphertext)?;
+                verify_signature(&secret, signature, body)?
+            }
+            None if hook.require_signature => {
+                return Err(AppError::Auth("This webhook requires a signed payload".to_string()))
+            }
+            None => {}
+        }
+
+        let payload: HookPayload = if body.iter().all(u8::is_ascii_whitespace) {
+            HookPayload::default()
+        } else {
+            serde_json::from_slice(body)?
+        };
+        let current = self.service_manager.get_service(&hook.service_id).await?.image;
+        let image = match &payload.tag {
+            Some(tag) => {
+                validate_tag(tag)?;
+                format!("{}:{}", repository(&current), tag)
+            }
+            None => current,
+        };
+        Ok(image)
+    }
+
+    /// Counts a call of the hook. A refusal says whether it is the first since the
+    /// last call that was let through.
+    fn take_rate_slot(&self, hook_id: &str) -> std::result::Result<(), (AppError, bool)> {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let calls = recent.entry(hook_id.to_string()).or_default();
+        let now = Instant::now();
+        while calls.at.front().is_some_and(|at| now.duration_since(*at) > RATE_WINDOW) {
+            calls.at.pop_front();
+        }
+        if calls.at.len() >= RATE_LIMIT {
+            let first = !std::mem::replace(&mut calls.refusing, true);
+            let error = AppError::RateLimited(format!(
+                "Webhook called more than {} times in {} seconds",
+                RATE_LIMIT,
+                RATE_WINDOW.as_secs()
+            ));
+            return Err((error, first));
+        }
+        calls.refusing = false;
+        calls.at.push_back(now);
+        Ok(())
+    }
+
+    /// Pulls the image and redeploys the service, then records how it went.
+    pub async fn deploy(&self, trigger: Trigger) -> Result<Service> {
+        let result = self.service_manager.pull_and_deploy(&trigger.service_id, &trigger.image).await;
+        self.record(Invocation {
+            hook_id: trigger.hook_id,
+            service_id: trigger.service_id,
+            at: Utc::now(),
+            source_ip: trigger.source_ip,
+            image: Some(trigger.image),
+            outcome: if result.is_ok() { InvocationOutcome::Succeeded } else { InvocationOutcome::Failed },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            revision: result.as_ref().ok().map(|s| s.revision),
+        })
+        .await;
+        result
+    }
+
+    /// A failure to save the history does not fail the invocation.
+    async fn record(&self, invocation: Invocation) {
+        let result = self
+            .file
+            .transaction(|file| {
+                file.invocations.push(invocation);
+                let service_id = &file.invocations[file.invocations.len() - 1].service_id;
+                let count = file.invocations.iter().filter(|i| &i.service_id == service_id).count();
+                if count > MAX_INVOCATIONS {
+                    let oldest = file.invocations.iter().position(|i| &i.service_id == service_id);
+                    if let Some(oldest) = oldest {
+                        file.invocations.remove(oldest);
+                    }
+                }
+                Ok(())
+            })
+            .await;
+        if let Err(e) = result {
+            tracing::warn!("failed to record webhook invocation: {}", e);
+        }
+    }
+}
+
+/// Accepts GitHub's `sha256=<hex>` as well as a bare hex digest.
+fn verify_signature(secret: &str, signature: &str, body: &[u8]) -> Result<()> {
+    let invalid = || AppError::Auth("Invalid webhook signature".to_string());
+    let digest = hex::decode(signature.trim().trim_start_matches("sha256=")).map_err(|_| invalid())?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| invalid())?;
+    mac.update(body);
+    mac.verify_slice(&digest).map_err(|_| invalid())
+}
+
+/// Docker's tag grammar: up to 128 of `[A-Za-z0-9_.-]`, not starting with `.` or `-`.
+fn validate_tag(tag: &str) -> Result<()> {
+    let valid = !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(AppError::Service(format!("Invalid image tag {:?}", tag)));
+    }
+    Ok(())
+}
+
+/// `image` without its tag or digest. A registry port (`host:5000/app`) is kept.
+fn repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+    match image.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => image,
+    }
+}
+
+/// The additional data a hook's key is encrypted with.
+fn secret_context(hook_id: &str) -> String {
+    format!("webhook:{}", hook_id)
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn signatures_are_accepted_with_or_without_prefix() {
+        let body = br#"{"tag":"v2"}"#;
+        let digest = sign("key", body);
+        assert!(verify_signature("key", &format!("sha256={}", digest), body).is_ok());
+        assert!(verify_signature("key", &digest, body).is_ok());
+    }
+
+    #[test]
+    fn wrong_signatures_are_refused() {
+        let body = br#"{"tag":"v2"}"#;
+        assert!(verify_signature("other key", &sign("key", body), body).is_err());
+        assert!(verify_signature("key", &sign("key", body), br#"{"tag":"v3"}"#).is_err());
+        assert!(verify_signature("key", "sha256=not-hex", body).is_err());
+        assert!(verify_signature("key", "", body).is_err());
+    }
+
+    #[test]
+    fn tags_follow_docker_grammar() {
+        assert!(validate_tag("v1.2.3-rc_1").is_ok());
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag("-latest").is_err());
+        assert!(validate_tag("a/b").is_err());
+        assert!(validate_tag(&"a".repeat(129)).is_err());
+    }
+
+    #[test]
+    fn repository_drops_tag_and_digest_but_keeps_registry_port() {
+        assert_eq!(repository("nginx:1.25"), "nginx");
+        assert_eq!(repository("registry:5000/app:v1"), "registry:5000/app");
+        assert_eq!(repository("registry:5000/app"), "registry:5000/app");
+        assert_eq!(repository("app@sha256:abc"), "app");
+    }
+}
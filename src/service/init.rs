@@ -2,6 +2,8 @@ use anyhow::Result;
 use std::fs;
 use std::path::Path;
 
+use crate::service::{migrations, persist};
+
 pub struct Initializer {
     data_dir: String,
 }
@@ -19,18 +21,54 @@ impl Initializer {
             fs::create_dir_all(&self.data_dir)?;
         }
 
+        // Bring existing state up to the current schema before anything deserializes it
+        migrations::run(&self.data_dir)?;
+
         // Initialize services.json if it doesn't exist
+        // A missing file with backups left is restored from them on load instead.
         let services_file = format!("{}/services.json", self.data_dir);
-        if !Path::new(&services_file).exists() {
-            fs::write(&services_file, "[]")?;
+        if !Path::new(&services_file).exists() && !persist::has_backups(&services_file) {
+            persist::write_atomic(&services_file, b"[]")?;
         }
 
         // Initialize users.json if it doesn't exist
         let users_file = format!("{}/users.json", self.data_dir);
-        if !Path::new(&users_file).exists() {
-            fs::write(&users_file, "[]")?;
+        if !Path::new(&users_file).exists() && !persist::has_backups(&users_file) {
+            persist::write_atomic(&users_file, b"[]")?;
         }
 
         Ok(())
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("longshoreman-init-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn a_fresh_directory_is_created_and_stamped() {
+        let dir = temp_dir().join("nested");
+        Initializer::new(dir.to_str().unwrap()).init().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("schema_version")).unwrap().trim(),
+            migrations::CURRENT_VERSION.to_string()
+        );
+        assert_eq!(fs::read_to_string(dir.join("services.json")).unwrap(), "[]");
+        assert_eq!(fs::read_to_string(dir.join("users.json")).unwrap(), "[]");
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_directory_from_a_newer_build_is_refused() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("schema_version"), format!("{}\n", migrations::CURRENT_VERSION + 1)).unwrap();
+        assert!(Initializer::new(dir.to_str().unwrap()).init().is_err());
+        assert!(!dir.join("services.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
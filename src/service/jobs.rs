@@ -0,0 +1,784 @@
+use bollard::container::{KillContainerOptions, LogsOptions, RemoveContainerOptions, StartContainerOptions, WaitContainerOptions};
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::labels;
+use crate::service::manager::ServiceManager;
+use crate::service::models::{CreateServiceRequest, MountSpec, SecretMount};
+use crate::service::redact;
+use crate::service::secrets;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Runs kept per job; the oldest are dropped first.
+const MAX_RUNS: usize = 50;
+/// Log lines kept per run.
+const MAX_LOG_LINES: usize = 500;
+/// The scheduler looks at the jobs at least this often, besides when they change.
+const SCHEDULER_TICK: Duration = Duration::from_secs(60);
+
+/// What happens when a run is due while an earlier one is still going.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcurrencyPolicy {
+    /// Start it anyway.
+    Allow,
+    /// Skip it.
+    #[default]
+    Forbid,
+    /// Stop the earlier runs and start it.
+    Replace,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSpec {
+    pub name: String,
+    /// When to run, in UTC: a cron expression with five fields (`minute hour day month
+    /// weekday`, weekdays numbered from 0 or 7 for Sunday), or six with seconds first
+    /// and weekdays numbered from 1 for Sunday, or `@daily` and the like. Without a
+    /// schedule the job only runs when triggered.
+    pub schedule: Option<String>,
+    /// Service (name or id) whose image, command, env, mounts and secrets the job
+    /// starts from; the fields below override them.
+    pub service: Option<String>,
+    /// Required when there is no `service`.
+    pub image: Option<String>,
+    pub command: Option<Vec<String>>,
+    /// Added to the service's env, replacing entries with the same name.
+    pub env: Option<Vec<String>>,
+    pub mounts: Option<Vec<MountSpec>>,
+    pub secrets: Option<Vec<SecretMount>>,
+    /// Env names masked in API responses, as for services.
+    pub sensitive_env: Option<Vec<String>>,
+    #[serde(default)]
+    pub concurrency: ConcurrencyPolicy,
+    /// Runs still going after this long are killed.
+    pub timeout_secs: Option<u64>,
+    /// Stops scheduled runs; the job can still be triggered by hand.
+    #[serde(default)]
+    pub suspended: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(flatten)]
+    pub spec: JobSpec,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A job as the API shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    #[serde(flatten)]
+    pub job: Job,
+    /// When the schedule fires next, unless the job is suspended.
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// Runs in progress.
+    pub running: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    /// Exited with code 0.
+    Succeeded,
+    Failed,
+    /// Killed after `timeout_secs`.
+    TimedOut,
+    /// Stopped to make way for a newer run, under the `replace` policy.
+    Replaced,
+    /// Not started because an earlier run was still going, under the `forbid` policy.
+    Skipped,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunTrigger {
+    Schedule,
+    Manual,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRun {
+    pub id: String,
+    pub job_id: String,
+    pub trigger: RunTrigger,
+    /// Who started a manual run.
+    pub triggered_by: Option<String>,
+    pub status: RunStatus,
+    pub image: Option<String>,
+    pub container_id: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<u64>,
+    pub exit_code: Option<i64>,
+    pub error: Option<String>,
+    /// The last lines of the container's output.
+    pub logs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct JobFile {
+    jobs: Vec<Job>,
+    runs: Vec<JobRun>,
+}
+
+/// A run in progress.
+#[derive(Debug, Default)]
+struct ActiveRun {
+    container_id: Option<String>,
+    /// Set when the run is being stopped, and why.
+    stopping: Option<RunStatus>,
+}
+
+/// Jobs and their runs, in `<data_dir>/jobs.json`. Each run is a container created for
+/// it, waited for and removed once it is done.
+#[derive(Debug)]
+pub struct JobManager {
+    docker: Docker,
+    service_manager: Arc<ServiceManager>,
+    file: FsStruct<JobFile>,
+    /// Runs in progress, by job id and run id.
+    active: Mutex<HashMap<String, HashMap<String, ActiveRun>>>,
+    /// Wakes the scheduler when jobs change.
+    changed: Notify,
+}
+
+impl JobManager {
+    pub async fn open(data_dir: &str, docker: Docker, service_manager: Arc<ServiceManager>) -> Result<Self> {
+        let file: FsStruct<JobFile> = FsStruct::open(format!("{}/jobs.json", data_dir)).await?;
+        // Runs still going when Longshoreman went down are not waited for again; their
+        // containers are removed.
+        let interrupted = file
+            .transaction(|file| {
+                let mut containers = Vec::new();
+                for run in file.runs.iter_mut().filter(|r| r.status == RunStatus::Running) {
+                    run.status = RunStatus::Failed;
+                    run.error = Some("Interrupted by a restart".to_string());
+                    run.finished_at = Some(Utc::now());
+                    containers.extend(run.container_id.clone());
+                }
+                Ok(containers)
+            })
+            .await?;
+        for container_id in interrupted {
+            remove_container(&docker, &container_id).await;
+        }
+        Ok(Self {
+            docker,
+            service_manager,
+            file,
+            active: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        })
+    }
+
+    pub async fn list(&self) -> Vec<JobInfo> {
+        let file = self.file.snapshot().await;
+        file.jobs.into_iter().map(|job| self.info(job)).collect()
+    }
+
+    pub async fn get(&self, id: &str) -> Result<JobInfo> {
+        Ok(self.info(self.find(id).await?))
+    }
+
+    /// The job with id or name `reference`.
+    async fn find(&self, reference: &str) -> Result<Job> {
+        self.file
+            .snapshot()
+            .await
+            .jobs
+            .into_iter()
+            .find(|j| j.id == reference || j.spec.name == reference)
+            .ok_or_else(|| AppError::Service(format!("Job {} not found", reference)))
+    }
+
+    fn info(&self, job: Job) -> JobInfo {
+        let next_run_at = match (&job.spec.schedule, job.spec.suspended) {
+            (Some(schedule), false) => parse_schedule(schedule).ok().and_then(|s| s.upcoming(Utc).next()),
+            _ => None,
+        };
+        let running = self.lock_active().get(&job.id).map_or(0, HashMap::len);
+        JobInfo {
+            job,
+            next_run_at,
+            running,
+        }
+    }
+
+    pub async fn create(&self, spec: JobSpec) -> Result<Job> {
+        self.validate(&spec).await?;
+        let now = Utc::now();
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            spec,
+            created_at: now,
+            updated_at: now,
+        };
+        let stored = job.clone();
+        self.file
+            .transaction(|file| {
+                if file.jobs.iter().any(|j| j.spec.name == stored.spec.name) {
+                    return Err(AppError::Service(format!("A job named {} already exists", stored.spec.name)));
+                }
+                file.jobs.push(stored);
+                Ok(())
+            })
+            .await?;
+        self.changed.notify_one();
+        Ok(job)
+    }
+
+    /// Replaces the spec of job `id`. Masked env values are kept from the current spec.
+    pub async fn update(&self, id: &str, mut spec: JobSpec) -> Result<Job> {
+        let current = self.find(id).await?;
+        redact::unmask_env(&format!("job {}", spec.name), &mut spec.env, current.spec.env.as_deref())?;
+        self.validate(&spec).await?;
+        let job = self
+            .file
+            .transaction(|file| {
+                if file.jobs.iter().any(|j| j.id != current.id && j.spec.name == spec.name) {
+                    return Err(AppError::Service(format!("A job named {} already exists", spec.name)));
+                }
+                let job = file
+                    .jobs
+                    .iter_mut()
+                    .find(|j| j.id == current.id)
+                    .ok_or_else(|| AppError::Service("Job not found".to_string()))?;
+                job.spec = spec;
+                job.updated_at = Utc::now();
+                Ok(job.clone())
+            })
+            .await?;
+        self.changed.notify_one();
+        Ok(job)
+    }
+
+    /// Removes the job and its run history. Refused while it is running.
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let job = self.find(id).await?;
+        if self.lock_active().get(&job.id).is_some_and(|runs| !runs.is_empty()) {
+            return Err(AppError::Service(format!("Job {} is running", job.spec.name)));
+        }
+        self.file
+            .transaction(|file| {
+                file.jobs.retain(|j| j.id != job.id);
+                file.runs.retain(|r| r.job_id != job.id);
+                Ok(())
+            })
+            .await?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    async fn validate(&self, spec: &JobSpec) -> Result<()> {
+        if spec.name.trim().is_empty() {
+            return Err(AppError::Service("A job needs a name".to_string()));
+        }
+        if let Some(schedule) = &spec.schedule {
+            parse_schedule(schedule)?;
+        }
+        if spec.timeout_secs == Some(0) {
+            return Err(AppError::Service("timeout_secs must be more than 0".to_string()));
+        }
+        match (&spec.service, &spec.image) {
+            (Some(service), _) => {
+                self.service_manager.find_service(service).await?;
+            }
+            (None, None) => return Err(AppError::Service("A job needs a service or an image".to_string())),
+            (None, Some(_)) => {}
+        }
+        Ok(())
+    }
+
+    /// Names of the jobs referring to secret `name`.
+    pub async fn jobs_using_secret(&self, name: &str) -> Vec<String> {
+        self.file
+            .snapshot()
+            .await
+            .jobs
+            .iter()
+            .filter(|j| {
+                let spec = CreateServiceRequest {
+                    env: j.spec.env.clone(),
+                    secrets: j.spec.secrets.clone(),
+                    ..bare_spec(&j.spec.name, "")
+                };
+                secrets::references(&spec).contains(name)
+            })
+            .map(|j| j.spec.name.clone())
+            .collect()
+    }
+
+    /// The job's runs, newest first.
+    pub async fn runs(&self, id: &str) -> Result<Vec<JobRun>> {
+        let job = self.find(id).await?;
+        let file = self.file.snapshot().await;
+        Ok(file.runs.into_iter().rev().filter(|r| r.job_id == job.id).collect())
+    }
+
+    pub async fn run(&self, id: &str, run_id: &str) -> Result<JobRun> {
+        self.runs(id)
+            .await?
+            .into_iter()
+            .find(|r| r.id == run_id)
+            .ok_or_else(|| AppError::Service("Run not found".to_string()))
+    }
+
+    /// Starts a run of job `id` in the background, following its concurrency policy,
+    /// and returns it as started.
+    pub async fn trigger(self: &Arc<Self>, id: &str, trigger: RunTrigger, triggered_by: Option<String>) -> Result<JobRun> {
+        let job = self.find(id).await?;
+        let mut run = JobRun {
+            id: Uuid::new_v4().to_string(),
+            job_id: job.id.clone(),
+            trigger,
+            triggered_by,
+            status: RunStatus::Running,
+            image: None,
+            container_id: None,
+            started_at: Utc::now(),
+            finished_at: None,
+            duration_ms: None,
+            exit_code: None,
+            error: None,
+            logs: Vec::new(),
+        };
+
+        let replaced = {
+            let mut active = self.lock_active();
+            let runs = active.entry(job.id.clone()).or_default();
+            if !runs.is_empty() && job.spec.concurrency == ConcurrencyPolicy::Forbid {
+                None
+            } else {
+                let mut replaced = Vec::new();
+                if job.spec.concurrency == ConcurrencyPolicy::Replace {
+                    for active_run in runs.values_mut() {
+                        active_run.stopping = Some(RunStatus::Replaced);
+                        replaced.extend(active_run.container_id.clone());
+                    }
+                }
+                runs.insert(run.id.clone(), ActiveRun::default());
+                Some(replaced)
+            }
+        };
+        let Some(replaced) = replaced else {
+            let error = format!("Job {} is still running", job.spec.name);
+            if trigger == RunTrigger::Schedule {
+                run.status = RunStatus::Skipped;
+                run.error = Some(error.clone());
+                run.finished_at = Some(run.started_at);
+                self.save_run(&run).await?;
+            }
+            return Err(AppError::Service(error));
+        };
+        for container_id in replaced {
+            self.kill(&container_id).await;
+        }
+
+        if let Err(e) = self.save_run(&run).await {
+            self.finish_active(&job.id, &run.id);
+            return Err(e);
+        }
+        let manager = self.clone();
+        let started = run.clone();
+        tokio::spawn(async move { manager.execute(job, run).await });
+        Ok(started)
+    }
+
+    async fn execute(&self, job: Job, mut run: JobRun) {
+        let result = self.run_container(&job, &mut run).await;
+        let finished_at = Utc::now();
+        run.finished_at = Some(finished_at);
+        run.duration_ms = (finished_at - run.started_at).num_milliseconds().try_into().ok();
+        run.exit_code = result.as_ref().ok().copied();
+        run.error = result.as_ref().err().map(|e| e.to_string());
+        run.status = match (self.finish_active(&job.id, &run.id), result) {
+            (Some(stopped), _) => stopped,
+            (None, Ok(0)) => RunStatus::Succeeded,
+            (None, Ok(code)) => {
+                run.error = Some(format!("Exited with code {}", code));
+                RunStatus::Failed
+            }
+            (None, Err(_)) => RunStatus::Failed,
+        };
+        if let Some(container_id) = &run.container_id {
+            remove_container(&self.docker, container_id).await;
+        }
+        if let Err(e) = self.save_run(&run).await {
+            tracing::warn!("failed to record run {} of job {}: {}", run.id, job.spec.name, e);
+        }
+    }
+
+    /// Creates, starts and waits for the run's container; its exit code.
+    async fn run_container(&self, job: &Job, run: &mut JobRun) -> Result<i64> {
+        let spec = self.container_spec(&job.spec).await?;
+        run.image = Some(spec.image.clone());
+        let name = format!("{}-run-{}", job.spec.name, &run.id[..8]);
+        let labels = labels::for_job_run(&spec, &job.id, &run.id);
+        let container_id = self.service_manager.create_task_container(&name, &spec, labels).await?;
+        run.container_id = Some(container_id.clone());
+
+        let stopping = {
+            let mut active = self.lock_active();
+            let entry = active.get_mut(&job.id).and_then(|runs| runs.get_mut(&run.id));
+            entry.and_then(|entry| {
+                entry.container_id = Some(container_id.clone());
+                entry.stopping
+            })
+        };
+        if stopping.is_some() {
+            return Err(AppError::Service("Stopped before it started".to_string()));
+        }
+        self.save_run(run).await?;
+
+        self.docker
+            .start_container(&container_id, None::<StartContainerOptions<String>>)
+            .await?;
+        let exit_code = match job.spec.timeout_secs {
+            Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), self.wait(&container_id)).await {
+                Ok(exit_code) => exit_code,
+                Err(_) => {
+                    self.set_stopping(&job.id, &run.id, RunStatus::TimedOut);
+                    self.kill(&container_id).await;
+                    Err(AppError::Service(format!("Timed out after {} seconds", secs)))
+                }
+            },
+            None => self.wait(&container_id).await,
+        };
+        run.logs = self.logs(&container_id).await;
+        exit_code
+    }
+
+    /// The spec of the run's container: the service's (or a bare one) with the job's
+    /// fields over it. Ports, healthchecks, dependencies and restarts are left out, a
+    /// run is not a service.
+    async fn container_spec(&self, job: &JobSpec) -> Result<CreateServiceRequest> {
+        let mut spec = match (&job.service, &job.image) {
+            (Some(service), _) => self.service_manager.find_service(service).await?.spec(),
+            (None, Some(image)) => bare_spec(&job.name, image),
+            (None, None) => return Err(AppError::Service("A job needs a service or an image".to_string())),
+        };
+        spec.name = job.name.clone();
+        if let Some(image) = &job.image {
+            spec.image = image.clone();
+        }
+        if job.command.is_some() {
+            spec.command = job.command.clone();
+        }
+        if let Some(env) = &job.env {
+            let merged = spec.env.get_or_insert_with(Vec::new);
+            for entry in env {
+                let name = entry.split('=').next().unwrap_or(entry);
+                merged.retain(|e| e.split('=').next() != Some(name));
+                merged.push(entry.clone());
+            }
+        }
+        if job.mounts.is_some() {
+            spec.mounts = job.mounts.clone();
+        }
+        if job.secrets.is_some() {
+            spec.secrets = job.secrets.clone();
+        }
+        spec.ports = None;
+        spec.healthcheck = None;
+        spec.depends_on = None;
+        spec.restart_policy = None;
+        spec.replicas = None;
+        spec.domains = None;
+        spec.routes = None;
+        Ok(spec)
+    }
+
+    async fn wait(&self, container_id: &str) -> Result<i64> {
+        let mut wait = self.docker.wait_container(container_id, None::<WaitContainerOptions<String>>);
+        match wait.next().await {
+            Some(Ok(response)) => Ok(response.status_code),
+            // bollard reports a non-zero exit as an error.
+            Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. })) => Ok(code),
+            Some(Err(e)) => Err(e.into()),
+            None => Err(AppError::Service("Docker stopped reporting on the container".to_string())),
+        }
+    }
+
+    /// The last lines of the container's output. Missing logs do not fail the run.
+    async fn logs(&self, container_id: &str) -> Vec<String> {
+        let options = LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            tail: MAX_LOG_LINES.to_string(),
+            ..Default::default()
+        };
+        let output: std::result::Result<Vec<_>, _> = self.docker.logs(container_id, Some(options)).try_collect().await;
+        match output {
+            Ok(output) => output
+                .iter()
+                .flat_map(|chunk| chunk.to_string().lines().map(str::to_string).collect::<Vec<_>>())
+                .collect(),
+            Err(e) => vec![format!("(could not read the logs: {})", e)],
+        }
+    }
+
+    async fn kill(&self, container_id: &str) {
+        let killed = self
+            .docker
+            .kill_container(container_id, None::<KillContainerOptions<String>>)
+            .await;
+        if let Err(e) = killed {
+            tracing::warn!("failed to kill job container {}: {}", container_id, e);
+        }
+    }
+
+    fn set_stopping(&self, job_id: &str, run_id: &str, status: RunStatus) {
+        if let Some(run) = self.lock_active().get_mut(job_id).and_then(|runs| runs.get_mut(run_id)) {
+            run.stopping = Some(status);
+        }
+    }
+
+    /// Forgets a run that has ended; why it was stopped, if it was.
+    fn finish_active(&self, job_id: &str, run_id: &str) -> Option<RunStatus> {
+        let mut active = self.lock_active();
+        let runs = active.get_mut(job_id)?;
+        let run = runs.remove(run_id);
+        if runs.is_empty() {
+            active.remove(job_id);
+        }
+        run?.stopping
+    }
+
+    async fn save_run(&self, run: &JobRun) -> Result<()> {
+        let run = run.clone();
+        self.file
+            .transaction(|file| {
+                match file.runs.iter_mut().find(|r| r.id == run.id) {
+                    Some(existing) => *existing = run,
+                    None => {
+                        let job_id = run.job_id.clone();
+                        file.runs.push(run);
+                        let count = file.runs.iter().filter(|r| r.job_id == job_id).count();
+                        if count > MAX_RUNS {
+                            if let Some(oldest) = file.runs.iter().position(|r| r.job_id == job_id) {
+                                file.runs.remove(oldest);
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Starts jobs as their schedules come due. Runs for the lifetime of the server;
+    /// runs missed while it was down are not made up.
+    pub async fn run_scheduler(self: Arc<Self>) {
+        let mut checked_until = Utc::now();
+        loop {
+            let now = Utc::now();
+            let jobs = self.file.snapshot().await.jobs;
+            let mut next_due: Option<DateTime<Utc>> = None;
+            for job in jobs.iter().filter(|j| !j.spec.suspended) {
+                let Some(schedule) = job.spec.schedule.as_deref().and_then(|s| parse_schedule(s).ok()) else {
+                    continue;
+                };
+                // A job created or changed since the last check only counts from then.
+                let since = checked_until.max(job.updated_at);
+                if schedule.after(&since).next().is_some_and(|due| due <= now) {
+                    if let Err(e) = self.trigger(&job.id, RunTrigger::Schedule, None).await {
+                        tracing::warn!("scheduled run of job {} did not start: {}", job.spec.name, e);
+                    }
+                }
+                if let Some(due) = schedule.after(&now).next() {
+                    next_due = Some(next_due.map_or(due, |d| d.min(due)));
+                }
+            }
+            checked_until = now;
+
+            tokio::select! {
+                _ = tokio::time::sleep(idle_for(next_due, Utc::now())) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+
+    fn lock_active(&self) -> std::sync::MutexGuard<'_, HashMap<String, HashMap<String, ActiveRun>>> {
+        self.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// How long the scheduler waits for `next_due`: at most [`SCHEDULER_TICK`], and that long
+/// when nothing is scheduled.
+fn idle_for(next_due: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Duration {
+    next_due
+        .map(|due| (due - now).to_std().unwrap_or_default())
+        .unwrap_or(SCHEDULER_TICK)
+        .min(SCHEDULER_TICK)
+}
+
+/// Accepts the usual five cron fields by adding the seconds the `cron` crate wants.
+fn parse_schedule(expression: &str) -> Result<Schedule> {
+    let expression = expression.trim();
+    let invalid = |e: String| AppError::Service(format!("Invalid schedule {:?}: {}", expression, e));
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let full = if !expression.starts_with('@') && fields.len() == 5 {
+        let weekdays = standard_weekdays(fields[4]).map_err(invalid)?;
+        format!("0 {} {}", fields[..4].join(" "), weekdays)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&full).map_err(|e| invalid(e.to_string()))
+}
+
+/// Renumbers a five-field weekday field from the usual 0-7 (both 0 and 7 are Sunday) to
+/// the 1-7 from Sunday the `cron` crate uses. Names, `*` and `?` are kept as they are.
+fn standard_weekdays(field: &str) -> std::result::Result<String, String> {
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let numeric = if range == "*" {
+            step.is_some()
+        } else {
+            !range.is_empty() && range.chars().all(|c| c.is_ascii_digit() || c == '-')
+        };
+        if !numeric {
+            items.push(item.to_string());
+            continue;
+        }
+        let number = |n: &str| match n.parse::<u32>() {
+            Ok(n) if n <= 7 => Ok(n),
+            _ => Err(format!("weekday {} is not between 0 and 7", n)),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `n/step` runs from n to the end of the week.
+            None if step.is_some() => (number(range)?, 6),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(format!("weekday range {} runs backwards", range));
+        }
+        let step = match step.map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(step)) if step > 0 => step,
+            Some(_) => return Err(format!("invalid weekday step in {}", item)),
+        };
+        let days: BTreeSet<u32> = (first..=last).step_by(step).map(|day| day % 7 + 1).collect();
+        items.extend(days.iter().map(u32::to_string));
+    }
+    Ok(items.join(","))
+}
+
+fn bare_spec(name: &str, image: &str) -> CreateServiceRequest {
+    CreateServiceRequest {
+        name: name.to_string(),
+        image: image.to_string(),
+        command: None,
+        env: None,
+        ports: None,
+        healthcheck: None,
+        depends_on: None,
+        mounts: None,
+        restart_policy: None,
+        labels: None,
+        stack: None,
+        secrets: None,
+        sensitive_env: None,
+        replicas: None,
+        domains: None,
+        routes: None,
+    }
+}
+
+async fn remove_container(docker: &Docker, container_id: &str) {
+    let removed = docker
+        .remove_container(
+            container_id,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await;
+    if let Err(e) = removed {
+        tracing::warn!("failed to remove job container {}: {}", container_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone};
+
+    #[test]
+    fn scheduler_idles_a_full_tick_when_nothing_is_scheduled() {
+        assert_eq!(idle_for(None, Utc::now()), SCHEDULER_TICK);
+    }
+
+    #[test]
+    fn scheduler_wakes_for_the_next_due_job() {
+        let now = Utc::now();
+        assert_eq!(idle_for(Some(now + chrono::Duration::seconds(10)), now), Duration::from_secs(10));
+        assert_eq!(idle_for(Some(now + chrono::Duration::hours(2)), now), SCHEDULER_TICK);
+        assert_eq!(idle_for(Some(now - chrono::Duration::seconds(1)), now), Duration::ZERO);
+    }
+
+    #[test]
+    fn five_field_schedules_get_seconds() {
+        let schedule = parse_schedule("30 2 * * *").unwrap();
+        let after = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2026, 1, 1, 2, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn six_field_and_shorthand_schedules_parse_as_is() {
+        let after = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let every_ten_seconds = parse_schedule("*/10 * * * * *").unwrap();
+        assert_eq!(every_ten_seconds.after(&after).next().unwrap(), after + chrono::Duration::seconds(10));
+        let daily = parse_schedule(" @daily ").unwrap();
+        assert_eq!(daily.after(&after).next().unwrap(), after + chrono::Duration::days(1));
+    }
+
+    #[test]
+    fn five_field_weekdays_count_from_sunday_as_zero() {
+        // 2026-01-03 is a Saturday.
+        let after = Utc.with_ymd_and_hms(2026, 1, 3, 0, 0, 0).unwrap();
+        let runs = |expression| -> Vec<u32> {
+            let schedule = parse_schedule(expression).unwrap();
+            schedule.after(&after).take(5).map(|at| at.weekday().num_days_from_sunday()).collect()
+        };
+        assert_eq!(runs("0 2 * * 1-5"), [1, 2, 3, 4, 5]);
+        assert_eq!(runs("0 2 * * 0"), [0; 5]);
+        assert_eq!(runs("0 2 * * 7"), [0; 5]);
+        assert_eq!(runs("0 2 * * 5-7"), [6, 0, 5, 6, 0]);
+        assert_eq!(runs("0 2 * * */3"), [6, 0, 3, 6, 0]);
+        assert_eq!(runs("0 2 * * MON-FRI"), [1, 2, 3, 4, 5]);
+        assert_eq!(standard_weekdays("0,6").unwrap(), "1,7");
+    }
+
+    #[test]
+    fn invalid_schedules_are_refused() {
+        assert!(parse_schedule("every day").is_err());
+        assert!(parse_schedule("61 * * * *").is_err());
+        assert!(parse_schedule("0 2 * * 8").is_err());
+        assert!(parse_schedule("0 2 * * 5-1").is_err());
+        assert!(parse_schedule("0 2 * * 1/0").is_err());
+    }
+}
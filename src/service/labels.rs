@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::error::AppError;
+use crate::service::models::{CreateServiceRequest, Service};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+pub const MANAGED_BY: &str = "io.longshoreman.managed-by";
+pub const SERVICE_ID: &str = "io.longshoreman.service.id";
+pub const REVISION: &str = "io.longshoreman.revision";
+pub const REPLICA: &str = "io.longshoreman.replica";
+pub const STACK: &str = "io.longshoreman.stack";
+/// The full spec the container was created from, as JSON.
+pub const SPEC: &str = "io.longshoreman.spec";
+/// On the containers of job runs, which are not services.
+pub const JOB_ID: &str = "io.longshoreman.job.id";
+pub const JOB_RUN: &str = "io.longshoreman.job.run";
+
+pub const MANAGER: &str = "longshoreman";
+
+const PREFIX: &str = "io.longshoreman.";
+
+/// The labels stamped on replica `replica` of `service`, merged over the user's own.
+pub fn for_service(service: &Service, replica: u32) -> Result<HashMap<String, String>> {
+    let spec = service.spec();
+    let mut labels = spec.labels.clone().unwrap_or_default();
+    strip(&mut labels);
+
+    labels.insert(MANAGED_BY.to_string(), MANAGER.to_string());
+    labels.insert(SERVICE_ID.to_string(), service.id.clone());
+    labels.insert(REVISION.to_string(), service.revision.to_string());
+    labels.insert(REPLICA.to_string(), replica.to_string());
+    if let Some(stack) = &service.stack {
+        labels.insert(STACK.to_string(), stack.clone());
+    }
+    labels.insert(SPEC.to_string(), serde_json::to_string(&spec)?);
+    Ok(labels)
+}
+
+/// The labels stamped on the container of a job run, merged over the user's own.
+pub fn for_job_run(spec: &CreateServiceRequest, job_id: &str, run_id: &str) -> HashMap<String, String> {
+    let mut labels = spec.labels.clone().unwrap_or_default();
+    strip(&mut labels);
+    labels.insert(JOB_ID.to_string(), job_id.to_string());
+    labels.insert(JOB_RUN.to_string(), run_id.to_string());
+    labels
+}
+
+/// Removes Longshoreman's own labels, leaving what the user asked for.
+pub fn strip(labels: &mut HashMap<String, String>) {
+    labels.retain(|k, _| !k.starts_with(PREFIX));
+}
+
+#[derive(Debug, Clone)]
+pub struct ManagedLabels {
+    pub service_id: String,
+    pub revision: u64,
+    pub replica: u32,
+    pub spec: Option<CreateServiceRequest>,
+}
+
+/// Reads Longshoreman's labels back, `None` for containers it did not create.
+pub fn parse(labels: &HashMap<String, String>) -> Option<ManagedLabels> {
+    if labels.get(MANAGED_BY).map(String::as_str) != Some(MANAGER) {
+        return None;
+    }
+    Some(ManagedLabels {
+        service_id: labels.get(SERVICE_ID)?.clone(),
+        revision: labels.get(REVISION).and_then(|r| r.parse().ok()).unwrap_or_default(),
+        // Containers from before replicas existed were the only one of their service.
+        replica: labels.get(REPLICA).and_then(|r| r.parse().ok()).unwrap_or(1),
+        spec: labels.get(SPEC).and_then(|s| serde_json::from_str(s).ok()),
+    })
+}
+
+/// A disagreement between `services.json` and the labels on the Docker host.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// The file knows the service but the container of one of its replicas is gone.
+    MissingContainer { service_id: String, name: String, replica: u32 },
+    /// A container carries our labels but the file does not know its service.
+    UntrackedContainer { container_id: String, service_id: String },
+    /// The container was created from a different revision than the file records.
+    RevisionMismatch {
+        service_id: String,
+        name: String,
+        file: u64,
+        container: u64,
+    },
+    /// The service's container is labelled as belonging to another service.
+    ServiceIdMismatch {
+        name: String,
+        file: String,
+        container: String,
+    },
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discrepancy::MissingContainer { service_id, name, replica } => {
+                write!(f, "replica {} of service {} ({}) has no container", replica, name, service_id)
+            }
+            Discrepancy::UntrackedContainer { container_id, service_id } => write!(
+                f,
+                "container {} is labelled for unknown service {}",
+                container_id, service_id
+            ),
+            Discrepancy::RevisionMismatch { service_id, name, file, container } => write!(
+                f,
+                "service {} ({}) is at revision {} but its container has revision {}",
+                name, service_id, file, container
+            ),
+            Discrepancy::ServiceIdMismatch { name, file, container } => write!(
+                f,
+                "service {} is {} in services.json but its container is labelled {}",
+                name, file, container
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RecoveryReport {
+    pub discrepancies: Vec<Discrepancy>,
+    /// The service list after recovery (or as it would be, for a dry run).
+    pub services: Vec<Service>,
+}
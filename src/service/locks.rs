@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+/// One async mutex per key, created on first use and forgotten again once nobody holds
+/// or waits for it.
+#[derive(Debug, Default)]
+pub struct KeyedLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl KeyedLocks {
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn holders_of_one_key_take_turns() {
+        let locks = Arc::new(KeyedLocks::default());
+        let inside = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let (locks, inside) = (locks.clone(), inside.clone());
+            tasks.push(tokio::spawn(async move {
+                let _guard = locks.lock("service").await;
+                assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                inside.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn other_keys_are_not_blocked() {
+        let locks = KeyedLocks::default();
+        let _a = locks.lock("a").await;
+        let b = tokio::time::timeout(Duration::from_millis(100), locks.lock("b")).await;
+        assert!(b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn released_locks_are_forgotten() {
+        let locks = KeyedLocks::default();
+        drop(locks.lock("a").await);
+        drop(locks.lock("b").await);
+        assert!(locks.locks.lock().unwrap().len() <= 1);
+    }
+}
@@ -0,0 +1,267 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Login attempts allowed per window, from one address and for one account.
+const IP_LIMIT: usize = 20;
+const ACCOUNT_LIMIT: usize = 10;
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Consecutive failures after which an account is locked.
+const LOCKOUT_THRESHOLD: u32 = 5;
+/// The first lockout; every further failure doubles it, up to [`MAX_LOCKOUT`].
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+/// Tracked addresses plus accounts beyond which stale entries are dropped on the next
+/// attempt, so a flood of made-up emails cannot grow the state without bound.
+const PRUNE_ABOVE: usize = 10_000;
+
+/// An account that is currently locked, as shown to admins.
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+    pub email: String,
+    pub failures: u32,
+    pub locked_until: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct Account {
+    attempts: VecDeque<Instant>,
+    /// Failures since the last successful login.
+    failures: u32,
+    locked_until: Option<(Instant, DateTime<Utc>)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    ips: HashMap<String, VecDeque<Instant>>,
+    accounts: HashMap<String, Account>,
+}
+
+/// Throttles `/api/login`. Kept in memory: a restart lifts all limits and lockouts.
+///
+/// Unknown emails are tracked like real ones, so lockouts do not tell which accounts
+/// exist.
+#[derive(Debug, Default)]
+pub struct LoginGuard {
+    state: Mutex<State>,
+}
+
+impl LoginGuard {
+    /// Refuses the attempt if the address or the account is over its limit or the
+    /// account is locked; otherwise counts it.
+    pub fn check(&self, ip: Option<&str>, email: &str) -> Result<()> {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+        let now = Instant::now();
+        if state.ips.len() + state.accounts.len() > PRUNE_ABOVE {
+            forget_stale(state, now);
+        }
+
+        let account = state.accounts.entry(email.to_string()).or_default();
+        if let Some((until, _)) = account.locked_until {
+            if until > now {
+                return Err(AppError::RateLimited(format!(
+                    "Too many failed logins, try again in {} seconds",
+                    (until - now).as_secs().max(1)
+                )));
+            }
+        }
+        prune(&mut account.attempts, now);
+        if account.attempts.len() >= ACCOUNT_LIMIT {
+            return Err(AppError::RateLimited("Too many login attempts for this account".to_string()));
+        }
+
+        if let Some(ip) = ip {
+            let attempts = state.ips.entry(ip.to_string()).or_default();
+            prune(attempts, now);
+            if attempts.len() >= IP_LIMIT {
+                return Err(AppError::RateLimited("Too many login attempts from this address".to_string()));
+            }
+            attempts.push_back(now);
+        }
+        account.attempts.push_back(now);
+        Ok(())
+    }
+
+    /// Counts a failed login. Returns the lockout when this failure locked the account.
+    pub fn record_failure(&self, email: &str) -> Option<Lockout> {
+        let mut state = self.lock();
+        let account = state.accounts.entry(email.to_string()).or_default();
+        account.failures += 1;
+        if account.failures < LOCKOUT_THRESHOLD {
+            return None;
+        }
+
+        let doublings = (account.failures - LOCKOUT_THRESHOLD).min(16);
+        let duration = BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+        let locked_until = Utc::now() + chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::zero());
+        account.locked_until = Some((Instant::now() + duration, locked_until));
+        Some(Lockout {
+            email: email.to_string(),
+            failures: account.failures,
+            locked_until,
+        })
+    }
+
+    pub fn record_success(&self, email: &str) {
+        self.lock().accounts.remove(email);
+    }
+
+    /// Accounts locked right now.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let mut state = self.lock();
+        let now = Instant::now();
+        forget_stale(&mut state, now);
+        state
+            .accounts
+            .iter()
+            .filter_map(|(email, account)| {
+                let (until, locked_until) = account.locked_until?;
+                (until > now).then(|| Lockout {
+                    email: email.clone(),
+                    failures: account.failures,
+                    locked_until,
+                })
+            })
+            .collect()
+    }
+
+    /// Lifts a lockout and forgets the account's failures.
+    pub fn unlock(&self, email: &str) -> Result<()> {
+        self.lock()
+            .accounts
+            .remove(email)
+            .map(|_| ())
+            .ok_or_else(|| AppError::Service(format!("{} is not locked", email)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Drops addresses without recent attempts and accounts that are neither locked nor
+/// recently tried. An account quiet for a whole window starts over with no failures.
+fn forget_stale(state: &mut State, now: Instant) {
+    state.accounts.retain(|_, a| {
+        prune(&mut a.attempts, now);
+        !a.attempts.is_empty() || a.locked_until.is_some_and(|(until, _)| until > now)
+    });
+    state.ips.retain(|_, attempts| {
+        prune(attempts, now);
+        !attempts.is_empty()
+    });
+}
+
+fn prune(attempts: &mut VecDeque<Instant>, now: Instant) {
+    while attempts.front().is_some_and(|at| now.duration_since(*at) > WINDOW) {
+        attempts.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An instant `ago` before now, for state that should look old.
+    fn past(ago: Duration) -> Instant {
+        Instant::now().checked_sub(ago).unwrap()
+    }
+
+    #[test]
+    fn each_address_has_its_own_limit() {
+        let guard = LoginGuard::default();
+        for n in 0..IP_LIMIT {
+            guard.check(Some("10.0.0.1"), &format!("user{}@example.com", n)).unwrap();
+        }
+        assert!(guard.check(Some("10.0.0.1"), "other@example.com").is_err());
+        assert!(guard.check(Some("10.0.0.2"), "other@example.com").is_ok());
+    }
+
+    #[test]
+    fn each_account_has_its_own_limit() {
+        let guard = LoginGuard::default();
+        for n in 0..ACCOUNT_LIMIT {
+            guard.check(Some(&format!("10.0.0.{}", n)), "admin@example.com").unwrap();
+        }
+        assert!(guard.check(Some("10.0.1.1"), "admin@example.com").is_err());
+        assert!(guard.check(None, "admin@example.com").is_err());
+        assert!(guard.check(Some("10.0.1.1"), "user@example.com").is_ok());
+    }
+
+    #[test]
+    fn attempts_outside_the_window_no_longer_count() {
+        let guard = LoginGuard::default();
+        guard.lock().accounts.entry("a@example.com".to_string()).or_default().attempts =
+            (0..ACCOUNT_LIMIT).map(|_| past(WINDOW + Duration::from_secs(1))).collect();
+        assert!(guard.check(None, "a@example.com").is_ok());
+    }
+
+    #[test]
+    fn failures_lock_the_account_from_the_threshold() {
+        let guard = LoginGuard::default();
+        for _ in 1..LOCKOUT_THRESHOLD {
+            assert!(guard.record_failure("a@example.com").is_none());
+        }
+        assert!(guard.check(None, "a@example.com").is_ok());
+
+        let lockout = guard.record_failure("a@example.com").unwrap();
+        assert_eq!(lockout.failures, LOCKOUT_THRESHOLD);
+        assert!(guard.check(None, "a@example.com").is_err());
+        assert_eq!(guard.lockouts().len(), 1);
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_cap() {
+        let guard = LoginGuard::default();
+        let lengths: Vec<i64> = (0..LOCKOUT_THRESHOLD + 20)
+            .filter_map(|_| guard.record_failure("a@example.com"))
+            .map(|lockout| (lockout.locked_until - Utc::now()).num_seconds())
+            .collect();
+        // Allow a second for the time between recording and measuring.
+        let near = |seconds: i64, expected: u64| (seconds - expected as i64).abs() <= 1;
+        assert!(near(lengths[0], BASE_LOCKOUT.as_secs()));
+        assert!(near(lengths[1], BASE_LOCKOUT.as_secs() * 2));
+        assert!(near(lengths[2], BASE_LOCKOUT.as_secs() * 4));
+        assert!(near(*lengths.last().unwrap(), MAX_LOCKOUT.as_secs()));
+    }
+
+    #[test]
+    fn expired_lockouts_let_logins_through() {
+        let guard = LoginGuard::default();
+        for _ in 0..LOCKOUT_THRESHOLD {
+            guard.record_failure("a@example.com");
+        }
+        guard.lock().accounts.get_mut("a@example.com").unwrap().locked_until = Some((past(Duration::from_secs(1)), Utc::now()));
+        assert!(guard.check(None, "a@example.com").is_ok());
+        assert!(guard.lockouts().is_empty());
+    }
+
+    #[test]
+    fn a_successful_login_clears_failures_and_the_lock() {
+        let guard = LoginGuard::default();
+        for _ in 0..LOCKOUT_THRESHOLD {
+            guard.record_failure("a@example.com");
+        }
+        guard.record_success("a@example.com");
+        assert!(guard.check(None, "a@example.com").is_ok());
+        assert!(guard.record_failure("a@example.com").is_none());
+    }
+
+    #[test]
+    fn unlocking_needs_a_tracked_account() {
+        let guard = LoginGuard::default();
+        assert!(guard.unlock("a@example.com").is_err());
+        for _ in 0..LOCKOUT_THRESHOLD {
+            guard.record_failure("a@example.com");
+        }
+        guard.unlock("a@example.com").unwrap();
+        assert!(guard.check(None, "a@example.com").is_ok());
+    }
+}
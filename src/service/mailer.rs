@@ -0,0 +1,95 @@
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Upgrade with STARTTLS (port 587 by default).
+    #[default]
+    Starttls,
+    /// TLS from the start (port 465 by default).
+    Tls,
+    /// Unencrypted, for a relay on localhost or a test server.
+    None,
+}
+
+/// `smtp` in the config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// The default port of `security` when not set.
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The sender, as `Longshoreman <ops@example.com>` or a bare address.
+    pub from: String,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+}
+
+/// Sends mail through the configured SMTP server.
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl std::fmt::Debug for Mailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Mailer {{ from: {} }}", self.from)
+    }
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig) -> Result<Self> {
+        let smtp_error = |e: lettre::transport::smtp::Error| AppError::Service(format!("Invalid SMTP settings: {}", e));
+        let mut builder = match config.security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host).map_err(smtp_error)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host).map_err(smtp_error)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let from = config
+            .from
+            .parse()
+            .map_err(|e| AppError::Service(format!("Invalid SMTP sender {:?}: {}", config.from, e)))?;
+        Ok(Self {
+            transport: builder.build(),
+            from,
+        })
+    }
+
+    /// Sends a plain text mail.
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| AppError::Service(format!("Invalid recipient {:?}: {}", to, e)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| AppError::Service(format!("Failed to build mail: {}", e)))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::Service(format!("Failed to send mail: {}", e)))?;
+        Ok(())
+    }
+}
@@ -1,192 +1,1341 @@
-use bollard::container::{Config, CreateContainerOptions};
+use bollard::container::{Config, CreateContainerOptions, StartContainerOptions, StopContainerOptions};
+use bollard::container::{InspectContainerOptions, ListContainersOptions, UploadToContainerOptions};
+use bollard::image::CreateImageOptions;
+use bollard::system::EventsOptions;
+use bollard::models::{
+    ContainerStateStatusEnum, ContainerSummary, HealthConfig, HealthStatusEnum, HostConfig, PortBinding, RestartPolicyNameEnum,
+};
 use bollard::Docker;
-use std::fs;
-use std::path::Path;
+use futures::TryStreamExt;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use uuid::Uuid;
 
 use crate::error::AppError;
-use crate::service::models::{CreateServiceRequest, Service};
+use crate::service::adopt::{self, AdoptRequest, DiscoveredContainer};
+use crate::service::apply::{self, DesiredState, Plan, PlanAction, PlanStep};
+use crate::service::dependency::{self, DependencyGraph};
+use crate::service::events::{EventBus, EventKind};
+use crate::service::labels::{self, Discrepancy, RecoveryReport};
+use crate::service::locks::KeyedLocks;
+use crate::service::secrets::{self, SecretStore};
+use crate::service::operations;
+use crate::service::proxy;
+use crate::service::redact;
+use crate::service::storage::Storage;
+use crate::service::models::{CreateServiceRequest, DependencyCondition, Replica, RestartPolicy, Service};
 
 type Result<T> = std::result::Result<T, AppError>;
 
+/// How long a dependency gets to reach its condition before the start is aborted.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(120);
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shared by every request; nothing here needs `&mut self`.
+///
+/// Reads go straight to the storage snapshot and never wait for Docker. Mutations of a
+/// single service hold that service's lock across their Docker calls, so two requests
+/// for the same service run one after the other while different services proceed in
+/// parallel. Anything that can invalidate other services' dependencies (deleting,
+/// renaming, rewiring `depends_on`) or rewrites many services at once (apply,
+/// recover) takes `bulk` exclusively instead.
 #[derive(Debug)]
 pub struct ServiceManager {
-    services: Vec<Service>,
-    file_path: String,
+    storage: Arc<dyn Storage>,
     docker: Docker,
+    locks: KeyedLocks,
+    bulk: RwLock<()>,
+    events: Arc<EventBus>,
+    secrets: Arc<SecretStore>,
 }
 
 impl ServiceManager {
-    pub fn new(docker: Docker, file_path: &str) -> Result<Self> {
-        let services = if Path::new(file_path).exists() {
-            let contents = fs::read_to_string(file_path)?;
-            serde_json::from_str(&contents)?
-        } else {
-            Vec::new()
-        };
-
+    pub async fn new(
+        docker: Docker,
+        storage: Arc<dyn Storage>,
+        events: Arc<EventBus>,
+        secrets: Arc<SecretStore>,
+    ) -> Result<Self> {
         Ok(Self {
-            services,
-            file_path: file_path.to_string(),
+            storage,
             docker,
+            locks: KeyedLocks::default(),
+            bulk: RwLock::new(()),
+            events,
+            secrets,
         })
     }
 
-    fn save(&self) -> Result<()> {
-        let contents = serde_json::to_string_pretty(&self.services)?;
-        fs::write(&self.file_path, contents)?;
-        Ok(())
-    }
+    fn container_config(request: &CreateServiceRequest) -> Config<String> {
+        let mut exposed_ports = HashMap::new();
+        let mut port_bindings: HashMap<String, Option<Vec<PortBinding>>> = HashMap::new();
+        for port in request.ports.as_deref().unwrap_or_default() {
+            let key = format!(
+                "{}/{}",
+                port.container_port,
+                port.protocol.as_deref().unwrap_or("tcp")
+            );
+            exposed_ports.insert(key.clone(), HashMap::new());
+            port_bindings
+                .entry(key)
+                .or_default()
+                .get_or_insert_with(Vec::new)
+                .push(PortBinding {
+                    host_ip: Some("0.0.0.0".to_string()),
+                    host_port: Some(port.host_port.to_string()),
+                });
+        }
 
-    pub async fn list_services(&self) -> Result<Vec<Service>> {
-        Ok(self.services.clone())
-    }
+        let binds: Vec<String> = request
+            .mounts
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(|m| {
+                let mode = if m.read_only { ":ro" } else { "" };
+                format!("{}:{}{}", m.source, m.target, mode)
+            })
+            .collect();
 
-    pub async fn create_service(&mut self, request: CreateServiceRequest) -> Result<Service> {
-        if self.services.iter().any(|s| s.name == request.name) {
-            return Err(AppError::Service(format!("Service with name {} already exists", request.name)));
-        }
+        let restart_policy = request.restart_policy.as_ref().map(|p| {
+            let (name, maximum_retry_count) = match p {
+                RestartPolicy::No => (RestartPolicyNameEnum::NO, None),
+                RestartPolicy::Always => (RestartPolicyNameEnum::ALWAYS, None),
+                RestartPolicy::UnlessStopped => (RestartPolicyNameEnum::UNLESS_STOPPED, None),
+                RestartPolicy::OnFailure { max_retries } => (RestartPolicyNameEnum::ON_FAILURE, *max_retries),
+            };
+            bollard::models::RestartPolicy {
+                name: Some(name),
+                maximum_retry_count,
+            }
+        });
+
+        let healthcheck = request.healthcheck.as_ref().map(|h| HealthConfig {
+            test: Some(h.test.clone()),
+            interval: h.interval_secs.map(|s| Duration::from_secs(s).as_nanos() as i64),
+            timeout: h.timeout_secs.map(|s| Duration::from_secs(s).as_nanos() as i64),
+            retries: h.retries.map(i64::from),
+            ..Default::default()
+        });
 
-        let mut config = Config {
+        Config {
             image: Some(request.image.clone()),
             cmd: request.command.clone(),
             env: request.env.clone(),
+            labels: request.labels.clone(),
+            exposed_ports: (!exposed_ports.is_empty()).then_some(exposed_ports),
+            healthcheck,
+            host_config: Some(HostConfig {
+                port_bindings: (!port_bindings.is_empty()).then_some(port_bindings),
+                binds: (!binds.is_empty()).then_some(binds),
+                restart_policy,
+                network_mode: Self::proxied(request).then(|| proxy::NETWORK.to_string()),
+                ..Default::default()
+            }),
             ..Default::default()
+        }
+    }
+
+    fn service_from(id: String, containers: Vec<Replica>, revision: u64, request: CreateServiceRequest) -> Service {
+        let mut service = Service {
+            id,
+            container_id: String::new(),
+            containers: Vec::new(),
+            revision,
+            name: request.name,
+            image: request.image,
+            status: "created".to_string(),
+            command: request.command,
+            env: request.env,
+            ports: request.ports,
+            healthcheck: request.healthcheck,
+            depends_on: request.depends_on,
+            mounts: request.mounts,
+            restart_policy: request.restart_policy,
+            labels: request.labels,
+            stack: request.stack,
+            secrets: request.secrets,
+            sensitive_env: request.sensitive_env,
+            replicas: request.replicas,
+            domains: request.domains,
+            routes: request.routes,
         };
+        service.set_containers(containers);
+        service
+    }
+
+    /// Whether the reverse proxy routes to the service, which puts its containers on the
+    /// proxy network.
+    fn proxied(request: &CreateServiceRequest) -> bool {
+        request.domains.as_ref().is_some_and(|d| !d.is_empty())
+    }
+
+    /// A service needs at least one replica, and a host port can only be bound by one
+    /// container.
+    fn check_replicas(request: &CreateServiceRequest) -> Result<()> {
+        let replicas = request.replica_count();
+        if replicas == 0 {
+            return Err(AppError::Service(format!("Service {} needs at least one replica", request.name)));
+        }
+        if replicas > 1 {
+            if let Some(port) = request.ports.iter().flatten().find(|p| p.host_port != 0) {
+                return Err(AppError::Service(format!(
+                    "Service {} publishes host port {}, which only one of its {} replicas could bind; use host port 0 to have Docker pick one per replica",
+                    request.name, port.host_port, replicas
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the container of replica `index` of `service`, named `<service>-<index>`
+    /// and labelled so it can be traced back to it.
+    async fn create_replica(&self, service: &Service, index: u32) -> Result<Replica> {
+        let name = format!("{}-{}", service.name, index);
+        let labels = labels::for_service(service, index)?;
+        Ok(Replica {
+            index,
+            container_id: self.create_container(&name, &service.spec(), labels).await?,
+            revision: service.revision,
+            status: "created".to_string(),
+        })
+    }
+
+    /// Creates a stopped container for a one-off task, such as a job run, pulling the
+    /// image if it is missing. Nothing is stored for it; the caller removes it.
+    pub async fn create_task_container(
+        &self,
+        name: &str,
+        request: &CreateServiceRequest,
+        labels: HashMap<String, String>,
+    ) -> Result<String> {
+        self.ensure_image(&request.image).await?;
+        self.create_container(name, request, labels).await
+    }
 
-        // if let Some(ports) = &request.ports {
-        //     let mut port_bindings = std::collections::HashMap::new();
-        //     for port in ports {
-        //         port_bindings.insert(
-        //             format!("{}/tcp", port.container_port),
-        //             Some(vec![bollard::models::PortBinding {
-        //                 host_ip: Some("0.0.0.0".to_string()),
-        //                 host_port: Some(port.host_port.to_string()),
-        //             }]),
-        //         );
-        //     }
-        //     config.exposed_ports = Some(port_bindings);
-        // }
+    /// Creates container `name` from `request` with `labels` in place of the request's
+    /// own. Secret references are resolved here and nowhere else.
+    async fn create_container(
+        &self,
+        name: &str,
+        request: &CreateServiceRequest,
+        labels: HashMap<String, String>,
+    ) -> Result<String> {
+        if Self::proxied(request) {
+            proxy::ensure_network(&self.docker).await?;
+        }
+        let mut config = Self::container_config(request);
+        config.labels = Some(labels);
+        if let Some(env) = &request.env {
+            config.env = Some(self.secrets.resolve_env(env).await?);
+        }
+        let secret_files = match request.secrets.as_deref() {
+            Some(mounts) if !mounts.is_empty() => Some(self.secrets.archive(mounts).await?),
+            _ => None,
+        };
 
         let container = self
             .docker
             .create_container(
                 Some(CreateContainerOptions {
-                    name: request.name.clone(),
+                    name: name.to_string(),
                     ..Default::default()
                 }),
                 config,
             )
             .await?;
 
-        let service = Service {
-            id: container.id.clone(),
-            name: request.name,
-            image: request.image,
-            status: "created".to_string(),
-            command: request.command,
-            env: request.env,
-            ports: request.ports,
-        };
+        if let Some(archive) = secret_files {
+            let uploaded = self
+                .docker
+                .upload_to_container(
+                    &container.id,
+                    Some(UploadToContainerOptions {
+                        path: "/",
+                        ..Default::default()
+                    }),
+                    archive.into(),
+                )
+                .await;
+            if let Err(e) = uploaded {
+                self.remove_container(&container.id).await.ok();
+                return Err(e.into());
+            }
+        }
+        Ok(container.id)
+    }
 
-        self.services.push(service.clone());
-        self.save()?;
+    /// The dependency graph as it would look with `request` stored under `replacing`
+    /// (or added, when `replacing` is `None`).
+    fn graph_with(services: &[Service], replacing: Option<&str>, request: &CreateServiceRequest) -> DependencyGraph {
+        let mut graph: DependencyGraph = services
+            .iter()
+            .filter(|s| Some(s.id.as_str()) != replacing)
+            .map(|s| (s.name.clone(), dependency::dependency_names(s.depends_on.as_deref())))
+            .collect();
+        graph.insert(
+            request.name.clone(),
+            dependency::dependency_names(request.depends_on.as_deref()),
+        );
+        graph
+    }
+
+    pub async fn list_services(&self) -> Result<Vec<Service>> {
+        self.storage.services().await
+    }
+
+    pub async fn create_service(&self, request: CreateServiceRequest) -> Result<Service> {
+        let _bulk = self.bulk.read().await;
+        // Locked by id like every other operation on a service; two creations under the
+        // same name are told apart when they are stored.
+        let id = Uuid::new_v4().to_string();
+        let _service = self.locks.lock(&id).await;
+        self.create(id, request).await
+    }
 
+    async fn create(&self, id: String, mut request: CreateServiceRequest) -> Result<Service> {
+        redact::unmask(&mut request, None)?;
+        {
+            let services = self.storage.services().await?;
+            if services.iter().any(|s| s.name == request.name) {
+                return Err(AppError::Service(format!("Service with name {} already exists", request.name)));
+            }
+            dependency::validate(&Self::graph_with(&services, None, &request))?;
+            Self::check_replicas(&request)?;
+            proxy::check_routes(&services, None, &request)?;
+        }
+
+        self.secrets.check_references(&request).await?;
+        operations::checkpoint()?;
+        operations::log(format!("creating containers for {}", request.name));
+        let mut service = Self::service_from(id, Vec::new(), 1, request);
+        let mut containers = Vec::new();
+        for index in 1..=service.spec().replica_count() {
+            match self.create_replica(&service, index).await {
+                Ok(replica) => containers.push(replica),
+                Err(e) => {
+                    self.discard(&containers).await;
+                    return Err(e);
+                }
+            }
+        }
+        service.set_containers(containers);
+
+        // Another service may have been created while the container was; the check above
+        // only saw the services at the time.
+        let stored = service.clone();
+        let committed = self
+            .storage
+            .service_transaction(|services| {
+                if services.iter().any(|s| s.name == stored.name) {
+                    return Err(AppError::Service(format!("Service with name {} already exists", stored.name)));
+                }
+                dependency::validate(&Self::graph_with(services, None, &stored.spec()))?;
+                proxy::check_routes(services, None, &stored.spec())?;
+                services.push(stored);
+                Ok(())
+            })
+            .await;
+        if let Err(e) = committed {
+            self.discard(&service.containers).await;
+            return Err(e);
+        }
+
+        self.events.publish(EventKind::ServiceCreated {
+            service_id: service.id.clone(),
+            name: service.name.clone(),
+        });
         Ok(service)
     }
 
     pub async fn get_service(&self, id: &str) -> Result<Service> {
-        self.services
-            .iter()
+        self.storage
+            .services()
+            .await?
+            .into_iter()
             .find(|s| s.id == id)
-            .cloned()
             .ok_or_else(|| AppError::Service("Service not found".to_string()))
     }
 
-    pub async fn update_service(&mut self, id: &str, request: CreateServiceRequest) -> Result<Service> {
-        let index = self
-            .services
-            .iter()
-            .position(|s| s.id == id)
-            .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+    pub async fn update_service(&self, id: &str, request: CreateServiceRequest) -> Result<Service> {
+        {
+            let _bulk = self.bulk.read().await;
+            let _service = self.locks.lock(id).await;
+            let current = self.get_service(id).await?;
+            let rewires = current.name != request.name
+                || dependency::dependency_names(current.depends_on.as_deref())
+                    != dependency::dependency_names(request.depends_on.as_deref());
+            if !rewires {
+                return self.recreate(id, request).await;
+            }
+        }
 
-        // Delete the old container
-        self.docker
-            .remove_container(
-                id,
-                Some(bollard::container::RemoveContainerOptions {
-                    force: true,
-                    ..Default::default()
-                }),
-            )
+        let _bulk = self.bulk.write().await;
+        self.recreate(id, request).await
+    }
+
+    /// Moves service `id` onto a new spec with a rolling update: replicas are replaced
+    /// one at a time, and a replica that was running is started again and has to be
+    /// ready (healthy, if there is a healthcheck) before the next one is touched.
+    ///
+    /// The new spec is stored up front. If the rollout stops part way, the service stays
+    /// on it with the remaining replicas at their old revision; updating again finishes
+    /// the job.
+    async fn recreate(&self, id: &str, mut request: CreateServiceRequest) -> Result<Service> {
+        let previous = {
+            let services = self.storage.services().await?;
+            let previous = services
+                .iter()
+                .find(|s| s.id == id)
+                .cloned()
+                .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+            redact::unmask(&mut request, Some(&previous.spec()))?;
+            dependency::validate(&Self::graph_with(&services, Some(id), &request))?;
+            Self::check_replicas(&request)?;
+            proxy::check_routes(&services, Some(id), &request)?;
+            previous
+        };
+
+        self.secrets.check_references(&request).await?;
+        operations::checkpoint()?;
+
+        let mut service = Self::service_from(previous.id, previous.containers, previous.revision + 1, request);
+        service.status = previous.status;
+        let stored = service.clone();
+        self.storage
+            .service_transaction(|services| {
+                let index = services
+                    .iter()
+                    .position(|s| s.id == stored.id)
+                    .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+                services[index] = stored;
+                Ok(())
+            })
             .await?;
+        self.events.publish(EventKind::ServiceUpdated {
+            service_id: service.id.clone(),
+            name: service.name.clone(),
+            revision: service.revision,
+        });
 
-        // Create new container
-        let mut config = Config {
-            image: Some(request.image.clone()),
-            cmd: request.command.clone(),
-            env: request.env.clone(),
-            ..Default::default()
+        // Surplus replicas are not worth replacing, and new ones already run the new spec.
+        let service = self.resize(&service).await?;
+        for replica in service.containers.iter().filter(|r| r.revision < service.revision) {
+            self.replace_replica(&service, replica).await?;
+        }
+        self.get_service(id).await
+    }
+
+    /// Replaces the container of `replica` with one created from the current spec of
+    /// `service`, starting it if the old one was running and waiting until it is ready.
+    async fn replace_replica(&self, service: &Service, replica: &Replica) -> Result<()> {
+        operations::checkpoint()?;
+        let was_running = self.is_running(&replica.container_id).await.unwrap_or(false);
+        operations::log(format!("replacing replica {} of {}", replica.index, service.name));
+        self.remove_container(&replica.container_id).await?;
+
+        let created = match self.create_replica(service, replica.index).await {
+            Ok(created) => created,
+            Err(e) => {
+                // The old container is gone; the replica is missing until the next update.
+                self.save_replica(&service.id, replica.index, None).await?;
+                return Err(e);
+            }
+        };
+        let created = self.add_replica(&service.id, created, was_running).await?;
+        if was_running {
+            let condition = match service.healthcheck {
+                Some(_) => DependencyCondition::Healthy,
+                None => DependencyCondition::Started,
+            };
+            self.wait_for_replicas(&service.name, std::slice::from_ref(&created), condition).await?;
+        }
+        Ok(())
+    }
+
+    /// Brings the number of containers of `service` to its replica count: surplus
+    /// replicas are removed, highest first, and missing ones are created, and started
+    /// if the service is running. Returns the service as stored afterwards.
+    async fn resize(&self, service: &Service) -> Result<Service> {
+        let count = service.spec().replica_count();
+        let before = service.containers.len();
+        for replica in service.containers.iter().rev().filter(|r| r.index > count) {
+            operations::checkpoint()?;
+            operations::log(format!("removing replica {} of {}", replica.index, service.name));
+            self.remove_container(&replica.container_id).await?;
+            self.save_replica(&service.id, replica.index, None).await?;
+        }
+        for index in (1..=count).filter(|i| !service.containers.iter().any(|r| r.index == *i)) {
+            operations::checkpoint()?;
+            operations::log(format!("creating replica {} of {}", index, service.name));
+            let replica = self.create_replica(service, index).await?;
+            self.add_replica(&service.id, replica, service.status == "running").await?;
+        }
+
+        let service = self.get_service(&service.id).await?;
+        if service.containers.len() != before {
+            self.events.publish(EventKind::ServiceScaled {
+                service_id: service.id.clone(),
+                name: service.name.clone(),
+                replicas: count,
+            });
+        }
+        Ok(service)
+    }
+
+    /// Stores a newly created replica, after starting it when `start` is set. One that
+    /// fails to start is stored all the same, as created.
+    async fn add_replica(&self, service_id: &str, mut replica: Replica, start: bool) -> Result<Replica> {
+        let started = match start {
+            true => self
+                .docker
+                .start_container(&replica.container_id, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(AppError::from),
+            false => Ok(()),
+        };
+        if start && started.is_ok() {
+            replica.status = "running".to_string();
+        }
+        self.save_replica(service_id, replica.index, Some(replica.clone())).await?;
+        started.map(|()| replica)
+    }
+
+    /// Stores `replica` as replica `index` of service `id`, or drops that replica for `None`.
+    async fn save_replica(&self, id: &str, index: u32, replica: Option<Replica>) -> Result<()> {
+        self.storage
+            .service_transaction(|services| {
+                let service = services
+                    .iter_mut()
+                    .find(|s| s.id == id)
+                    .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+                let mut containers: Vec<Replica> =
+                    service.containers.iter().filter(|r| r.index != index).cloned().collect();
+                containers.extend(replica);
+                service.set_containers(containers);
+                Ok(())
+            })
+            .await
+    }
+
+    /// Sets the number of replicas of service `id`, creating or removing containers.
+    /// The replicas that stay are left alone.
+    pub async fn scale_service(&self, id: &str, replicas: u32) -> Result<Service> {
+        let _bulk = self.bulk.read().await;
+        let _service = self.locks.lock(id).await;
+        let mut spec = self.get_service(id).await?.spec();
+        spec.replicas = Some(replicas);
+        self.replace_spec(id, spec).await
+    }
+
+    pub async fn delete_service(&self, id: &str) -> Result<()> {
+        let _bulk = self.bulk.write().await;
+        self.remove(id).await
+    }
+
+    async fn remove(&self, id: &str) -> Result<()> {
+        let service = {
+            let services = self.storage.services().await?;
+            let service = services
+                .iter()
+                .find(|s| s.id == id)
+                .cloned()
+                .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+
+            let graph = dependency::graph_of(&services);
+            let dependents = dependency::dependents_of(&graph, &service.name);
+            if !dependents.is_empty() {
+                return Err(AppError::Service(format!(
+                    "Service {} is required by {}",
+                    service.name,
+                    dependents.join(", ")
+                )));
+            }
+            service
         };
 
-        // if let Some(ports) = &request.ports {
-        //     let mut port_bindings = std::collections::HashMap::new();
-        //     for port in ports {
-        //         port_bindings.insert(
-        //             format!("{}/tcp", port.container_port),
-        //             Some(vec![bollard::container::PortBinding {
-        //                 host_ip: Some("0.0.0.0".to_string()),
-        //                 host_port: Some(port.host_port.to_string()),
-        //             }]),
-        //         );
-        //     }
-        //     config.exposed_ports = Some(port_bindings);
-        // }
+        operations::checkpoint()?;
+        operations::log(format!("removing containers of {}", service.name));
+        for replica in &service.containers {
+            self.remove_container(&replica.container_id).await?;
+        }
 
+        self.storage
+            .service_transaction(|services| {
+                services.retain(|s| s.id != service.id);
+                Ok(())
+            })
+            .await?;
+        self.events.publish(EventKind::ServiceDeleted {
+            service_id: service.id,
+            name: service.name,
+        });
+        Ok(())
+    }
+
+    /// Containers on the host that are not backing any managed service.
+    pub async fn discover(&self) -> Result<Vec<DiscoveredContainer>> {
+        let containers = self
+            .docker
+            .list_containers(Some(ListContainersOptions::<String> {
+                all: true,
+                ..Default::default()
+            }))
+            .await?;
+        let services = self.storage.services().await?;
+
+        Ok(containers
+            .into_iter()
+            .filter_map(|c| {
+                let id = c.id?;
+                if services.iter().any(|s| s.has_container(&id)) {
+                    return None;
+                }
+                // Job runs come and go on their own.
+                if c.labels.as_ref().is_some_and(|l| l.contains_key(labels::JOB_ID)) {
+                    return None;
+                }
+                let name = c
+                    .names
+                    .and_then(|names| names.into_iter().next())
+                    .map(|n| n.trim_start_matches('/').to_string())
+                    .unwrap_or_else(|| id.clone());
+                Some(DiscoveredContainer {
+                    id,
+                    name,
+                    image: c.image.unwrap_or_default(),
+                    state: c.state.unwrap_or_default(),
+                    status: c.status.unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    /// Brings an existing container under management as-is. The spec is reconstructed
+    /// from the container so later updates recreate it faithfully.
+    pub async fn adopt(&self, request: AdoptRequest) -> Result<Service> {
+        let _bulk = self.bulk.read().await;
         let container = self
             .docker
-            .create_container(
-                Some(CreateContainerOptions {
-                    name: request.name.clone(),
-                    ..Default::default()
-                }),
-                config,
-            )
+            .inspect_container(&request.container, None::<InspectContainerOptions>)
             .await?;
+        let id = container
+            .id
+            .clone()
+            .ok_or_else(|| AppError::Service("Container has no id".to_string()))?;
+        let services = self.storage.services().await?;
+        if services.iter().any(|s| s.has_container(&id)) {
+            return Err(AppError::Service(format!("Container {} is already managed", request.container)));
+        }
 
-        let service = Service {
-            id: container.id.clone(),
-            name: request.name,
-            image: request.image,
-            status: "created".to_string(),
-            command: request.command,
-            env: request.env,
-            ports: request.ports,
+        let name = match request.name {
+            Some(name) => name,
+            None => container
+                .name
+                .as_deref()
+                .map(|n| n.trim_start_matches('/').to_string())
+                .unwrap_or_else(|| id.clone()),
+        };
+        if services.iter().any(|s| s.name == name) {
+            return Err(AppError::Service(format!("Service with name {} already exists", name)));
+        }
+
+        // Compare against the image the container was created from, not whatever the tag
+        // points at today.
+        let image_config = match container.image.as_deref() {
+            Some(image_id) => self.docker.inspect_image(image_id).await.ok().and_then(|i| i.config),
+            None => None,
         };
+        let spec = adopt::spec_from_inspect(&name, &container, image_config.as_ref());
 
-        self.services[index] = service.clone();
-        self.save()?;
+        let status = container
+            .state
+            .and_then(|s| s.status)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "created".to_string());
+        let replica = Replica {
+            index: 1,
+            container_id: id,
+            revision: 0,
+            status: status.clone(),
+        };
+        let mut service = Self::service_from(Uuid::new_v4().to_string(), vec![replica], 0, spec);
+        service.status = status;
 
+        let stored = service.clone();
+        self.storage
+            .service_transaction(|services| {
+                if services.iter().any(|s| s.has_container(&stored.container_id) || s.name == stored.name) {
+                    return Err(AppError::Service(format!("Service with name {} already exists", stored.name)));
+                }
+                services.push(stored);
+                Ok(())
+            })
+            .await?;
+        self.events.publish(EventKind::ServiceCreated {
+            service_id: service.id.clone(),
+            name: service.name.clone(),
+        });
         Ok(service)
     }
 
-    pub async fn delete_service(&mut self, id: &str) -> Result<()> {
-        let index = self
-            .services
+    /// Containers carrying Longshoreman's labels, with the labels parsed.
+    async fn labelled_containers(&self) -> Result<Vec<(ContainerSummary, labels::ManagedLabels)>> {
+        let mut filters = HashMap::new();
+        filters.insert(
+            "label".to_string(),
+            vec![format!("{}={}", labels::MANAGED_BY, labels::MANAGER)],
+        );
+        let containers = self
+            .docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters,
+                ..Default::default()
+            }))
+            .await?;
+
+        Ok(containers
+            .into_iter()
+            .filter_map(|c| {
+                let managed = labels::parse(c.labels.as_ref()?)?;
+                Some((c, managed))
+            })
+            .collect())
+    }
+
+    /// Compares `services.json` with the labelled containers on the host.
+    pub async fn discrepancies(&self) -> Result<Vec<Discrepancy>> {
+        let containers = self.labelled_containers().await?;
+        let all_ids: Vec<String> = self
+            .docker
+            .list_containers(Some(ListContainersOptions::<String> {
+                all: true,
+                ..Default::default()
+            }))
+            .await?
+            .into_iter()
+            .filter_map(|c| c.id)
+            .collect();
+
+        let services = self.storage.services().await?;
+        let mut discrepancies = Vec::new();
+        for service in &services {
+            for replica in &service.containers {
+                if !all_ids.contains(&replica.container_id) {
+                    discrepancies.push(Discrepancy::MissingContainer {
+                        service_id: service.id.clone(),
+                        name: service.name.clone(),
+                        replica: replica.index,
+                    });
+                    continue;
+                }
+                // Adopted containers carry no labels of ours; nothing to compare.
+                let Some((_, managed)) = containers
+                    .iter()
+                    .find(|(c, _)| c.id.as_deref() == Some(replica.container_id.as_str()))
+                else {
+                    continue;
+                };
+                if managed.service_id != service.id {
+                    discrepancies.push(Discrepancy::ServiceIdMismatch {
+                        name: service.name.clone(),
+                        file: service.id.clone(),
+                        container: managed.service_id.clone(),
+                    });
+                } else if managed.revision != replica.revision {
+                    discrepancies.push(Discrepancy::RevisionMismatch {
+                        service_id: service.id.clone(),
+                        name: service.name.clone(),
+                        file: replica.revision,
+                        container: managed.revision,
+                    });
+                }
+            }
+        }
+
+        for (container, managed) in &containers {
+            let container_id = container.id.clone().unwrap_or_default();
+            if !services.iter().any(|s| s.has_container(&container_id)) {
+                discrepancies.push(Discrepancy::UntrackedContainer {
+                    container_id,
+                    service_id: managed.service_id.clone(),
+                });
+            }
+        }
+
+        Ok(discrepancies)
+    }
+
+    /// Rebuilds the service list from the labels on the host's containers.
+    ///
+    /// Labelled containers are authoritative. Services without labels (adopted ones) are
+    /// kept as long as their container still exists. With `dry_run` nothing is written.
+    pub async fn recover(&self, dry_run: bool) -> Result<RecoveryReport> {
+        let _bulk = self.bulk.write().await;
+        let discrepancies = self.discrepancies().await?;
+        let containers = self.labelled_containers().await?;
+
+        // The replicas of a service, each with its own labels.
+        let mut groups: Vec<(String, Vec<(ContainerSummary, labels::ManagedLabels)>)> = Vec::new();
+        for (container, managed) in containers {
+            match groups.iter_mut().find(|(id, _)| *id == managed.service_id) {
+                Some((_, group)) => group.push((container, managed)),
+                None => groups.push((managed.service_id.clone(), vec![(container, managed)])),
+            }
+        }
+
+        let mut services = Vec::new();
+        for (service_id, mut group) in groups {
+            // The spec comes from the newest revision, in case a rolling update stopped
+            // part way; among equals, from the lowest replica.
+            group.sort_by_key(|(_, managed)| (std::cmp::Reverse(managed.revision), managed.replica));
+            let (container, managed) = &group[0];
+            let container_id = container.id.clone().unwrap_or_default();
+            let mut spec = match managed.spec.clone() {
+                Some(spec) => spec,
+                None => {
+                    let inspect = self
+                        .docker
+                        .inspect_container(&container_id, None::<InspectContainerOptions>)
+                        .await?;
+                    let name = inspect
+                        .name
+                        .as_deref()
+                        .map(|n| n.trim_start_matches('/').to_string())
+                        .unwrap_or_else(|| container_id.clone());
+                    adopt::spec_from_inspect(&name, &inspect, None)
+                }
+            };
+            let revision = managed.revision;
+            let status = container.state.clone().unwrap_or_else(|| "created".to_string());
+
+            let replicas: Vec<Replica> = group
+                .into_iter()
+                .map(|(container, managed)| Replica {
+                    index: managed.replica,
+                    container_id: container.id.unwrap_or_default(),
+                    revision: managed.revision,
+                    status: container.state.unwrap_or_else(|| "created".to_string()),
+                })
+                .collect();
+            // The containers that exist are what the service runs now.
+            if spec.replica_count() as usize != replicas.len() {
+                spec.replicas = Some(replicas.len() as u32);
+            }
+            let mut service = Self::service_from(service_id, replicas, revision, spec);
+            service.status = status;
+            services.push(service);
+        }
+
+        let missing: Vec<&str> = discrepancies
+            .iter()
+            .filter_map(|d| match d {
+                Discrepancy::MissingContainer { service_id, .. } => Some(service_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        for service in self.storage.services().await?.iter() {
+            let recovered = services
+                .iter()
+                .any(|s| s.id == service.id || service.containers.iter().any(|r| s.has_container(&r.container_id)));
+            if !recovered && !missing.contains(&service.id.as_str()) {
+                services.push(service.clone());
+            }
+        }
+
+        if !dry_run {
+            let recovered = services.clone();
+            self.storage
+                .service_transaction(|stored| {
+                    *stored = recovered;
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(RecoveryReport { discrepancies, services })
+    }
+
+    /// Stores a new spec for a service without recreating its containers, then adds or
+    /// removes replicas to match its replica count. Only valid for changes to the fields
+    /// of [`PlanAction::Update`]. Containers keep the spec label they were created with
+    /// until they are next recreated.
+    async fn replace_spec(&self, id: &str, request: CreateServiceRequest) -> Result<Service> {
+        Self::check_replicas(&request)?;
+        let service = self
+            .storage
+            .service_transaction(|services| {
+                let index = services
+                    .iter()
+                    .position(|s| s.id == id)
+                    .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+                dependency::validate(&Self::graph_with(services, Some(id), &request))?;
+                proxy::check_routes(services, Some(id), &request)?;
+
+                let current = &services[index];
+                let mut service =
+                    Self::service_from(id.to_string(), current.containers.clone(), current.revision, request);
+                service.status = current.status.clone();
+                services[index] = service.clone();
+                Ok(service)
+            })
+            .await?;
+        self.events.publish(EventKind::ServiceUpdated {
+            service_id: service.id.clone(),
+            name: service.name.clone(),
+            revision: service.revision,
+        });
+        self.resize(&service).await
+    }
+
+    /// Works out what [`ServiceManager::apply`] would do, without doing it.
+    pub async fn plan(&self, desired: &DesiredState, prune: bool) -> Result<Plan> {
+        apply::compute_plan(&self.storage.services().await?, desired, prune)
+    }
+
+    /// Converges the managed services onto `desired`.
+    ///
+    /// Everything that can fail without side effects (parsing, dependency validation,
+    /// image pulls) happens before the first change. If a step still fails, the steps
+    /// already applied are reverted in reverse order.
+    pub async fn apply(&self, desired: &DesiredState, prune: bool) -> Result<Plan> {
+        let _bulk = self.bulk.write().await;
+        let plan = self.plan(desired, prune).await?;
+
+        for step in &plan.steps {
+            if let (PlanAction::Create | PlanAction::Recreate, Some(spec)) = (step.action, &step.desired) {
+                self.ensure_image(&spec.image).await?;
+            }
+        }
+
+        operations::set_total(plan.steps.iter().filter(|s| s.action != PlanAction::Unchanged).count());
+        let mut applied: Vec<(&PlanStep, Option<Service>)> = Vec::new();
+        for step in plan.steps.iter().filter(|s| s.action != PlanAction::Unchanged) {
+            let previous = match step.id.as_deref() {
+                Some(id) => self.get_service(id).await.ok(),
+                None => None,
+            };
+
+            operations::step(format!("{:?} {}", step.action, step.service));
+            let result = match operations::checkpoint() {
+                Ok(()) => self.apply_step(step).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                operations::log("rolling back applied steps");
+                let rollback = match self.rollback(&applied).await {
+                    Ok(()) => "previous steps were rolled back".to_string(),
+                    Err(rollback_error) => format!("rollback incomplete: {}", rollback_error),
+                };
+                return Err(AppError::Service(format!(
+                    "Failed to {:?} service {}: {}; {}",
+                    step.action, step.service, e, rollback
+                )));
+            }
+            applied.push((step, previous));
+        }
+
+        Ok(plan)
+    }
+
+    async fn apply_step(&self, step: &PlanStep) -> Result<()> {
+        let id = step.id.as_deref().unwrap_or_default();
+        let spec = || {
+            step.desired
+                .clone()
+                .ok_or_else(|| AppError::Service(format!("No desired spec for {}", step.service)))
+        };
+
+        match step.action {
+            PlanAction::Create => self.create(Uuid::new_v4().to_string(), spec()?).await.map(|_| ()),
+            PlanAction::Update => self.replace_spec(id, spec()?).await.map(|_| ()),
+            PlanAction::Recreate => self.recreate(id, spec()?).await.map(|_| ()),
+            PlanAction::Delete => self.remove(id).await,
+            PlanAction::Unchanged => Ok(()),
+        }
+    }
+
+    async fn rollback(&self, applied: &[(&PlanStep, Option<Service>)]) -> Result<()> {
+        for (step, previous) in applied.iter().rev() {
+            let current_id = self.service_by_name(&step.service).await.ok().map(|s| s.id);
+
+            match (step.action, previous, current_id) {
+                (PlanAction::Create, _, Some(id)) => self.remove(&id).await?,
+                (PlanAction::Update, Some(previous), Some(id)) => {
+                    self.replace_spec(&id, previous.spec()).await?;
+                }
+                (PlanAction::Recreate, Some(previous), Some(id)) => {
+                    self.recreate(&id, previous.spec()).await?;
+                }
+                (PlanAction::Delete, Some(previous), None) => {
+                    self.create(previous.id.clone(), previous.spec()).await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Pulls `image` unless it is already present locally.
+    async fn ensure_image(&self, image: &str) -> Result<()> {
+        if self.docker.inspect_image(image).await.is_ok() {
+            return Ok(());
+        }
+        self.pull_image(image).await
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<()> {
+        // Without an explicit tag the Docker API pulls every tag of the repository.
+        let (from_image, tag) = match image.rsplit_once(':') {
+            _ if image.contains('@') => (image, ""),
+            Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+            _ => (image, "latest"),
+        };
+
+        operations::log(format!("pulling {}", image));
+        let mut pull = self.docker.create_image(
+            Some(CreateImageOptions {
+                from_image,
+                tag,
+                ..Default::default()
+            }),
+            None,
+            None,
+        );
+        while let Some(info) = pull.try_next().await? {
+            operations::checkpoint()?;
+            // Byte-level progress updates would drown out everything else.
+            if info.progress.is_none() {
+                if let Some(status) = info.status {
+                    match info.id {
+                        Some(layer) => operations::log(format!("{}: {}", layer, status)),
+                        None => operations::log(status),
+                    }
+                }
+            }
+        }
+        self.events.publish(EventKind::ImagePulled { image: image.to_string() });
+        Ok(())
+    }
+
+    /// Forwards Docker's events for managed containers to the event bus. Runs for the
+    /// lifetime of the server, reconnecting from the last event seen if Docker goes away.
+    pub async fn watch_docker_events(&self) {
+        let mut since: Option<i64> = None;
+        loop {
+            let mut filters = HashMap::new();
+            filters.insert("type".to_string(), vec!["container".to_string()]);
+            let mut stream = self.docker.events(Some(EventsOptions {
+                since: since.map(|t| t.to_string()),
+                filters,
+                ..Default::default()
+            }));
+
+            loop {
+                let message = match stream.try_next().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::warn!("Docker event stream failed: {}", e);
+                        break;
+                    }
+                };
+                since = message.time.or(since);
+
+                let Some(container_id) = message.actor.as_ref().and_then(|a| a.id.as_deref()) else {
+                    continue;
+                };
+                let Ok(services) = self.storage.services().await else {
+                    continue;
+                };
+                let Some(service) = services.iter().find(|s| s.has_container(container_id)) else {
+                    continue;
+                };
+                if let Some(event) = EventKind::from_docker(&message, service) {
+                    self.events.publish(event);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    /// Starts a service after starting its dependencies (transitively) and waiting for
+    /// each of them to reach the condition it is depended upon with.
+    pub async fn start_service(&self, id: &str) -> Result<Service> {
+        let _bulk = self.bulk.read().await;
+        let name = self.get_service(id).await?.name;
+        let graph = dependency::graph_of(&self.storage.services().await?);
+        let order = dependency::closure_order(&graph, &name)?;
+        self.start_in_order(&order).await?;
+        self.get_service(id).await
+    }
+
+    /// Starts every service in dependency order.
+    pub async fn start_all(&self) -> Result<Vec<Service>> {
+        let _bulk = self.bulk.read().await;
+        let order = dependency::start_order(&dependency::graph_of(&self.storage.services().await?))?;
+        self.start_in_order(&order).await?;
+        self.list_services().await
+    }
+
+    /// Starts the services that were running when the server stopped, and what they
+    /// depend on, in dependency order; run once at startup so a host restart brings the
+    /// services back the way `start_all` would. Containers Docker already restarted
+    /// are left alone, but their dependents still wait for them.
+    pub async fn resume(&self) -> Result<()> {
+        let _bulk = self.bulk.read().await;
+        let services = self.storage.services().await?;
+        let graph = dependency::graph_of(&services);
+        let mut wanted = BTreeSet::new();
+        for service in services.iter().filter(|s| s.status == "running") {
+            wanted.extend(dependency::closure_order(&graph, &service.name)?);
+        }
+        let order: Vec<String> = dependency::start_order(&graph)?
+            .into_iter()
+            .filter(|n| wanted.contains(n))
+            .collect();
+        self.start_in_order(&order).await
+    }
+
+    /// Stops a service and, before it, every service that (transitively) depends on it.
+    pub async fn stop_service(&self, id: &str) -> Result<Service> {
+        let _bulk = self.bulk.read().await;
+        let name = self.get_service(id).await?.name;
+        let graph = dependency::graph_of(&self.storage.services().await?);
+
+        let mut affected = vec![name.clone()];
+        let mut index = 0;
+        while index < affected.len() {
+            for dependent in dependency::dependents_of(&graph, &affected[index]) {
+                if !affected.iter().any(|a| a == dependent) {
+                    affected.push(dependent.to_string());
+                }
+            }
+            index += 1;
+        }
+
+        let order: Vec<String> = dependency::start_order(&graph)?
+            .into_iter()
+            .rev()
+            .filter(|n| affected.contains(n))
+            .collect();
+        self.stop_in_order(&order).await?;
+        self.get_service(id).await
+    }
+
+    /// Stops every service, dependents before their dependencies.
+    pub async fn stop_all(&self) -> Result<Vec<Service>> {
+        let _bulk = self.bulk.read().await;
+        let mut order = dependency::start_order(&dependency::graph_of(&self.storage.services().await?))?;
+        order.reverse();
+        self.stop_in_order(&order).await?;
+        self.list_services().await
+    }
+
+    async fn start_in_order(&self, order: &[String]) -> Result<()> {
+        operations::set_total(order.len());
+        for name in order {
+            operations::checkpoint()?;
+            operations::step(format!("starting {}", name));
+            let service = self.service_by_name(name).await?;
+
+            // Dependencies come earlier in `order`, so they have already been started.
+            for dep in service.depends_on.as_deref().unwrap_or_default() {
+                let dep_service = self.service_by_name(&dep.service).await?;
+                self.wait_for(&dep_service, dep.condition).await?;
+            }
+
+            // Re-read under the lock in case an update replaced containers meanwhile.
+            let _service = self.locks.lock(&service.id).await;
+            let service = self.get_service(&service.id).await?;
+            for replica in &service.containers {
+                if !self.is_running(&replica.container_id).await? {
+                    self.docker
+                        .start_container(&replica.container_id, None::<StartContainerOptions<String>>)
+                        .await?;
+                }
+            }
+            self.set_status(&service.id, "running").await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_in_order(&self, order: &[String]) -> Result<()> {
+        operations::set_total(order.len());
+        for name in order {
+            operations::checkpoint()?;
+            operations::step(format!("stopping {}", name));
+            let service = self.service_by_name(name).await?;
+            let _service = self.locks.lock(&service.id).await;
+            let service = self.get_service(&service.id).await?;
+            for replica in &service.containers {
+                if self.is_running(&replica.container_id).await? {
+                    self.docker
+                        .stop_container(&replica.container_id, None::<StopContainerOptions>)
+                        .await?;
+                }
+            }
+            self.set_status(&service.id, "exited").await?;
+        }
+        Ok(())
+    }
+
+    /// Recreates the service called `name` from its current spec with `image` swapped
+    /// in, e.g. after building a new version of it.
+    pub async fn deploy_image(&self, name: &str, image: &str) -> Result<Service> {
+        let service = self.service_by_name(name).await?;
+        let mut spec = service.spec();
+        spec.image = image.to_string();
+        self.update_service(&service.id, spec).await
+    }
+
+    /// Pulls `image` and recreates service `id` with it. The pull happens even when
+    /// the image is present, since a tag such as `latest` may have moved.
+    pub async fn pull_and_deploy(&self, id: &str, image: &str) -> Result<Service> {
+        self.pull_image(image).await?;
+        let mut spec = self.get_service(id).await?.spec();
+        spec.image = image.to_string();
+        self.update_service(id, spec).await
+    }
+
+    /// The service with id or name `reference`.
+    pub async fn find_service(&self, reference: &str) -> Result<Service> {
+        self.storage
+            .services()
+            .await?
+            .into_iter()
+            .find(|s| s.id == reference || s.name == reference)
+            .ok_or_else(|| AppError::Service(format!("Service {} not found", reference)))
+    }
+
+    async fn service_by_name(&self, name: &str) -> Result<Service> {
+        self.storage
+            .services()
+            .await?
+            .into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| AppError::Service(format!("Service {} not found", name)))
+    }
+
+    async fn set_status(&self, id: &str, status: &str) -> Result<()> {
+        self.storage
+            .service_transaction(|services| {
+                let service = services
+                    .iter_mut()
+                    .find(|s| s.id == id)
+                    .ok_or_else(|| AppError::Service("Service not found".to_string()))?;
+                service.status = status.to_string();
+                for replica in service.containers.iter_mut() {
+                    replica.status = status.to_string();
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Names of the services referring to secret `name`.
+    pub async fn services_using_secret(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .storage
+            .services()
+            .await?
             .iter()
-            .position(|s| s.id == id).expect("can't find service");
+            .filter(|s| secrets::references(&s.spec()).contains(name))
+            .map(|s| s.name.clone())
+            .collect())
+    }
 
+    async fn remove_container(&self, container_id: &str) -> Result<()> {
         self.docker
             .remove_container(
-                id,
+                container_id,
                 Some(bollard::container::RemoveContainerOptions {
                     force: true,
                     ..Default::default()
                 }),
             )
             .await?;
-
-        self.services.remove(index);
-        self.save()?;
         Ok(())
     }
-} 
\ No newline at end of file
+
+    /// Removes the containers of replicas that never made it into storage.
+    async fn discard(&self, replicas: &[Replica]) {
+        for replica in replicas {
+            self.remove_container(&replica.container_id).await.ok();
+        }
+    }
+
+    async fn is_running(&self, container_id: &str) -> Result<bool> {
+        let inspect = self.docker.inspect_container(container_id, None).await?;
+        Ok(inspect.state.and_then(|s| s.running).unwrap_or(false))
+    }
+
+    /// Polls the containers of `service` until every replica satisfies `condition`.
+    async fn wait_for(&self, service: &Service, condition: DependencyCondition) -> Result<()> {
+        self.wait_for_replicas(&service.name, &service.containers, condition).await
+    }
+
+    async fn wait_for_replicas(&self, name: &str, replicas: &[Replica], condition: DependencyCondition) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + READINESS_TIMEOUT;
+        operations::log(format!("waiting for {} to be {:?}", name, condition));
+
+        loop {
+            operations::checkpoint()?;
+            let mut ready = true;
+            for replica in replicas {
+                let state = self
+                    .docker
+                    .inspect_container(&replica.container_id, None)
+                    .await?
+                    .state
+                    .unwrap_or_default();
+
+                ready &= match condition {
+                    DependencyCondition::Started => state.running.unwrap_or(false),
+                    DependencyCondition::Healthy => match state.health.and_then(|h| h.status) {
+                        Some(HealthStatusEnum::HEALTHY) => true,
+                        Some(HealthStatusEnum::UNHEALTHY) => {
+                            return Err(AppError::Service(format!(
+                                "Replica {} of service {} is unhealthy",
+                                replica.index, name
+                            )));
+                        }
+                        Some(HealthStatusEnum::STARTING) => false,
+                        // Without a healthcheck the best we can do is "running".
+                        _ => state.running.unwrap_or(false),
+                    },
+                    DependencyCondition::Completed => match state.status {
+                        Some(ContainerStateStatusEnum::EXITED) | Some(ContainerStateStatusEnum::DEAD) => {
+                            match state.exit_code {
+                                Some(0) => true,
+                                code => {
+                                    return Err(AppError::Service(format!(
+                                        "Replica {} of service {} exited with code {}",
+                                        replica.index,
+                                        name,
+                                        code.unwrap_or(-1)
+                                    )));
+                                }
+                            }
+                        }
+                        _ => false,
+                    },
+                };
+            }
+
+            if ready {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(AppError::Service(format!(
+                    "Timed out waiting for service {} to be {:?}",
+                    name, condition
+                )));
+            }
+            tokio::time::sleep(READINESS_POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use chrono::Utc;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::AppError;
+use crate::service::{persist, sqlite};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// The layout of the data directory this build reads and writes.
+pub const CURRENT_VERSION: u32 = 3;
+
+const VERSION_FILE: &str = "schema_version";
+
+/// Everything a migration may rewrite, as plain JSON so steps don't depend on the
+/// current shape of `Service` and `User`.
+#[derive(Debug, Default)]
+pub struct Documents {
+    pub services: Vec<Value>,
+    pub users: Vec<Value>,
+}
+
+struct Migration {
+    /// The version the data directory is at after this step.
+    version: u32,
+    description: &'static str,
+    apply: fn(&mut Documents) -> Result<()>,
+}
+
+/// Steps in order. Version 1 is the layout from before `schema_version` existed.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "give every service a container_id and revision",
+        apply: container_ids,
+    },
+    Migration {
+        version: 3,
+        description: "list every service's container as its only replica",
+        apply: replica_containers,
+    },
+];
+
+/// Brings the data directory up to [`CURRENT_VERSION`].
+///
+/// A fresh directory is simply stamped. An older one is copied to
+/// `backups/schema-v<n>-<timestamp>` first and then migrated one step at a time,
+/// recording the version after each step. A step fails, leaving the version where it
+/// was, when a file it would rewrite can't be read. A directory written by a newer
+/// build is refused rather than risk losing fields this build doesn't know about.
+pub fn run(data_dir: &str) -> Result<()> {
+    let Some(version) = read_version(data_dir)? else {
+        return write_version(data_dir, CURRENT_VERSION);
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(AppError::Storage(format!(
+            "{} is at schema version {}, but this build only understands up to version {}; upgrade Longshoreman or restore an older backup",
+            data_dir, version, CURRENT_VERSION
+        )));
+    }
+    if version == CURRENT_VERSION {
+        return Ok(());
+    }
+
+    let backup = backup(data_dir, version)?;
+    tracing::info!(
+        "migrating {} from schema version {} to {}, backup in {}",
+        data_dir,
+        version,
+        CURRENT_VERSION,
+        backup.display()
+    );
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        tracing::info!("schema version {}: {}", migration.version, migration.description);
+        migrate_json(data_dir, migration.apply)?;
+        sqlite::migrate(data_dir, migration.apply)?;
+        write_version(data_dir, migration.version)?;
+    }
+    Ok(())
+}
+
+/// `None` for a directory without any state yet.
+fn read_version(data_dir: &str) -> Result<Option<u32>> {
+    let path = Path::new(data_dir).join(VERSION_FILE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|_| {
+            AppError::Storage(format!("{} does not contain a version number", path.display()))
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let has_state = ["services.json", "users.json", "longshoreman.db"].iter().any(|name| {
+                let file = Path::new(data_dir).join(name);
+                file.exists() || persist::has_backups(&file)
+            });
+            Ok(has_state.then_some(1))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_version(data_dir: &str, version: u32) -> Result<()> {
+    persist::write_atomic(Path::new(data_dir).join(VERSION_FILE), format!("{}\n", version).as_bytes())
+}
+
+/// Copies every file in the data directory, so the old build can be pointed at the
+/// backup if the upgrade has to be rolled back.
+fn backup(data_dir: &str, version: u32) -> Result<PathBuf> {
+    let target = Path::new(data_dir)
+        .join("backups")
+        .join(format!("schema-v{}-{}", version, Utc::now().format("%Y%m%d%H%M%S")));
+    fs::create_dir_all(&target)?;
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), target.join(entry.file_name()))?;
+        }
+    }
+    Ok(target)
+}
+
+fn migrate_json(data_dir: &str, apply: fn(&mut Documents) -> Result<()>) -> Result<()> {
+    let services_file = format!("{}/services.json", data_dir);
+    let users_file = format!("{}/users.json", data_dir);
+    let services = load(&services_file)?;
+    let users = load(&users_file)?;
+
+    let mut documents = Documents {
+        services: services.clone().unwrap_or_default(),
+        users: users.clone().unwrap_or_default(),
+    };
+    apply(&mut documents)?;
+
+    if services.is_some() {
+        persist::write_atomic(&services_file, serde_json::to_string_pretty(&documents.services)?.as_bytes())?;
+    }
+    if users.is_some() {
+        persist::write_atomic(&users_file, serde_json::to_string_pretty(&documents.users)?.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// `None` when there is no file. One that can't be read fails the migration: stamping
+/// the new version would leave it in the old layout for good.
+fn load(path: &str) -> Result<Option<Vec<Value>>> {
+    persist::load_json(path).map_err(|e| {
+        AppError::Storage(format!(
+            "cannot migrate {}: {}; restore it from the backups directory and start again",
+            path, e
+        ))
+    })
+}
+
+/// Version 2: services used to be named after their container; `id` now stays stable
+/// and `container_id` tracks whichever container currently runs the service.
+fn container_ids(documents: &mut Documents) -> Result<()> {
+    for service in documents.services.iter_mut() {
+        let Some(service) = service.as_object_mut() else {
+            return Err(AppError::Storage("services.json contains a non-object entry".to_string()));
+        };
+        let id = service.get("id").cloned().unwrap_or(Value::Null);
+        let container_id = service.entry("container_id").or_insert(Value::Null);
+        if container_id.as_str().is_none_or(str::is_empty) {
+            *container_id = id;
+        }
+        service.entry("revision").or_insert(Value::from(0));
+    }
+    Ok(())
+}
+
+/// Version 3: a service can run several containers. Existing services have exactly one,
+/// at the service's own revision and status.
+fn replica_containers(documents: &mut Documents) -> Result<()> {
+    for service in documents.services.iter_mut() {
+        let Some(service) = service.as_object_mut() else {
+            return Err(AppError::Storage("services.json contains a non-object entry".to_string()));
+        };
+        if service.contains_key("containers") {
+            continue;
+        }
+        let replica = serde_json::json!({
+            "index": 1,
+            "container_id": service.get("container_id").cloned().unwrap_or(Value::Null),
+            "revision": service.get("revision").cloned().unwrap_or(Value::from(0)),
+            "status": service.get("status").cloned().unwrap_or(Value::from("created")),
+        });
+        service.insert("containers".to_string(), Value::Array(vec![replica]));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn services(services: Vec<Value>) -> Documents {
+        Documents {
+            services,
+            users: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn container_ids_default_to_the_service_id() {
+        let mut documents = services(vec![
+            json!({"id": "web"}),
+            json!({"id": "db", "container_id": "abc", "revision": 4}),
+        ]);
+        container_ids(&mut documents).unwrap();
+        assert_eq!(documents.services[0]["container_id"], "web");
+        assert_eq!(documents.services[0]["revision"], 0);
+        assert_eq!(documents.services[1]["container_id"], "abc");
+        assert_eq!(documents.services[1]["revision"], 4);
+    }
+
+    #[test]
+    fn single_containers_become_the_first_replica() {
+        let mut documents = services(vec![json!({"id": "web", "container_id": "abc", "revision": 2, "status": "running"})]);
+        replica_containers(&mut documents).unwrap();
+        assert_eq!(
+            documents.services[0]["containers"],
+            json!([{"index": 1, "container_id": "abc", "revision": 2, "status": "running"}])
+        );
+
+        // Running the step again leaves the replicas alone.
+        documents.services[0]["container_id"] = json!("other");
+        replica_containers(&mut documents).unwrap();
+        assert_eq!(documents.services[0]["containers"][0]["container_id"], "abc");
+    }
+
+    #[test]
+    fn non_object_services_are_refused() {
+        assert!(container_ids(&mut services(vec![json!("web")])).is_err());
+        assert!(replica_containers(&mut services(vec![json!(1)])).is_err());
+    }
+
+    #[test]
+    fn unreadable_files_fail_the_migration() {
+        let dir = std::env::temp_dir().join(format!("longshoreman-migrations-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let data_dir = dir.to_str().unwrap();
+        fs::write(dir.join("services.json"), "not json").unwrap();
+        write_version(data_dir, 1).unwrap();
+
+        assert!(run(data_dir).is_err());
+        assert_eq!(read_version(data_dir).unwrap(), Some(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
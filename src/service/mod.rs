@@ -1,12 +1,46 @@
+mod adopt;
+mod apply;
+pub mod audit;
 mod auth;
+pub mod builds;
+pub mod certificates;
+mod dependency;
+mod events;
+pub mod hooks;
 mod init;
+pub mod jobs;
+mod labels;
+mod locks;
+pub mod login_guard;
+pub mod mailer;
 mod manager;
+mod migrations;
 mod models;
+pub mod oidc;
+mod operations;
+pub mod password_reset;
+mod persist;
+pub mod policy;
+pub mod proxy;
+mod redact;
+pub mod secrets;
+mod sqlite;
+pub mod tokens;
+pub mod totp;
+pub mod storage;
+pub mod tls;
 mod user;
 mod fs_struct;
 
+pub use adopt::{AdoptRequest, DiscoveredContainer};
+pub use apply::{DesiredState, Plan};
 pub use auth::{Claims, JwtManager, Token};
 pub use init::Initializer;
+pub use events::{Event, EventBus};
+pub use labels::{Discrepancy, RecoveryReport};
 pub use manager::ServiceManager;
-pub use models::{CreateServiceRequest, PortMapping, Service};
-pub use user::{LoginRequest, LoginResponse, UserManager, ChangePasswordRequest}; 
\ No newline at end of file
+pub use operations::{Operation, OperationRegistry, OperationStatus};
+pub use redact::EnvRedactor;
+pub use models::{CreateServiceRequest, Dependency, DependencyCondition, HealthCheck, PortMapping, ProxyRoute, Replica, ScaleRequest, Service};
+pub use storage::{JsonStorage, Storage, StorageBackend};
+pub use user::{LoginRequest, LoginResponse, UserManager, ChangePasswordRequest, MfaTokenRequest, SecondFactorRequest, TotpCodeRequest};
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct CreateServiceRequest {
@@ -7,21 +8,206 @@ pub struct CreateServiceRequest {
     pub command: Option<Vec<String>>,
     pub env: Option<Vec<String>>,
     pub ports: Option<Vec<PortMapping>>,
+    pub healthcheck: Option<HealthCheck>,
+    pub depends_on: Option<Vec<Dependency>>,
+    pub mounts: Option<Vec<MountSpec>>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub labels: Option<HashMap<String, String>>,
+    /// Groups related services; stamped on their containers.
+    pub stack: Option<String>,
+    /// Secrets written into the container as files. Env entries can reference secrets
+    /// too, as `NAME=${secret:db_password}`.
+    pub secrets: Option<Vec<SecretMount>>,
+    /// Env names whose values are masked in API responses, on top of those matching the
+    /// configured sensitive patterns.
+    pub sensitive_env: Option<Vec<String>>,
+    /// How many identical containers run the service; one when not given.
+    pub replicas: Option<u32>,
+    /// Host names the built-in reverse proxy sends to this service, `*.example.com`
+    /// for any subdomain. The service's containers join the proxy network.
+    pub domains: Option<Vec<String>>,
+    /// How requests for `domains` are routed by path; everything to the first container
+    /// port when not given.
+    pub routes: Option<Vec<ProxyRoute>>,
+}
+
+impl CreateServiceRequest {
+    pub fn replica_count(&self) -> u32 {
+        self.replicas.unwrap_or(1)
+    }
+}
+
+/// Body of `POST /api/services/:id/scale`.
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct ScaleRequest {
+    pub replicas: u32,
+}
+
+/// Requests to one of the service's domains under `path_prefix`.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Eq)]
+pub struct ProxyRoute {
+    #[serde(default = "root_path")]
+    pub path_prefix: String,
+    /// The container port to forward to; the first of `ports`, or 80, when not given.
+    pub port: Option<u16>,
+    /// Forward the path with `path_prefix` taken off.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// How long a replica gets to start answering; the proxy's default when not given.
+    pub timeout_secs: Option<u64>,
+}
+
+fn root_path() -> String {
+    "/".to_string()
+}
+
+impl Default for ProxyRoute {
+    fn default() -> Self {
+        Self {
+            path_prefix: root_path(),
+            port: None,
+            strip_prefix: false,
+            timeout_secs: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct PortMapping {
     pub host_port: u16,
     pub container_port: u16,
+    /// `tcp` when not given.
+    pub protocol: Option<String>,
+}
+
+/// A named volume or host path mounted into the container.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Eq)]
+pub struct MountSpec {
+    /// Volume name or absolute host path.
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// A secret exposed to the container as a read-only file.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Eq)]
+pub struct SecretMount {
+    pub secret: String,
+    /// Path inside the container; `/run/secrets/<secret>` when not given.
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    No,
+    Always,
+    UnlessStopped,
+    OnFailure { max_retries: Option<i64> },
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct Service {
     pub id: String,
+    /// The container of the first replica; replaced on every recreate.
+    pub container_id: String,
+    /// One per replica, in replica order.
+    pub containers: Vec<Replica>,
+    /// Bumped every time the container is recreated from a new spec. Zero for adopted
+    /// containers Longshoreman did not create.
+    pub revision: u64,
     pub name: String,
     pub image: String,
     pub status: String,
     pub command: Option<Vec<String>>,
     pub env: Option<Vec<String>>,
     pub ports: Option<Vec<PortMapping>>,
-} 
\ No newline at end of file
+    pub healthcheck: Option<HealthCheck>,
+    pub depends_on: Option<Vec<Dependency>>,
+    pub mounts: Option<Vec<MountSpec>>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub labels: Option<HashMap<String, String>>,
+    pub stack: Option<String>,
+    pub secrets: Option<Vec<SecretMount>>,
+    pub sensitive_env: Option<Vec<String>>,
+    pub replicas: Option<u32>,
+    pub domains: Option<Vec<String>>,
+    pub routes: Option<Vec<ProxyRoute>>,
+}
+
+/// One of the containers running a service.
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct Replica {
+    /// Starts at 1; the container is named `<service>-<index>`.
+    pub index: u32,
+    pub container_id: String,
+    /// The service revision the container was created from. Behind the service's own
+    /// while a rolling update is under way, or after one that stopped part way.
+    pub revision: u64,
+    pub status: String,
+}
+
+impl Service {
+    /// The request that would (re)create this service as it is now.
+    pub fn spec(&self) -> CreateServiceRequest {
+        CreateServiceRequest {
+            name: self.name.clone(),
+            image: self.image.clone(),
+            command: self.command.clone(),
+            env: self.env.clone(),
+            ports: self.ports.clone(),
+            healthcheck: self.healthcheck.clone(),
+            depends_on: self.depends_on.clone(),
+            mounts: self.mounts.clone(),
+            restart_policy: self.restart_policy.clone(),
+            labels: self.labels.clone(),
+            stack: self.stack.clone(),
+            secrets: self.secrets.clone(),
+            sensitive_env: self.sensitive_env.clone(),
+            replicas: self.replicas,
+            domains: self.domains.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+
+    /// Whether `container_id` is one of this service's replicas.
+    pub fn has_container(&self, container_id: &str) -> bool {
+        self.containers.iter().any(|r| r.container_id == container_id)
+    }
+
+    /// Replaces the replica list, keeping `container_id` on the first replica.
+    pub fn set_containers(&mut self, mut containers: Vec<Replica>) {
+        containers.sort_by_key(|r| r.index);
+        self.container_id = containers.first().map(|r| r.container_id.clone()).unwrap_or_default();
+        self.containers = containers;
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct HealthCheck {
+    pub test: Vec<String>,
+    pub interval_secs: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct Dependency {
+    pub service: String,
+    #[serde(default)]
+    pub condition: DependencyCondition,
+}
+
+/// What a dependency has to reach before its dependents are started.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyCondition {
+    /// The container is running.
+    #[default]
+    Started,
+    /// The container's healthcheck reports healthy.
+    Healthy,
+    /// The container ran to completion with exit code 0.
+    Completed,
+}
@@ -0,0 +1,481 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::{
+    reqwest, AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet,
+    EndpointNotSet, EndpointSet, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+use crate::service::tokens::Scope;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How long the provider's metadata and signing keys are used before they are fetched
+/// again, so rotated keys are picked up.
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long the user has at the provider before the login is forgotten.
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+/// Logins that may be waiting at the provider at once. Starting one takes no
+/// credentials, so this bounds what anonymous requests can make us keep.
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+type OidcClient =
+    CoreClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointMaybeSet, EndpointMaybeSet>;
+
+/// `oidc` in the config: signing in with an OpenID Connect provider.
+///
+/// The provider is trusted to have checked whatever second factor it requires: the
+/// `require_totp` policy only applies to password logins. Enforce MFA at the provider
+/// for users who sign in this way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// The provider's issuer; its metadata is read from `/.well-known/openid-configuration`
+    /// below it. Plain `http` works for a local mock provider.
+    pub issuer_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// This server's `/api/oidc/callback`, as registered with the provider.
+    pub redirect_url: String,
+    /// Requested besides `openid`.
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// The ID token claim that lists the user's groups.
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// What members of each group may do; a user gets the scopes of all their groups.
+    #[serde(default)]
+    pub group_scopes: HashMap<String, Vec<Scope>>,
+    /// Given to users in none of the mapped groups. Without it, they cannot sign in.
+    #[serde(default)]
+    pub default_scopes: Option<Vec<Scope>>,
+    /// Create a user on their first sign-in. When off, only users that already exist
+    /// (matched by verified email) may sign in.
+    #[serde(default = "default_true")]
+    pub auto_provision: bool,
+    /// Where to send the browser after signing in, with the session token as
+    /// `#token=<jwt>`. The token is returned as JSON when not set.
+    #[serde(default)]
+    pub post_login_redirect: Option<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["email".to_string(), "profile".to_string()]
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Who the provider says signed in, and what they may do here.
+#[derive(Debug, Clone)]
+pub struct SsoIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: String,
+    /// Whether the provider vouches for the email; only then may it be matched to an
+    /// existing user.
+    pub email_verified: bool,
+    pub groups: Vec<String>,
+    pub scopes: Vec<Scope>,
+}
+
+struct PendingLogin {
+    pkce_verifier: PkceCodeVerifier,
+    nonce: Nonce,
+    expires: Instant,
+}
+
+/// The authorization code flow with PKCE against the configured provider.
+pub struct OidcManager {
+    config: OidcConfig,
+    http: reqwest::Client,
+    client: tokio::sync::Mutex<Option<(Instant, OidcClient)>>,
+    /// Logins sent to the provider, by their `state`.
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl std::fmt::Debug for OidcManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OidcManager {{ issuer_url: {} }}", self.config.issuer_url)
+    }
+}
+
+impl OidcManager {
+    pub fn new(config: OidcConfig) -> Result<Self> {
+        let http = reqwest::ClientBuilder::new()
+            // Following redirects would let the provider's responses point us anywhere.
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| AppError::Service(format!("Failed to build the OIDC HTTP client: {}", e)))?;
+        Ok(Self {
+            config,
+            http,
+            client: tokio::sync::Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn auto_provision(&self) -> bool {
+        self.config.auto_provision
+    }
+
+    pub fn post_login_redirect(&self) -> Option<&str> {
+        self.config.post_login_redirect.as_deref()
+    }
+
+    /// The client for the provider, discovering it again once [`DISCOVERY_TTL`] is up.
+    async fn client(&self) -> Result<OidcClient> {
+        let mut cached = self.client.lock().await;
+        if let Some((fetched, client)) = cached.as_ref() {
+            if fetched.elapsed() < DISCOVERY_TTL {
+                return Ok(client.clone());
+            }
+        }
+
+        let issuer = IssuerUrl::new(self.config.issuer_url.clone())
+            .map_err(|e| AppError::Service(format!("Invalid OIDC issuer URL: {}", e)))?;
+        let metadata = CoreProviderMetadata::discover_async(issuer, &self.http)
+            .await
+            .map_err(|e| AppError::Service(format!("OIDC discovery failed: {}", e)))?;
+        let redirect = RedirectUrl::new(self.config.redirect_url.clone())
+            .map_err(|e| AppError::Service(format!("Invalid OIDC redirect URL: {}", e)))?;
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(self.config.client_id.clone()),
+            self.config.client_secret.clone().map(ClientSecret::new),
+        )
+        .set_redirect_uri(redirect);
+        *cached = Some((Instant::now(), client.clone()));
+        Ok(client)
+    }
+
+    /// Starts a login: the provider URL to send the browser to.
+    pub async fn authorization_url(&self) -> Result<String> {
+        let client = self.client().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = client
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .set_pkce_challenge(pkce_challenge);
+        for scope in &self.config.scopes {
+            request = request.add_scope(openidconnect::Scope::new(scope.clone()));
+        }
+        let (url, state, nonce) = request.url();
+
+        self.remember(
+            state.secret().clone(),
+            PendingLogin {
+                pkce_verifier,
+                nonce,
+                expires: Instant::now() + LOGIN_TTL,
+            },
+        )?;
+        Ok(url.to_string())
+    }
+
+    /// Keeps `login` until the provider sends the browser back with `state`.
+    fn remember(&self, state: String, login: PendingLogin) -> Result<()> {
+        let mut pending = self.lock_pending();
+        let now = Instant::now();
+        pending.retain(|_, login| login.expires > now);
+        if pending.len() >= MAX_PENDING_LOGINS {
+            return Err(AppError::RateLimited("Too many single sign-on logins in progress, try again later".to_string()));
+        }
+        pending.insert(state, login);
+        Ok(())
+    }
+
+    /// Finishes the login the provider redirected back with: exchanges `code`, checks the
+    /// ID token (signature, issuer, audience, expiry, nonce and access token hash) and
+    /// maps the user's groups to scopes.
+    pub async fn complete(&self, code: &str, state: &str) -> Result<SsoIdentity> {
+        let login = self
+            .lock_pending()
+            .remove(state)
+            .filter(|login| login.expires > Instant::now())
+            .ok_or_else(|| AppError::Auth("Unknown or expired single sign-on login".to_string()))?;
+
+        let client = self.client().await?;
+        let response = client
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .map_err(|e| AppError::Service(format!("OIDC provider has no token endpoint: {}", e)))?
+            .set_pkce_verifier(login.pkce_verifier)
+            .request_async(&self.http)
+            .await
+            .map_err(|e| AppError::Auth(format!("OIDC code exchange failed: {}", e)))?;
+
+        let invalid = |e: &dyn std::fmt::Display| AppError::Auth(format!("Invalid ID token: {}", e));
+        let id_token = response
+            .id_token()
+            .ok_or_else(|| AppError::Auth("OIDC provider returned no ID token".to_string()))?;
+        let verifier = client.id_token_verifier();
+        let claims = id_token.claims(&verifier, &login.nonce).map_err(|e| invalid(&e))?;
+        if let Some(expected) = claims.access_token_hash() {
+            let actual = AccessTokenHash::from_token(
+                response.access_token(),
+                id_token.signing_alg().map_err(|e| invalid(&e))?,
+                id_token.signing_key(&verifier).map_err(|e| invalid(&e))?,
+            )
+            .map_err(|e| invalid(&e))?;
+            if actual != *expected {
+                return Err(AppError::Auth("Invalid ID token: access token hash mismatch".to_string()));
+            }
+        }
+
+        let email = claims
+            .email()
+            .map(|email| email.as_str().to_string())
+            .ok_or_else(|| AppError::Auth("The ID token has no email claim".to_string()))?;
+        // The token's signature was checked above, so its other claims can be read as is.
+        let groups = groups(&id_token.to_string(), &self.config.groups_claim);
+        let scopes = self.scopes_for(&groups).ok_or_else(|| {
+            AppError::Forbidden(format!("{} is in none of the groups allowed to sign in", email))
+        })?;
+        Ok(SsoIdentity {
+            issuer: claims.issuer().as_str().to_string(),
+            subject: claims.subject().as_str().to_string(),
+            email,
+            email_verified: claims.email_verified().unwrap_or(false),
+            groups,
+            scopes,
+        })
+    }
+
+    /// The scopes of all mapped groups the user is in, or the default ones if none.
+    fn scopes_for(&self, groups: &[String]) -> Option<Vec<Scope>> {
+        let mut scopes: Vec<Scope> = Vec::new();
+        for scope in groups.iter().filter_map(|g| self.config.group_scopes.get(g)).flatten() {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+        if scopes.is_empty() {
+            return self.config.default_scopes.clone();
+        }
+        Some(scopes)
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, HashMap<String, PendingLogin>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The groups claim of a JWT, which may be a list or a single string.
+fn groups(jwt: &str, claim: &str) -> Vec<String> {
+    let payload = jwt
+        .split('.')
+        .nth(1)
+        .and_then(|part| URL_SAFE_NO_PAD.decode(part).ok())
+        .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok());
+    match payload.as_ref().and_then(|p| p.get(claim)) {
+        Some(Value::Array(groups)) => groups.iter().filter_map(|g| g.as_str().map(str::to_string)).collect(),
+        Some(Value::String(group)) => vec![group.clone()],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn config() -> OidcConfig {
+        serde_json::from_value(serde_json::json!({
+            "issuer_url": "http://localhost:8080",
+            "client_id": "longshoreman",
+            "redirect_url": "http://localhost:3000/api/oidc/callback",
+            "group_scopes": { "ops": ["admin"], "dev": [{ "deploy": ["web"] }, "read"], "qa": ["read"] },
+        }))
+        .unwrap()
+    }
+
+    fn jwt(claims: serde_json::Value) -> String {
+        format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    fn login(expires: Instant) -> PendingLogin {
+        PendingLogin {
+            pkce_verifier: PkceCodeVerifier::new("verifier".to_string()),
+            nonce: Nonce::new("nonce".to_string()),
+            expires,
+        }
+    }
+
+    #[test]
+    fn groups_are_read_from_a_list_or_a_string() {
+        assert_eq!(groups(&jwt(serde_json::json!({ "groups": ["a", "b", 1] })), "groups"), vec!["a", "b"]);
+        assert_eq!(groups(&jwt(serde_json::json!({ "roles": "ops" })), "roles"), vec!["ops"]);
+        assert!(groups(&jwt(serde_json::json!({})), "groups").is_empty());
+        assert!(groups("not a jwt", "groups").is_empty());
+    }
+
+    #[test]
+    fn scopes_of_all_groups_are_combined_once() {
+        let manager = OidcManager::new(config()).unwrap();
+        let scopes = manager.scopes_for(&["dev".to_string(), "qa".to_string()]).unwrap();
+        assert_eq!(scopes, vec![Scope::Deploy(vec!["web".to_string()]), Scope::Read]);
+    }
+
+    #[test]
+    fn unmapped_users_get_the_default_scopes_or_nothing() {
+        let manager = OidcManager::new(config()).unwrap();
+        assert_eq!(manager.scopes_for(&["sales".to_string()]), None);
+
+        let mut config = config();
+        config.default_scopes = Some(vec![Scope::Read]);
+        let manager = OidcManager::new(config).unwrap();
+        assert_eq!(manager.scopes_for(&[]), Some(vec![Scope::Read]));
+    }
+
+    #[test]
+    fn pending_logins_are_capped() {
+        let manager = OidcManager::new(config()).unwrap();
+        let expires = Instant::now() + LOGIN_TTL;
+        for i in 0..MAX_PENDING_LOGINS {
+            manager.remember(i.to_string(), login(expires)).unwrap();
+        }
+        assert!(matches!(manager.remember("one more".to_string(), login(expires)), Err(AppError::RateLimited(_))));
+    }
+
+    #[test]
+    fn expired_logins_make_room() {
+        let manager = OidcManager::new(config()).unwrap();
+        for i in 0..MAX_PENDING_LOGINS {
+            manager.remember(i.to_string(), login(Instant::now())).unwrap();
+        }
+        manager.remember("fresh".to_string(), login(Instant::now() + LOGIN_TTL)).unwrap();
+        assert_eq!(manager.lock_pending().len(), 1);
+    }
+
+    /// A provider on a local port that signs ID tokens with a fresh ES256 key. The ID
+    /// token it returns holds `claims`, which a test sets before completing a login.
+    struct MockProvider {
+        issuer: String,
+        claims: Arc<Mutex<Value>>,
+    }
+
+    impl MockProvider {
+        async fn start() -> Self {
+            use axum::routing::{get, post};
+            use axum::{Json, Router};
+            use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let key = rcgen::KeyPair::generate().unwrap();
+            // An uncompressed P-256 point: 0x04, then x and y.
+            let point = key.public_key_raw();
+            let jwks = serde_json::json!({ "keys": [{
+                "kty": "EC", "crv": "P-256", "use": "sig", "alg": "ES256", "kid": "test",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]});
+            let metadata = serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+                "response_types_supported": ["code"],
+                "subject_types_supported": ["public"],
+                "id_token_signing_alg_values_supported": ["ES256"],
+            });
+            let signing_key = EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap();
+            let claims = Arc::new(Mutex::new(Value::Null));
+
+            let token_claims = claims.clone();
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(move || async move { Json(metadata) }))
+                .route("/jwks", get(move || async move { Json(jwks) }))
+                .route(
+                    "/token",
+                    post(move || {
+                        let mut header = Header::new(Algorithm::ES256);
+                        header.kid = Some("test".to_string());
+                        let claims = token_claims.lock().unwrap().clone();
+                        let id_token = jsonwebtoken::encode(&header, &claims, &signing_key).unwrap();
+                        async move {
+                            Json(serde_json::json!({
+                                "access_token": "access",
+                                "token_type": "Bearer",
+                                "expires_in": 300,
+                                "id_token": id_token,
+                            }))
+                        }
+                    }),
+                );
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            Self { issuer, claims }
+        }
+
+        fn manager(&self) -> OidcManager {
+            let mut config = config();
+            config.issuer_url = self.issuer.clone();
+            OidcManager::new(config).unwrap()
+        }
+
+        /// Starts a login with `manager` and completes it with an ID token holding valid
+        /// claims for it, changed by `tamper`.
+        async fn log_in(&self, manager: &OidcManager, tamper: impl FnOnce(&mut Value)) -> Result<SsoIdentity> {
+            let url = openidconnect::url::Url::parse(&manager.authorization_url().await.unwrap()).unwrap();
+            let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).unwrap().1.into_owned();
+            let now = chrono::Utc::now().timestamp();
+            let mut claims = serde_json::json!({
+                "iss": self.issuer,
+                "sub": "user-1",
+                "aud": "longshoreman",
+                "iat": now,
+                "exp": now + 300,
+                "nonce": param("nonce"),
+                "email": "a@example.com",
+                "email_verified": true,
+                "groups": ["ops"],
+            });
+            tamper(&mut claims);
+            *self.claims.lock().unwrap() = claims;
+            manager.complete("code", &param("state")).await
+        }
+    }
+
+    #[tokio::test]
+    async fn logins_complete_against_a_mock_provider() {
+        let provider = MockProvider::start().await;
+        let manager = provider.manager();
+        let identity = provider.log_in(&manager, |_| {}).await.unwrap();
+        assert_eq!(identity.issuer, provider.issuer);
+        assert_eq!(identity.subject, "user-1");
+        assert_eq!(identity.email, "a@example.com");
+        assert!(identity.email_verified);
+        assert_eq!(identity.scopes, vec![Scope::Admin]);
+        // The login was used up.
+        assert!(manager.lock_pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tokens_for_another_login_or_client_are_refused() {
+        let provider = MockProvider::start().await;
+        let manager = provider.manager();
+        let wrong_nonce = provider.log_in(&manager, |claims| claims["nonce"] = "replayed".into()).await;
+        assert!(matches!(wrong_nonce, Err(AppError::Auth(_))));
+        let wrong_audience = provider.log_in(&manager, |claims| claims["aud"] = "other-client".into()).await;
+        assert!(matches!(wrong_audience, Err(AppError::Auth(_))));
+        let wrong_issuer = provider.log_in(&manager, |claims| claims["iss"] = "http://evil.example".into()).await;
+        assert!(matches!(wrong_issuer, Err(AppError::Auth(_))));
+        let expired = provider.log_in(&manager, |claims| claims["exp"] = 1.into()).await;
+        assert!(matches!(expired, Err(AppError::Auth(_))));
+        let ungrouped = provider.log_in(&manager, |claims| claims["groups"] = serde_json::json!(["sales"])).await;
+        assert!(matches!(ungrouped, Err(AppError::Forbidden(_))));
+
+        assert!(manager.complete("code", "unknown state").await.is_err());
+    }
+}
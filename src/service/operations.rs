@@ -0,0 +1,222 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Finished operations are forgotten after this long.
+const RETENTION: chrono::Duration = chrono::Duration::hours(1);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// A mutation running in the background, as reported by `GET /api/operations/:id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    pub id: String,
+    /// What was requested, e.g. `create_service`.
+    pub kind: String,
+    /// The service (or other object) it acts on, when there is a single one.
+    pub target: Option<String>,
+    pub status: OperationStatus,
+    pub current_step: Option<String>,
+    pub steps_done: usize,
+    /// Known up front for multi-step operations such as apply or start-all.
+    pub steps_total: Option<usize>,
+    pub logs: Vec<LogEntry>,
+    pub cancel_requested: bool,
+    /// What the synchronous endpoint would have returned.
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: watch::Sender<Operation>,
+    cancelled: AtomicBool,
+}
+
+impl Entry {
+    fn update(&self, change: impl FnOnce(&mut Operation)) {
+        self.state.send_modify(change);
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<Entry>;
+}
+
+/// Every mutation runs as an operation, whether the client waits for it or not, so
+/// it finishes (and is recorded) even if the client goes away.
+#[derive(Debug, Default)]
+pub struct OperationRegistry {
+    operations: Mutex<HashMap<String, Arc<Entry>>>,
+}
+
+impl OperationRegistry {
+    /// Runs `work` as a new operation. The handle yields its result for callers that
+    /// want to wait; dropping it does not stop the operation.
+    pub fn spawn<T, F>(&self, kind: &str, target: Option<String>, work: F) -> (Operation, JoinHandle<Result<T>>)
+    where
+        T: Serialize + Send + 'static,
+        F: Future<Output = Result<T>> + Send + 'static,
+    {
+        let operation = Operation {
+            id: Uuid::new_v4().to_string(),
+            kind: kind.to_string(),
+            target,
+            status: OperationStatus::Running,
+            current_step: None,
+            steps_done: 0,
+            steps_total: None,
+            logs: Vec::new(),
+            cancel_requested: false,
+            result: None,
+            error: None,
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+        let entry = Arc::new(Entry {
+            state: watch::channel(operation.clone()).0,
+            cancelled: AtomicBool::new(false),
+        });
+
+        {
+            let mut operations = self.lock();
+            let cutoff = Utc::now() - RETENTION;
+            operations.retain(|_, e| e.state.borrow().finished_at.is_none_or(|at| at > cutoff));
+            operations.insert(operation.id.clone(), entry.clone());
+        }
+
+        let handle = tokio::spawn(CURRENT.scope(entry.clone(), async move {
+            let result = work.await;
+            entry.update(|op| {
+                if op.current_step.take().is_some() && result.is_ok() {
+                    op.steps_done += 1;
+                }
+                match &result {
+                    Ok(value) => {
+                        op.status = OperationStatus::Succeeded;
+                        op.result = serde_json::to_value(value).ok();
+                    }
+                    Err(e) => {
+                        op.status = if entry.cancelled.load(Ordering::SeqCst) {
+                            OperationStatus::Cancelled
+                        } else {
+                            OperationStatus::Failed
+                        };
+                        op.error = Some(e.to_string());
+                    }
+                }
+                op.finished_at = Some(Utc::now());
+            });
+            result
+        }));
+
+        (operation, handle)
+    }
+
+    pub fn list(&self) -> Vec<Operation> {
+        let mut operations: Vec<Operation> = self.lock().values().map(|e| e.state.borrow().clone()).collect();
+        operations.sort_by_key(|op| op.created_at);
+        operations
+    }
+
+    pub fn get(&self, id: &str) -> Result<Operation> {
+        let operation = self.entry(id)?.state.borrow().clone();
+        Ok(operation)
+    }
+
+    /// Updates of one operation, starting with its current state.
+    pub fn subscribe(&self, id: &str) -> Result<watch::Receiver<Operation>> {
+        Ok(self.entry(id)?.state.subscribe())
+    }
+
+    /// Asks the operation to stop at its next checkpoint. Steps already taken are not
+    /// undone, except by operations that roll back on failure anyway (apply).
+    pub fn cancel(&self, id: &str) -> Result<Operation> {
+        let entry = self.entry(id)?;
+        if entry.state.borrow().status != OperationStatus::Running {
+            return Err(AppError::Service(format!("Operation {} has already finished", id)));
+        }
+        entry.cancelled.store(true, Ordering::SeqCst);
+        entry.update(|op| op.cancel_requested = true);
+        let operation = entry.state.borrow().clone();
+        Ok(operation)
+    }
+
+    fn entry(&self, id: &str) -> Result<Arc<Entry>> {
+        self.lock()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| AppError::Service("Operation not found".to_string()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Entry>>> {
+        self.operations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// The functions below report on whichever operation the calling task is running.
+// Outside of one they do nothing, so the service code can call them unconditionally.
+
+fn with_current(f: impl FnOnce(&Entry)) {
+    let _ = CURRENT.try_with(|entry| f(entry));
+}
+
+/// Starts the next step; the previous one counts as done.
+pub fn step(message: impl Into<String>) {
+    let message = message.into();
+    with_current(|entry| {
+        entry.update(|op| {
+            if op.current_step.is_some() {
+                op.steps_done += 1;
+            }
+            op.current_step = Some(message.clone());
+            op.logs.push(LogEntry { at: Utc::now(), message });
+        })
+    });
+}
+
+pub fn set_total(steps: usize) {
+    with_current(|entry| entry.update(|op| op.steps_total = Some(steps)));
+}
+
+pub fn log(message: impl Into<String>) {
+    let message = message.into();
+    with_current(|entry| entry.update(|op| op.logs.push(LogEntry { at: Utc::now(), message })));
+}
+
+/// Fails if the current operation has been cancelled. Called between steps, where
+/// stopping leaves nothing half done.
+pub fn checkpoint() -> Result<()> {
+    let cancelled = CURRENT
+        .try_with(|entry| entry.cancelled.load(Ordering::SeqCst))
+        .unwrap_or(false);
+    if cancelled {
+        return Err(AppError::Service("Operation cancelled".to_string()));
+    }
+    Ok(())
+}
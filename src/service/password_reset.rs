@@ -0,0 +1,190 @@
+use chrono::{DateTime, Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How long a reset token can be used.
+const RESET_TTL: Duration = Duration::hours(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredReset {
+    email: String,
+    /// SHA-256 of the token; the token itself is only handed out once.
+    token_hash: String,
+    /// The admin who issued it.
+    issued_by: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct IssueResetRequest {
+    /// Mail the reset link to the user instead of returning it.
+    #[serde(default)]
+    pub send_email: bool,
+}
+
+/// A reset just issued. The token and link are left out when they were mailed.
+#[derive(Debug, Serialize)]
+pub struct IssuedReset {
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+    pub emailed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// One-time password reset tokens in `<data_dir>/password_resets.json`.
+#[derive(Debug)]
+pub struct PasswordResets {
+    resets: FsStruct<Vec<StoredReset>>,
+    /// The reset page, with `{token}` where the token goes.
+    link_template: Option<String>,
+}
+
+impl PasswordResets {
+    pub async fn open(data_dir: &str, link_template: Option<String>) -> Result<Self> {
+        Ok(Self {
+            resets: FsStruct::open(format!("{}/password_resets.json", data_dir)).await?,
+            link_template,
+        })
+    }
+
+    /// A new token for `email`, replacing any the user still had.
+    pub async fn issue(&self, email: &str, issued_by: &str) -> Result<(String, DateTime<Utc>)> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let now = Utc::now();
+        let reset = StoredReset {
+            email: email.to_string(),
+            token_hash: hash(&token),
+            issued_by: issued_by.to_string(),
+            expires_at: now + RESET_TTL,
+        };
+        let expires_at = reset.expires_at;
+        self.resets
+            .transaction(|resets| {
+                resets.retain(|r| r.email != email && r.expires_at > now);
+                resets.push(reset);
+                Ok(())
+            })
+            .await?;
+        Ok((token, expires_at))
+    }
+
+    /// The link to the reset page for `token`, if one is configured.
+    pub fn link(&self, token: &str) -> Option<String> {
+        self.link_template.as_ref().map(|t| t.replace("{token}", token))
+    }
+
+    /// The user `token` resets the password of, leaving the token for
+    /// [`PasswordResets::redeem`].
+    pub async fn peek(&self, token: &str) -> Result<String> {
+        let token_hash = hash(token);
+        let now = Utc::now();
+        self.resets
+            .snapshot()
+            .await
+            .into_iter()
+            .find(|r| r.token_hash == token_hash && r.expires_at > now)
+            .map(|r| r.email)
+            .ok_or_else(|| AppError::Auth("Invalid or expired reset token".to_string()))
+    }
+
+    /// The user `token` resets the password of. Uses the token up.
+    pub async fn redeem(&self, token: &str) -> Result<String> {
+        let token_hash = hash(token);
+        let now = Utc::now();
+        self.resets
+            .transaction(|resets| {
+                let found = resets.iter().position(|r| r.token_hash == token_hash);
+                let reset = found.map(|i| resets.remove(i));
+                resets.retain(|r| r.expires_at > now);
+                match reset {
+                    Some(reset) if reset.expires_at > now => Ok(reset.email),
+                    _ => Err(AppError::Auth("Invalid or expired reset token".to_string())),
+                }
+            })
+            .await
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("password-resets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn tokens_are_used_up_by_redeeming_only() {
+        let dir = scratch_dir();
+        let resets = PasswordResets::open(dir.to_str().unwrap(), None).await.unwrap();
+        let (token, _) = resets.issue("a@example.com", "admin@example.com").await.unwrap();
+
+        assert_eq!(resets.peek(&token).await.unwrap(), "a@example.com");
+        assert_eq!(resets.peek(&token).await.unwrap(), "a@example.com");
+        assert_eq!(resets.redeem(&token).await.unwrap(), "a@example.com");
+        assert!(resets.redeem(&token).await.is_err());
+        assert!(resets.peek(&token).await.is_err());
+        assert!(!std::fs::read_to_string(dir.join("password_resets.json")).unwrap().contains(&token));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_refused() {
+        let dir = scratch_dir();
+        let resets = PasswordResets::open(dir.to_str().unwrap(), None).await.unwrap();
+        let (token, expires_at) = resets.issue("a@example.com", "admin@example.com").await.unwrap();
+        assert!(expires_at > Utc::now() + RESET_TTL - Duration::minutes(1));
+        resets
+            .resets
+            .transaction(|resets| {
+                resets[0].expires_at = Utc::now() - Duration::seconds(1);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(resets.peek(&token).await.is_err());
+        assert!(resets.redeem(&token).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_new_token_replaces_the_earlier_one() {
+        let dir = scratch_dir();
+        let resets = PasswordResets::open(dir.to_str().unwrap(), Some("https://example.com/reset?t={token}".to_string()))
+            .await
+            .unwrap();
+        let (first, _) = resets.issue("a@example.com", "admin@example.com").await.unwrap();
+        let (other, _) = resets.issue("b@example.com", "admin@example.com").await.unwrap();
+        let (second, _) = resets.issue("a@example.com", "admin@example.com").await.unwrap();
+
+        assert!(resets.redeem(&first).await.is_err());
+        assert_eq!(resets.redeem(&second).await.unwrap(), "a@example.com");
+        assert_eq!(resets.redeem(&other).await.unwrap(), "b@example.com");
+        assert_eq!(resets.link("abc").unwrap(), "https://example.com/reset?t=abc");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
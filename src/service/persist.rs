@@ -0,0 +1,209 @@
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How many previous versions of a file are kept next to it as `<file>.bak.<n>`,
+/// `.bak.1` being the most recent.
+pub const BACKUP_COUNT: usize = 5;
+
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".bak.{}", n));
+    PathBuf::from(name)
+}
+
+fn sync_dir(path: &Path) -> Result<()> {
+    // Directories can only be fsynced on unix; elsewhere the rename has to do.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Replaces the file at `path` with `contents` so that a crash at any point leaves
+/// either the old or the new contents, never a mix. The previous contents are kept
+/// as the newest backup.
+pub fn write_atomic(path: impl AsRef<Path>, contents: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp-{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+
+    let written = (|| -> Result<()> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    if path.exists() {
+        for n in (1..BACKUP_COUNT).rev() {
+            let from = backup_path(path, n);
+            if from.exists() {
+                fs::rename(&from, backup_path(path, n + 1))?;
+            }
+        }
+        // Copy rather than rename so `path` never goes missing.
+        fs::copy(path, backup_path(path, 1))?;
+    }
+
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+pub fn has_backups(path: impl AsRef<Path>) -> bool {
+    (1..=BACKUP_COUNT).any(|n| backup_path(path.as_ref(), n).exists())
+}
+
+/// Loads a JSON file written by [`write_atomic`].
+///
+/// If the file is missing or does not parse, the newest backup that does parse is
+/// restored in its place and a warning says which one. The broken file is kept as
+/// `<file>.corrupt-<timestamp>`. Returns `None` when there is neither a file nor a
+/// backup; fails only when nothing readable is left.
+pub fn load_json<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Option<T>> {
+    let path = path.as_ref();
+
+    let problem = match fs::read_to_string(path) {
+        Ok(contents) => match serde_json::from_str(&contents) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => e.to_string(),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if !has_backups(path) {
+                return Ok(None);
+            }
+            "file is missing".to_string()
+        }
+        Err(e) => e.to_string(),
+    };
+
+    for n in 1..=BACKUP_COUNT {
+        let backup = backup_path(path, n);
+        let Ok(contents) = fs::read_to_string(&backup) else {
+            continue;
+        };
+        let Ok(value) = serde_json::from_str(&contents) else {
+            tracing::warn!("backup {} is not readable either, skipping it", backup.display());
+            continue;
+        };
+
+        if path.exists() {
+            let mut corrupt = path.as_os_str().to_owned();
+            corrupt.push(format!(".corrupt-{}", Utc::now().format("%Y%m%d%H%M%S")));
+            fs::rename(path, PathBuf::from(&corrupt))?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".restore");
+        fs::write(PathBuf::from(&tmp), &contents)?;
+        File::open(PathBuf::from(&tmp))?.sync_all()?;
+        fs::rename(PathBuf::from(&tmp), path)?;
+        sync_dir(path)?;
+
+        tracing::warn!(
+            "{} could not be loaded ({}); restored it from backup {}. Changes made after that backup are lost.",
+            path.display(),
+            problem,
+            backup.display()
+        );
+        return Ok(Some(value));
+    }
+
+    Err(AppError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "{} could not be loaded ({}) and no valid backup was found",
+            path.display(),
+            problem
+        ),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("persist-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn previous_versions_rotate_through_the_backups() {
+        let dir = scratch_dir();
+        let path = dir.join("values.json");
+        for n in 0..=BACKUP_COUNT + 1 {
+            write_atomic(&path, n.to_string().as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path), (BACKUP_COUNT + 1).to_string());
+        for n in 1..=BACKUP_COUNT {
+            assert_eq!(read(&backup_path(&path, n)), (BACKUP_COUNT + 1 - n).to_string());
+        }
+        assert!(!backup_path(&path, BACKUP_COUNT + 1).exists());
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), BACKUP_COUNT + 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_file_is_restored_from_the_newest_valid_backup() {
+        let dir = scratch_dir();
+        let path = dir.join("values.json");
+        write_atomic(&path, b"[1]").unwrap();
+        write_atomic(&path, b"[1, 2]").unwrap();
+        write_atomic(&path, b"[1, 2,").unwrap();
+        write_atomic(&path, b"{broken").unwrap();
+
+        // `.bak.1` is `[1, 2,`, which does not parse either.
+        let loaded: Vec<u32> = load_json(&path).unwrap().unwrap();
+        assert_eq!(loaded, [1, 2]);
+        assert_eq!(read(&path), "[1, 2]");
+        let kept = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().starts_with("values.json.corrupt-"))
+            .unwrap();
+        assert_eq!(read(&kept.path()), "{broken");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_comes_back_from_its_backups() {
+        let dir = scratch_dir();
+        let path = dir.join("values.json");
+        assert_eq!(load_json::<Vec<u32>>(&path).unwrap(), None);
+
+        write_atomic(&path, b"[1]").unwrap();
+        write_atomic(&path, b"[2]").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load_json::<Vec<u32>>(&path).unwrap(), Some(vec![1]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_readable_is_an_error() {
+        let dir = scratch_dir();
+        let path = dir.join("values.json");
+        write_atomic(&path, b"not json").unwrap();
+        write_atomic(&path, b"still not json").unwrap();
+        assert!(load_json::<Vec<u32>>(&path).is_err());
+        assert_eq!(read(&path), "still not json");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// The shortest minimum length an admin may set.
+const MIN_PASSWORD_LENGTH_FLOOR: usize = 8;
+/// bcrypt ignores everything past this many bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Sign-in rules an admin sets for everyone, in `<data_dir>/auth_policy.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthPolicy {
+    /// Users without an authenticator have to set one up at their next login.
+    #[serde(default)]
+    pub require_totp: bool,
+    /// In characters.
+    #[serde(default = "default_min_password_length")]
+    pub min_password_length: usize,
+    /// Refuse passwords on the bundled list of common and breached ones.
+    #[serde(default = "default_true")]
+    pub reject_common_passwords: bool,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        Self {
+            require_totp: false,
+            min_password_length: default_min_password_length(),
+            reject_common_passwords: true,
+        }
+    }
+}
+
+fn default_min_password_length() -> usize {
+    12
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl AuthPolicy {
+    /// Whether `password` may be set as the password of `email`.
+    pub fn check_password(&self, email: &str, password: &str) -> Result<()> {
+        if password.chars().count() < self.min_password_length {
+            return Err(AppError::User(format!(
+                "Password must be at least {} characters long",
+                self.min_password_length
+            )));
+        }
+        if password.len() > MAX_PASSWORD_BYTES {
+            return Err(AppError::User(format!("Password must be at most {} bytes long", MAX_PASSWORD_BYTES)));
+        }
+        if password.eq_ignore_ascii_case(email) {
+            return Err(AppError::User("Password must not be the email".to_string()));
+        }
+        if self.reject_common_passwords && common_passwords().contains(password.to_lowercase().as_str()) {
+            return Err(AppError::User("Password is too common, it appears in lists of breached passwords".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn common_passwords() -> &'static HashSet<&'static str> {
+    static LIST: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    LIST.get_or_init(|| {
+        include_str!("common_passwords.txt")
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    })
+}
+
+#[derive(Debug)]
+pub struct PolicyStore {
+    policy: FsStruct<AuthPolicy>,
+}
+
+impl PolicyStore {
+    pub async fn open(data_dir: &str) -> Result<Self> {
+        Ok(Self {
+            policy: FsStruct::open(format!("{}/auth_policy.json", data_dir)).await?,
+        })
+    }
+
+    pub async fn get(&self) -> AuthPolicy {
+        self.policy.snapshot().await
+    }
+
+    pub async fn set(&self, policy: AuthPolicy) -> Result<AuthPolicy> {
+        if policy.min_password_length < MIN_PASSWORD_LENGTH_FLOOR {
+            return Err(AppError::Service(format!(
+                "min_password_length cannot be below {}",
+                MIN_PASSWORD_LENGTH_FLOOR
+            )));
+        }
+        self.policy
+            .transaction(|current| {
+                *current = policy.clone();
+                Ok(())
+            })
+            .await?;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_is_counted_in_characters() {
+        let policy = AuthPolicy::default();
+        assert!(policy.check_password("a@example.com", "short-pass1").is_err());
+        assert!(policy.check_password("a@example.com", "ümläüte-pass").is_ok());
+    }
+
+    #[test]
+    fn passwords_bcrypt_would_cut_short_are_refused() {
+        let policy = AuthPolicy::default();
+        assert!(policy.check_password("a@example.com", &"x".repeat(MAX_PASSWORD_BYTES)).is_ok());
+        assert!(policy.check_password("a@example.com", &"x".repeat(MAX_PASSWORD_BYTES + 1)).is_err());
+    }
+
+    #[test]
+    fn the_email_is_not_a_password() {
+        let policy = AuthPolicy::default();
+        assert!(policy.check_password("someone@example.com", "SOMEONE@example.com").is_err());
+    }
+
+    #[test]
+    fn common_passwords_are_refused_unless_allowed() {
+        let common = common_passwords()
+            .iter()
+            .find(|p| p.chars().count() >= 12 && p.len() <= MAX_PASSWORD_BYTES)
+            .expect("the list has a long enough entry");
+        let mut policy = AuthPolicy::default();
+        assert!(policy.check_password("a@example.com", common).is_err());
+        assert!(policy.check_password("a@example.com", &common.to_uppercase()).is_err());
+        policy.reject_common_passwords = false;
+        assert!(policy.check_password("a@example.com", common).is_ok());
+    }
+}
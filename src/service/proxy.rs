@@ -0,0 +1,600 @@
+use bollard::models::HealthStatusEnum;
+use bollard::network::{CreateNetworkOptions, InspectNetworkOptions};
+use bollard::Docker;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::uri::Authority;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+use crate::error::AppError;
+use crate::service::events::{EventBus, EventKind};
+use crate::service::labels;
+use crate::service::manager::ServiceManager;
+use crate::service::models::{CreateServiceRequest, ProxyRoute, Service};
+
+type Result<T> = std::result::Result<T, AppError>;
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+pub type HttpClient = Client<HttpConnector, ProxyBody>;
+
+/// The Docker network that containers of services with `domains` are attached to, and
+/// through which the proxy reaches them.
+pub const NETWORK: &str = "longshoreman";
+
+/// The routes are rebuilt at least this often, for changes no event reports.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Headers that describe one connection and are not passed on to the next.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// `proxy` in the config: the HTTP reverse proxy in front of the services.
+///
+/// Longshoreman has to be able to reach the [`NETWORK`] network: run it on the host, or
+/// connect its own container to that network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// For routes without a timeout of their own.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+/// Refuses `request` if its domains or routes are malformed, or if a service other than
+/// `replacing` already serves one of its domains under the same path prefix.
+pub fn check_routes(services: &[Service], replacing: Option<&str>, request: &CreateServiceRequest) -> Result<()> {
+    let domains = request.domains.as_deref().unwrap_or_default();
+    if domains.is_empty() {
+        if request.routes.as_ref().is_some_and(|r| !r.is_empty()) {
+            return Err(AppError::Service(format!("Service {} has routes but no domains", request.name)));
+        }
+        return Ok(());
+    }
+    for domain in domains {
+        check_domain(domain)?;
+    }
+
+    let prefixes: Vec<String> = routes_of(request).iter().map(|r| normalize_prefix(&r.path_prefix)).collect();
+    for (i, prefix) in prefixes.iter().enumerate() {
+        if !prefix.starts_with('/') {
+            return Err(AppError::Service(format!("Route prefix {} does not start with /", prefix)));
+        }
+        if prefixes[..i].contains(prefix) {
+            return Err(AppError::Service(format!("Service {} routes {} twice", request.name, prefix)));
+        }
+    }
+
+    for other in services.iter().filter(|s| Some(s.id.as_str()) != replacing) {
+        let spec = other.spec();
+        let other_domains = spec.domains.as_deref().unwrap_or_default();
+        let other_prefixes: Vec<String> = routes_of(&spec).iter().map(|r| normalize_prefix(&r.path_prefix)).collect();
+        for domain in domains.iter().filter(|d| other_domains.iter().any(|o| o.eq_ignore_ascii_case(d))) {
+            if let Some(prefix) = prefixes.iter().find(|p| other_prefixes.contains(p)) {
+                return Err(AppError::Service(format!(
+                    "{}{} is already routed to service {}",
+                    domain, prefix, other.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_domain(domain: &str) -> Result<()> {
+    let name = domain.strip_prefix("*.").unwrap_or(domain);
+    let valid = !name.is_empty()
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if !valid {
+        return Err(AppError::Service(format!("Invalid domain {}", domain)));
+    }
+    Ok(())
+}
+
+/// The service's routes, or the single catch-all one when it lists none.
+fn routes_of(spec: &CreateServiceRequest) -> Vec<ProxyRoute> {
+    match spec.routes.as_deref() {
+        Some(routes) if !routes.is_empty() => routes.to_vec(),
+        _ => vec![ProxyRoute::default()],
+    }
+}
+
+/// `/api/` and `/api` are the same prefix.
+fn normalize_prefix(prefix: &str) -> String {
+    match prefix.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Creates the proxy network unless it exists.
+pub async fn ensure_network(docker: &Docker) -> Result<()> {
+    if docker.inspect_network(NETWORK, None::<InspectNetworkOptions<String>>).await.is_ok() {
+        return Ok(());
+    }
+    let created = docker
+        .create_network(CreateNetworkOptions {
+            name: NETWORK,
+            driver: "bridge",
+            labels: HashMap::from([(labels::MANAGED_BY, labels::MANAGER)]),
+            ..Default::default()
+        })
+        .await;
+    match created {
+        Ok(_) => Ok(()),
+        // Another service may have created it meanwhile.
+        Err(_) if docker.inspect_network(NETWORK, None::<InspectNetworkOptions<String>>).await.is_ok() => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Where requests for one domain and path prefix go.
+struct Route {
+    service: String,
+    /// The host, or `.example.com` for `*.example.com`.
+    domain: String,
+    wildcard: bool,
+    path_prefix: String,
+    strip_prefix: bool,
+    timeout: Duration,
+    /// The ready replicas, taken in turn.
+    backends: Vec<SocketAddr>,
+    next: AtomicUsize,
+}
+
+impl Route {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let host_matches = match self.wildcard {
+            true => host.ends_with(&self.domain),
+            false => host == self.domain,
+        };
+        host_matches && under(path, &self.path_prefix)
+    }
+
+    fn pick(&self) -> Option<SocketAddr> {
+        if self.backends.is_empty() {
+            return None;
+        }
+        Some(self.backends[self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len()])
+    }
+}
+
+/// Routes ordered so that the first match is the most specific one: exact domains
+/// before wildcards, longer path prefixes before shorter ones.
+#[derive(Default)]
+struct Table {
+    routes: Vec<Route>,
+}
+
+impl Table {
+    fn find(&self, host: &str, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(host, path))
+    }
+}
+
+/// The reverse proxy: routes requests by `Host` and path prefix to the replicas of the
+/// services with `domains`, round robin, passing WebSocket upgrades through.
+pub struct ProxyServer {
+    config: ProxyConfig,
+    services: Arc<ServiceManager>,
+    docker: Docker,
+    events: Arc<EventBus>,
+    client: HttpClient,
+    table: RwLock<Arc<Table>>,
+    /// Signalled when a replica could not be reached, so the routes are rebuilt
+    /// without waiting for the next refresh.
+    stale: Notify,
+}
+
+impl ProxyServer {
+    pub fn new(config: ProxyConfig, services: Arc<ServiceManager>, docker: Docker, events: Arc<EventBus>) -> Self {
+        Self {
+            config,
+            services,
+            docker,
+            events,
+            client: http_client(),
+            table: RwLock::new(Arc::new(Table::default())),
+            stale: Notify::new(),
+        }
+    }
+
+    /// Listens on the configured address for as long as the server runs, keeping the
+    /// routes in step with the services.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.listen).await?;
+        tracing::info!("reverse proxy listening on {}", self.config.listen);
+        tokio::spawn(self.clone().watch());
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("proxy failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(self.clone().serve(stream, peer));
+        }
+    }
+
+    async fn serve<I>(self: Arc<Self>, io: I, peer: SocketAddr)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |request| {
+            let proxy = self.clone();
+            async move { Ok::<_, Infallible>(proxy.forward(request, peer).await) }
+        });
+        let served = http1::Builder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .with_upgrades()
+            .await;
+        if let Err(e) = served {
+            tracing::debug!("proxy connection from {} ended: {}", peer, e);
+        }
+    }
+
+    /// Rebuilds the routes now, then again whenever a service or one of its containers
+    /// changes, a replica turns out to be unreachable, or [`REFRESH_INTERVAL`] passes.
+    async fn watch(self: Arc<Self>) {
+        let mut live = self.events.subscribe(None).live;
+        loop {
+            match self.build().await {
+                Ok(table) => *self.table.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(table),
+                Err(e) => tracing::warn!("could not rebuild the proxy routes: {}", e),
+            }
+            loop {
+                tokio::select! {
+                    received = live.recv() => match received {
+                        Ok(event) if matches!(event.kind, EventKind::ImagePulled { .. }) => continue,
+                        Err(RecvError::Closed) => return,
+                        _ => break,
+                    },
+                    _ = self.stale.notified() => break,
+                    _ = tokio::time::sleep(REFRESH_INTERVAL) => break,
+                }
+            }
+        }
+    }
+
+    async fn build(&self) -> Result<Table> {
+        let mut routes = Vec::new();
+        for service in self.services.list_services().await? {
+            let spec = service.spec();
+            let domains = spec.domains.as_deref().unwrap_or_default();
+            if domains.is_empty() {
+                continue;
+            }
+            let addresses = self.ready_addresses(&service).await;
+            let default_port = spec.ports.as_deref().unwrap_or_default().first().map_or(80, |p| p.container_port);
+
+            for route in routes_of(&spec) {
+                let port = route.port.unwrap_or(default_port);
+                let backends: Vec<SocketAddr> = addresses.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                for domain in domains {
+                    let domain = domain.to_ascii_lowercase();
+                    let (domain, wildcard) = match domain.strip_prefix('*') {
+                        Some(suffix) => (suffix.to_string(), true),
+                        None => (domain, false),
+                    };
+                    routes.push(Route {
+                        service: service.name.clone(),
+                        domain,
+                        wildcard,
+                        path_prefix: normalize_prefix(&route.path_prefix),
+                        strip_prefix: route.strip_prefix,
+                        timeout: Duration::from_secs(route.timeout_secs.unwrap_or(self.config.timeout_secs)),
+                        backends: backends.clone(),
+                        next: AtomicUsize::new(0),
+                    });
+                }
+            }
+        }
+        routes.sort_by_key(|r| (r.wildcard, std::cmp::Reverse(r.path_prefix.len())));
+        Ok(Table { routes })
+    }
+
+    /// The proxy network addresses of the replicas that are running and, if they have a
+    /// healthcheck, healthy.
+    async fn ready_addresses(&self, service: &Service) -> Vec<IpAddr> {
+        let mut addresses = Vec::new();
+        for replica in &service.containers {
+            let Ok(inspect) = self.docker.inspect_container(&replica.container_id, None).await else {
+                continue;
+            };
+            let state = inspect.state.unwrap_or_default();
+            let healthy = !matches!(
+                state.health.and_then(|h| h.status),
+                Some(HealthStatusEnum::STARTING | HealthStatusEnum::UNHEALTHY)
+            );
+            if !state.running.unwrap_or(false) || !healthy {
+                continue;
+            }
+            let address = inspect
+                .network_settings
+                .and_then(|n| n.networks)
+                .and_then(|mut networks| networks.remove(NETWORK))
+                .and_then(|endpoint| endpoint.ip_address)
+                .and_then(|ip| ip.parse::<IpAddr>().ok());
+            addresses.extend(address);
+        }
+        addresses
+    }
+
+    fn table(&self) -> Arc<Table> {
+        self.table.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    async fn forward(&self, request: Request<Incoming>, peer: SocketAddr) -> Response<ProxyBody> {
+        let Some(host) = host_of(&request) else {
+            return text_response(StatusCode::BAD_REQUEST, "Missing Host header".to_string());
+        };
+        let table = self.table();
+        let Some(route) = table.find(&host, request.uri().path()) else {
+            return text_response(StatusCode::NOT_FOUND, format!("No service is routed for {}{}", host, request.uri().path()));
+        };
+        let Some(backend) = route.pick() else {
+            return text_response(StatusCode::SERVICE_UNAVAILABLE, format!("Service {} has no ready replica", route.service));
+        };
+
+        let path = match route.strip_prefix {
+            true => strip(request.uri().path(), &route.path_prefix),
+            false => request.uri().path().to_string(),
+        };
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let uri = match format!("http://{}{}", backend, path_and_query).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("Invalid request path: {}", e)),
+        };
+
+        let forwarded = Forwarded {
+            client: peer.ip(),
+            host: &host,
+            proto: "http",
+        };
+        match tokio::time::timeout(route.timeout, relay(&self.client, request, uri, &forwarded)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                if e.is_connect() {
+                    self.stale.notify_one();
+                }
+                text_response(StatusCode::BAD_GATEWAY, format!("Service {} could not be reached: {}", route.service, e))
+            }
+            Err(_) => text_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("Service {} did not answer within {} seconds", route.service, route.timeout.as_secs()),
+            ),
+        }
+    }
+}
+
+/// A client for passing requests on over plain HTTP.
+pub fn http_client() -> HttpClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(CONNECT_TIMEOUT));
+    connector.set_nodelay(true);
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+/// Where a relayed request came from, for the `X-Forwarded-*` headers.
+pub struct Forwarded<'a> {
+    /// Appended to the request's own `X-Forwarded-For`, if it has one.
+    pub client: IpAddr,
+    pub host: &'a str,
+    pub proto: &'static str,
+}
+
+/// Sends `request` on to `uri`. Headers that only concern one hop are dropped, the
+/// `X-Forwarded-*` ones are set, and an accepted upgrade, such as to a WebSocket, is
+/// tunnelled through. Resolves once the response head has arrived.
+pub async fn relay(
+    client: &HttpClient,
+    mut request: Request<Incoming>,
+    uri: Uri,
+    forwarded: &Forwarded<'_>,
+) -> std::result::Result<Response<ProxyBody>, hyper_util::client::legacy::Error> {
+    *request.uri_mut() = uri;
+    let upgrade = upgrade_of(request.headers());
+    let headers = request.headers_mut();
+    remove_hop_by_hop(headers);
+    if let Some(protocol) = &upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, protocol.clone());
+    }
+    let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(earlier) => format!("{}, {}", earlier, forwarded.client),
+        None => forwarded.client.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    if let Ok(value) = HeaderValue::from_str(forwarded.host) {
+        headers.insert("x-forwarded-host", value.clone());
+        // The upstream sees the name it was asked for, not its address.
+        headers.entry(header::HOST).or_insert(value);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(forwarded.proto));
+
+    let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut request));
+    let mut response = client.request(request.map(|body| body.boxed())).await?;
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        if let Some(client_upgrade) = client_upgrade {
+            tokio::spawn(tunnel(client_upgrade, hyper::upgrade::on(&mut response)));
+        }
+    } else {
+        remove_hop_by_hop(response.headers_mut());
+    }
+    Ok(response.map(|body| body.boxed()))
+}
+
+/// The requested host, lowercased and without a port.
+pub fn host_of<B>(request: &Request<B>) -> Option<String> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| request.uri().authority().map(Authority::as_str))?;
+    let authority: Authority = host.parse().ok()?;
+    Some(authority.host().trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Whether `path` is `prefix` or below it; `/api` covers `/api/users` but not `/apis`.
+fn under(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn strip(path: &str, prefix: &str) -> String {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// The protocol a request asks to switch to, such as `websocket`.
+fn upgrade_of(headers: &HeaderMap) -> Option<HeaderValue> {
+    let asks = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    asks.then(|| headers.get(header::UPGRADE).cloned()).flatten()
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in listed.iter().map(String::as_str).chain(HOP_BY_HOP.iter().copied()) {
+        headers.remove(name);
+    }
+}
+
+/// Copies bytes both ways between an upgraded client connection and the replica's.
+async fn tunnel(client: OnUpgrade, backend: OnUpgrade) {
+    match tokio::try_join!(client, backend) {
+        Ok((client, backend)) => {
+            let (mut client, mut backend) = (TokioIo::new(client), TokioIo::new(backend));
+            if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut backend).await {
+                tracing::debug!("proxied upgraded connection ended: {}", e);
+            }
+        }
+        Err(e) => tracing::debug!("proxied connection upgrade failed: {}", e),
+    }
+}
+
+pub fn text_response(status: StatusCode, message: String) -> Response<ProxyBody> {
+    let body = Full::new(Bytes::from(message)).map_err(|never| match never {}).boxed();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn spec(value: serde_json::Value) -> CreateServiceRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn service(id: &str, value: serde_json::Value) -> Service {
+        let mut service = json!({"id": id, "container_id": "c", "containers": [], "revision": 1, "status": "running"});
+        service.as_object_mut().unwrap().extend(value.as_object().cloned().unwrap());
+        serde_json::from_value(service).unwrap()
+    }
+
+    #[test]
+    fn paths_are_under_whole_segments_of_a_prefix() {
+        assert!(under("/api", "/api"));
+        assert!(under("/api/users", "/api/"));
+        assert!(under("/anything", "/"));
+        assert!(!under("/apis", "/api"));
+        assert!(!under("/", "/api"));
+    }
+
+    #[test]
+    fn stripping_leaves_an_absolute_path() {
+        assert_eq!(strip("/api/users", "/api"), "/users");
+        assert_eq!(strip("/api/users", "/api/"), "/users");
+        assert_eq!(strip("/api", "/api"), "/");
+        assert_eq!(strip("/users", "/"), "/users");
+    }
+
+    #[test]
+    fn malformed_routes_are_refused() {
+        let check = |value| check_routes(&[], None, &spec(value));
+        assert!(check(json!({"name": "a", "image": "x"})).is_ok());
+        assert!(check(json!({"name": "a", "image": "x", "domains": ["*.example.com"], "routes": [{"path_prefix": "/api"}]})).is_ok());
+        assert!(check(json!({"name": "a", "image": "x", "routes": [{"path_prefix": "/api"}]})).is_err());
+        assert!(check(json!({"name": "a", "image": "x", "domains": ["bad domain"]})).is_err());
+        assert!(check(json!({"name": "a", "image": "x", "domains": ["a..b"]})).is_err());
+        assert!(check(json!({"name": "a", "image": "x", "domains": ["a.com"], "routes": [{"path_prefix": "api"}]})).is_err());
+        assert!(check(json!({"name": "a", "image": "x", "domains": ["a.com"], "routes": [{"path_prefix": "/api"}, {"path_prefix": "/api/"}]})).is_err());
+    }
+
+    #[test]
+    fn a_domain_and_prefix_belong_to_one_service() {
+        let existing = [service("web-id", json!({"name": "web", "image": "x", "domains": ["Example.com"]}))];
+        let root = spec(json!({"name": "other", "image": "x", "domains": ["example.com"]}));
+        assert!(check_routes(&existing, None, &root).is_err());
+        assert!(check_routes(&existing, Some("web-id"), &root).is_ok());
+
+        let api = spec(json!({"name": "api", "image": "x", "domains": ["example.com"], "routes": [{"path_prefix": "/api"}]}));
+        assert!(check_routes(&existing, None, &api).is_ok());
+        let elsewhere = spec(json!({"name": "other", "image": "x", "domains": ["example.org"]}));
+        assert!(check_routes(&existing, None, &elsewhere).is_ok());
+    }
+}
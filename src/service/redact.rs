@@ -0,0 +1,222 @@
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::service::apply::Plan;
+use crate::service::jobs::Job;
+use crate::service::labels::RecoveryReport;
+use crate::service::models::{CreateServiceRequest, Service};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Replaces the value of a masked env entry, so `API_KEY=s3cret` reads `API_KEY=********`.
+pub const MASK: &str = "********";
+
+/// Used when the config does not list its own patterns.
+const DEFAULT_PATTERNS: &[&str] = &[
+    "*PASSWORD*",
+    "*PASSWD*",
+    "*SECRET*",
+    "*TOKEN*",
+    "*_KEY",
+    "*APIKEY*",
+    "*CREDENTIAL*",
+    "*PRIVATE*",
+];
+
+/// Masks env values in everything the API returns.
+///
+/// An entry is masked when its name matches one of the patterns (`*` matches any run of
+/// characters, case is ignored) or the service lists it in `sensitive_env`. Values that
+/// are only `${secret:...}` references are shown as they are.
+#[derive(Debug, Clone)]
+pub struct EnvRedactor {
+    patterns: Vec<String>,
+}
+
+impl EnvRedactor {
+    pub fn new(patterns: Option<Vec<String>>) -> Self {
+        let patterns = patterns.unwrap_or_else(|| DEFAULT_PATTERNS.iter().map(|p| p.to_string()).collect());
+        Self {
+            patterns: patterns.into_iter().map(|p| p.to_ascii_uppercase()).collect(),
+        }
+    }
+
+    fn is_sensitive(&self, name: &str, marked: &[String]) -> bool {
+        let name = name.to_ascii_uppercase();
+        marked.iter().any(|m| m.eq_ignore_ascii_case(&name)) || self.patterns.iter().any(|p| glob_match(p, &name))
+    }
+
+    fn mask_entry(&self, entry: &mut String, marked: &[String]) {
+        let Some((name, value)) = entry.split_once('=') else {
+            return;
+        };
+        let reference_only = value.starts_with("${secret:") && value.ends_with('}') && value.matches('}').count() == 1;
+        if !reference_only && self.is_sensitive(name, marked) {
+            *entry = format!("{}={}", name, MASK);
+        }
+    }
+
+    fn mask_env(&self, env: &mut Option<Vec<String>>, marked: Option<&[String]>) {
+        let marked = marked.unwrap_or_default();
+        for entry in env.iter_mut().flatten() {
+            self.mask_entry(entry, marked);
+        }
+    }
+
+    pub fn service(&self, mut service: Service) -> Service {
+        self.mask_env(&mut service.env, service.sensitive_env.as_deref());
+        service
+    }
+
+    pub fn services(&self, services: Vec<Service>) -> Vec<Service> {
+        services.into_iter().map(|s| self.service(s)).collect()
+    }
+
+    pub fn job(&self, mut job: Job) -> Job {
+        self.mask_env(&mut job.spec.env, job.spec.sensitive_env.as_deref());
+        job
+    }
+
+    pub fn report(&self, mut report: RecoveryReport) -> RecoveryReport {
+        report.services = self.services(report.services);
+        report
+    }
+
+    /// Masks both sides of `env` diffs. The names marked on either side count.
+    pub fn plan(&self, mut plan: Plan) -> Plan {
+        for step in &mut plan.steps {
+            let mut marked: Vec<String> = step
+                .desired
+                .as_ref()
+                .and_then(|d| d.sensitive_env.clone())
+                .unwrap_or_default();
+            if let Some(diff) = step.diffs.iter().find(|d| d.field == "sensitive_env") {
+                marked.extend(strings(&diff.current));
+            }
+            for diff in step.diffs.iter_mut().filter(|d| d.field == "env") {
+                for side in [&mut diff.current, &mut diff.desired] {
+                    if let Value::Array(entries) = side {
+                        for entry in entries {
+                            if let Value::String(s) = entry {
+                                self.mask_entry(s, &marked);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        plan
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any (possibly empty) run of
+/// characters. Both are expected in the same case.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*` at all: an exact match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Puts the real values back into env entries a client sent back masked, taken from
+/// `current` (the service as it is now). Lets a spec read from the API be edited and
+/// submitted again without knowing the secrets in it.
+pub fn unmask(request: &mut CreateServiceRequest, current: Option<&CreateServiceRequest>) -> Result<()> {
+    let current_env = current.and_then(|c| c.env.as_deref());
+    unmask_env(&format!("service {}", request.name), &mut request.env, current_env)
+}
+
+/// [`unmask`] for any env list; `owner` names what it belongs to in errors.
+pub fn unmask_env(owner: &str, env: &mut Option<Vec<String>>, current: Option<&[String]>) -> Result<()> {
+    let current_env = current.unwrap_or_default();
+    for entry in env.iter_mut().flatten() {
+        let Some((name, value)) = entry.split_once('=') else {
+            continue;
+        };
+        if value != MASK {
+            continue;
+        }
+        let original = current_env
+            .iter()
+            .find(|e| e.split_once('=').is_some_and(|(n, _)| n == name))
+            .ok_or_else(|| {
+                AppError::Service(format!("Env {} of {} is masked but has no current value to keep", name, owner))
+            })?;
+        *entry = original.clone();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_any_run_of_characters() {
+        assert!(glob_match("*PASSWORD*", "DB_PASSWORD_FILE"));
+        assert!(glob_match("*PASSWORD*", "PASSWORD"));
+        assert!(glob_match("*_KEY", "API_KEY"));
+        assert!(!glob_match("*_KEY", "KEY_ID"));
+        assert!(glob_match("A*B*C", "AXXBYYC"));
+        assert!(!glob_match("A*A", "A"));
+        assert!(glob_match("HOST", "HOST"));
+        assert!(!glob_match("HOST", "HOSTNAME"));
+    }
+
+    #[test]
+    fn sensitive_values_are_masked() {
+        let redactor = EnvRedactor::new(None);
+        let mut env = Some(vec![
+            "db_password=hunter2".to_string(),
+            "API_KEY=${secret:api}".to_string(),
+            "PORT=80".to_string(),
+            "CUSTOM=x".to_string(),
+        ]);
+        redactor.mask_env(&mut env, Some(&["custom".to_string()]));
+        assert_eq!(
+            env.unwrap(),
+            [
+                format!("db_password={}", MASK),
+                "API_KEY=${secret:api}".to_string(),
+                "PORT=80".to_string(),
+                format!("CUSTOM={}", MASK),
+            ]
+        );
+
+        let mut own = Some(vec!["PASSWORD=x".to_string(), "MINE=y".to_string()]);
+        EnvRedactor::new(Some(vec!["mine".to_string()])).mask_env(&mut own, None);
+        assert_eq!(own.unwrap(), ["PASSWORD=x".to_string(), format!("MINE={}", MASK)]);
+    }
+
+    #[test]
+    fn masked_values_are_restored_from_the_current_env() {
+        let current = vec!["PASSWORD=hunter2".to_string(), "PORT=80".to_string()];
+        let mut env = Some(vec![format!("PASSWORD={}", MASK), "PORT=81".to_string()]);
+        unmask_env("service app", &mut env, Some(&current)).unwrap();
+        assert_eq!(env.unwrap(), ["PASSWORD=hunter2", "PORT=81"]);
+
+        let mut unknown = Some(vec![format!("TOKEN={}", MASK)]);
+        assert!(unmask_env("service app", &mut unknown, Some(&current)).is_err());
+        assert!(unmask_env("service app", &mut unknown, None).is_err());
+    }
+}
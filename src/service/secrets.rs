@@ -0,0 +1,368 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::models::{CreateServiceRequest, SecretMount};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Overrides `<data_dir>/master.key`; base64 of 32 bytes.
+const MASTER_KEY_ENV: &str = "LONGSHOREMAN_MASTER_KEY";
+/// Where secret files go in the container unless the mount says otherwise.
+const DEFAULT_SECRET_DIR: &str = "/run/secrets";
+
+const REFERENCE_START: &str = "${secret:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSecret {
+    name: String,
+    nonce: String,
+    ciphertext: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// What the API shows of a secret: never the value.
+#[derive(Debug, Clone, Serialize)]
+pub struct SecretInfo {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PutSecretRequest {
+    pub value: String,
+}
+
+impl From<&StoredSecret> for SecretInfo {
+    fn from(secret: &StoredSecret) -> Self {
+        Self {
+            name: secret.name.clone(),
+            created_at: secret.created_at,
+            updated_at: secret.updated_at,
+        }
+    }
+}
+
+/// Secrets encrypted with AES-256-GCM in `<data_dir>/secrets.json`.
+///
+/// Values are only decrypted when a container is created. Specs, `services.json` and
+/// API responses only ever hold references. Changing a secret takes effect when the
+/// services using it are next recreated.
+pub struct SecretStore {
+    cipher: Aes256Gcm,
+    secrets: FsStruct<Vec<StoredSecret>>,
+}
+
+impl fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretStore").field("secrets", &self.secrets).finish()
+    }
+}
+
+impl SecretStore {
+    pub async fn open(data_dir: &str) -> Result<Self> {
+        let key = master_key(data_dir)?;
+        Ok(Self {
+            cipher: Aes256Gcm::new(&key),
+            secrets: FsStruct::open(format!("{}/secrets.json", data_dir)).await?,
+        })
+    }
+
+    pub async fn list(&self) -> Vec<SecretInfo> {
+        self.secrets.snapshot().await.iter().map(SecretInfo::from).collect()
+    }
+
+    /// Creates the secret or replaces its value.
+    pub async fn put(&self, name: &str, value: &str) -> Result<SecretInfo> {
+        validate_name(name)?;
+        let (nonce, ciphertext) = self
+            .seal(name, value)
+            .map_err(|_| AppError::Storage(format!("Failed to encrypt secret {}", name)))?;
+
+        let now = Utc::now();
+        let mut secret = StoredSecret {
+            name: name.to_string(),
+            nonce,
+            ciphertext,
+            created_at: now,
+            updated_at: now,
+        };
+        self.secrets
+            .transaction(|secrets| {
+                match secrets.iter_mut().find(|s| s.name == name) {
+                    Some(existing) => {
+                        secret.created_at = existing.created_at;
+                        *existing = secret.clone();
+                    }
+                    None => secrets.push(secret.clone()),
+                }
+                Ok(SecretInfo::from(&secret))
+            })
+            .await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        self.secrets
+            .transaction(|secrets| {
+                let before = secrets.len();
+                secrets.retain(|s| s.name != name);
+                if secrets.len() == before {
+                    return Err(AppError::Service(format!("Secret {} not found", name)));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// The plaintext value of `name`.
+    pub async fn value(&self, name: &str) -> Result<String> {
+        let secrets = self.secrets.snapshot().await;
+        let secret = secrets
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| AppError::Service(format!("Secret {} not found", name)))?;
+
+        self.unseal(name, &secret.nonce, &secret.ciphertext)
+            .map_err(|_| AppError::Storage(format!("Secret {} is corrupt or the master key changed", name)))
+    }
+
+    /// Encrypts `value` with the master key, bound to `context` so it only decrypts under
+    /// the same one. Returns the nonce and ciphertext, base64.
+    pub fn seal(&self, context: &str, value: &str) -> Result<(String, String)> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: context.as_bytes() })
+            .map_err(|_| AppError::Storage(format!("Failed to encrypt {}", context)))?;
+        Ok((BASE64.encode(nonce), BASE64.encode(ciphertext)))
+    }
+
+    /// Decrypts what [`SecretStore::seal`] returned for `context`.
+    pub fn unseal(&self, context: &str, nonce: &str, ciphertext: &str) -> Result<String> {
+        let undecodable = || AppError::Storage(format!("{} is corrupt or the master key changed", context));
+        let nonce = BASE64.decode(nonce).map_err(|_| undecodable())?;
+        let ciphertext = BASE64.decode(ciphertext).map_err(|_| undecodable())?;
+        if nonce.len() != 12 {
+            return Err(undecodable());
+        }
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: context.as_bytes() })
+            .map_err(|_| undecodable())?;
+        String::from_utf8(plaintext).map_err(|_| undecodable())
+    }
+
+    /// Fails naming the first secret `spec` refers to that does not exist.
+    pub async fn check_references(&self, spec: &CreateServiceRequest) -> Result<()> {
+        let secrets = self.secrets.snapshot().await;
+        for name in references(spec) {
+            if !secrets.iter().any(|s| s.name == name) {
+                return Err(AppError::Service(format!(
+                    "Service {} refers to secret {}, which does not exist",
+                    spec.name, name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// A tar archive of the files for `mounts`, to be extracted at `/` in the container.
+    pub async fn archive(&self, mounts: &[SecretMount]) -> Result<Vec<u8>> {
+        let mut archive = tar::Builder::new(Vec::new());
+        for mount in mounts {
+            let value = self.value(&mount.secret).await?;
+            let target = mount
+                .target
+                .clone()
+                .unwrap_or_else(|| format!("{}/{}", DEFAULT_SECRET_DIR, mount.secret));
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(value.len() as u64);
+            // Readable by whatever user the container runs as, like Docker's own secrets.
+            header.set_mode(0o444);
+            header.set_mtime(Utc::now().timestamp().max(0) as u64);
+            archive.append_data(&mut header, target.trim_start_matches('/'), value.as_bytes())?;
+        }
+        Ok(archive.into_inner()?)
+    }
+
+    /// `env` with every `${secret:NAME}` replaced by the secret's value.
+    pub async fn resolve_env(&self, env: &[String]) -> Result<Vec<String>> {
+        let mut resolved = Vec::with_capacity(env.len());
+        for entry in env {
+            let mut value = String::new();
+            let mut rest = entry.as_str();
+            while let Some((before, name, after)) = split_reference(rest) {
+                value.push_str(before);
+                value.push_str(&self.value(name).await?);
+                rest = after;
+            }
+            value.push_str(rest);
+            resolved.push(value);
+        }
+        Ok(resolved)
+    }
+}
+
+/// Splits `s` around its first `${secret:NAME}` into the text before, `NAME` and the
+/// text after.
+fn split_reference(s: &str) -> Option<(&str, &str, &str)> {
+    let start = s.find(REFERENCE_START)?;
+    let name_start = start + REFERENCE_START.len();
+    let end = name_start + s[name_start..].find('}')?;
+    Some((&s[..start], &s[name_start..end], &s[end + 1..]))
+}
+
+/// The names of all secrets `spec` uses, in env references or as files.
+pub fn references(spec: &CreateServiceRequest) -> BTreeSet<String> {
+    references_in(spec.env.as_deref(), spec.secrets.as_deref())
+}
+
+/// The secrets referred to by `env` entries and `mounts`.
+pub fn references_in(env: Option<&[String]>, mounts: Option<&[SecretMount]>) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for entry in env.unwrap_or_default() {
+        let mut rest = entry.as_str();
+        while let Some((_, name, after)) = split_reference(rest) {
+            names.insert(name.to_string());
+            rest = after;
+        }
+    }
+    for mount in mounts.unwrap_or_default() {
+        names.insert(mount.secret.clone());
+    }
+    names
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 128
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(AppError::Service(format!(
+            "Invalid secret name {:?}: use letters, digits, '_', '-' and '.'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The key from the environment, else from `master.key`, which is generated (readable
+/// by the owner only) the first time. Losing it makes every secret unreadable.
+fn master_key(data_dir: &str) -> Result<Key<Aes256Gcm>> {
+    let invalid = |source: &str| AppError::Storage(format!("{} must be base64 of exactly 32 bytes", source));
+
+    if let Ok(encoded) = std::env::var(MASTER_KEY_ENV) {
+        let bytes = BASE64.decode(encoded.trim()).map_err(|_| invalid(MASTER_KEY_ENV))?;
+        if bytes.len() != 32 {
+            return Err(invalid(MASTER_KEY_ENV));
+        }
+        return Ok(*Key::<Aes256Gcm>::from_slice(&bytes));
+    }
+
+    let path = Path::new(data_dir).join("master.key");
+    if path.exists() {
+        let bytes = BASE64
+            .decode(std::fs::read_to_string(&path)?.trim())
+            .map_err(|_| invalid("master.key"))?;
+        if bytes.len() != 32 {
+            return Err(invalid("master.key"));
+        }
+        return Ok(*Key::<Aes256Gcm>::from_slice(&bytes));
+    }
+
+    let key = Aes256Gcm::generate_key(OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path)?;
+    file.write_all(BASE64.encode(key).as_bytes())?;
+    file.sync_all()?;
+    tracing::info!("generated a new master key in {}; back it up, secrets cannot be read without it", path.display());
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn sealed_values_only_open_under_their_context() {
+        let dir = scratch_dir();
+        let store = SecretStore::open(dir.to_str().unwrap()).await.unwrap();
+        let (nonce, ciphertext) = store.seal("webhook:1", "s3cret").unwrap();
+        assert_ne!(ciphertext, "s3cret");
+        assert_eq!(store.unseal("webhook:1", &nonce, &ciphertext).unwrap(), "s3cret");
+        assert!(store.unseal("webhook:2", &nonce, &ciphertext).is_err());
+        assert!(store.unseal("webhook:1", "bm90IGEgbm9uY2U=", &ciphertext).is_err());
+
+        // The generated master key is kept, so a restart can still decrypt.
+        let reopened = SecretStore::open(dir.to_str().unwrap()).await.unwrap();
+        assert_eq!(reopened.unseal("webhook:1", &nonce, &ciphertext).unwrap(), "s3cret");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn references_resolve_to_values() {
+        let dir = scratch_dir();
+        let store = SecretStore::open(dir.to_str().unwrap()).await.unwrap();
+        store.put("db-password", "hunter2").await.unwrap();
+        let env = vec![
+            "DATABASE_URL=postgres://app:${secret:db-password}@db/app".to_string(),
+            "PORT=80".to_string(),
+        ];
+        assert_eq!(
+            store.resolve_env(&env).await.unwrap(),
+            ["DATABASE_URL=postgres://app:hunter2@db/app", "PORT=80"]
+        );
+        assert!(store.resolve_env(&["KEY=${secret:missing}".to_string()]).await.is_err());
+
+        store.delete("db-password").await.unwrap();
+        assert!(store.resolve_env(&env).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn references_come_from_env_and_mounts() {
+        let env = vec!["A=${secret:one}-${secret:two}".to_string(), "B=${secret:unclosed".to_string()];
+        let mounts = vec![SecretMount {
+            secret: "three".to_string(),
+            target: None,
+        }];
+        let names: Vec<String> = references_in(Some(&env), Some(&mounts)).into_iter().collect();
+        assert_eq!(names, ["one", "three", "two"]);
+        assert!(references_in(None, None).is_empty());
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!(validate_name("db-password_v1.2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("with space").is_err());
+        assert!(validate_name("../escape").is_err());
+        assert!(validate_name(&"a".repeat(129)).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use gotcha::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::AppError;
+use crate::service::migrations::Documents;
+use crate::service::models::Service;
+use crate::service::persist;
+use crate::service::storage::{Change, Storage};
+use crate::service::user::User;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+const SERVICES: &str = "services";
+const USERS: &str = "users";
+
+/// Rows keep the full record as JSON next to its key, so adding a field to `Service`
+/// or `User` needs no schema change here.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS services (
+        key TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        key TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+/// Embedded SQLite storage in `<data_dir>/longshoreman.db`.
+///
+/// Updates only write the rows whose content or position actually changed.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub async fn open(data_dir: &str) -> Result<Self> {
+        let data_dir = data_dir.to_string();
+        tokio::task::spawn_blocking(move || Self::open_blocking(&data_dir))
+            .await
+            .map_err(|e| AppError::Storage(e.to_string()))?
+    }
+
+    fn open_blocking(data_dir: &str) -> Result<Self> {
+        let conn = Connection::open(Path::new(data_dir).join("longshoreman.db"))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        let storage = Self {
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        storage.import_json(data_dir)?;
+        Ok(storage)
+    }
+
+    /// One-shot migration from the JSON backend: the first time the database is opened,
+    /// whatever is in `services.json`/`users.json` is copied in. The files are left in
+    /// place so switching back is possible, but never imported again.
+    fn import_json(&self, data_dir: &str) -> Result<()> {
+        let mut conn = self.lock()?;
+        let imported: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'json_imported_at'", [], |row| row.get(0))
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        let services: Vec<Service> =
+            persist::load_json(format!("{}/services.json", data_dir))?.unwrap_or_default();
+        let users: Vec<User> = persist::load_json(format!("{}/users.json", data_dir))?.unwrap_or_default();
+
+        let tx = conn.transaction()?;
+        write_rows(&tx, SERVICES, &services, |s| s.id.as_str(), &HashMap::new())?;
+        write_rows(&tx, USERS, &users, |u| u.email.as_str(), &HashMap::new())?;
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES ('json_imported_at', ?1)",
+            params![chrono::Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+
+        if !services.is_empty() || !users.is_empty() {
+            tracing::info!(
+                "imported {} services and {} users from JSON files into longshoreman.db",
+                services.len(),
+                users.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| AppError::Storage("SQLite connection lock poisoned".to_string()))
+    }
+
+    fn load<T: DeserializeOwned>(&self, table: &str) -> Result<Vec<T>> {
+        let conn = self.lock()?;
+        Ok(read_rows(&conn, table)?.into_iter().map(|(_, _, value)| value).collect())
+    }
+
+    fn update<T: Serialize + DeserializeOwned>(
+        &self,
+        table: &str,
+        key: fn(&T) -> &str,
+        change: Change<'_, T>,
+    ) -> Result<()> {
+        let mut conn = self.lock()?;
+        let tx = conn.transaction()?;
+
+        let rows = read_rows::<T>(&tx, table)?;
+        let existing: HashMap<String, (i64, String)> = rows
+            .iter()
+            .map(|(position, body, value)| (key(value).to_string(), (*position, body.clone())))
+            .collect();
+        let mut values: Vec<T> = rows.into_iter().map(|(_, _, value)| value).collect();
+
+        change(&mut values)?;
+
+        write_rows(&tx, table, &values, key, &existing)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn read_rows<T: DeserializeOwned>(conn: &Connection, table: &str) -> Result<Vec<(i64, String, T)>> {
+    let mut statement = conn.prepare(&format!("SELECT position, body FROM {} ORDER BY position", table))?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+
+    let mut values = Vec::new();
+    for row in rows {
+        let (position, body) = row?;
+        let value = serde_json::from_str(&body)?;
+        values.push((position, body, value));
+    }
+    Ok(values)
+}
+
+/// Makes `table` hold exactly `values`, touching only rows that differ from `existing`.
+fn write_rows<T: Serialize>(
+    conn: &Connection,
+    table: &str,
+    values: &[T],
+    key: fn(&T) -> &str,
+    existing: &HashMap<String, (i64, String)>,
+) -> Result<()> {
+    for (position, value) in values.iter().enumerate() {
+        let position = position as i64;
+        let body = serde_json::to_string(value)?;
+        if existing.get(key(value)) == Some(&(position, body.clone())) {
+            continue;
+        }
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (key, position, body) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(key) DO UPDATE SET position = excluded.position, body = excluded.body",
+                table
+            ),
+            params![key(value), position, body],
+        )?;
+    }
+
+    for stale in existing.keys().filter(|k| !values.iter().any(|v| key(v) == k.as_str())) {
+        conn.execute(&format!("DELETE FROM {} WHERE key = ?1", table), params![stale])?;
+    }
+    Ok(())
+}
+
+/// Applies a schema migration step to the raw rows of an existing `longshoreman.db`.
+/// Both tables are rewritten in one transaction, keyed by whatever the step left in
+/// `id` and `email`.
+pub fn migrate(data_dir: &str, apply: fn(&mut Documents) -> Result<()>) -> Result<()> {
+    let path = Path::new(data_dir).join("longshoreman.db");
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+
+    let mut documents = Documents {
+        services: read_rows(&tx, SERVICES)?.into_iter().map(|(_, _, value)| value).collect(),
+        users: read_rows(&tx, USERS)?.into_iter().map(|(_, _, value)| value).collect(),
+    };
+    apply(&mut documents)?;
+
+    for (table, key, values) in [(SERVICES, "id", &documents.services), (USERS, "email", &documents.users)] {
+        tx.execute(&format!("DELETE FROM {}", table), [])?;
+        for (position, value) in values.iter().enumerate() {
+            let row_key = value.get(key).and_then(serde_json::Value::as_str).ok_or_else(|| {
+                AppError::Storage(format!("migrated {} row has no {}", table, key))
+            })?;
+            tx.execute(
+                &format!("INSERT INTO {} (key, position, body) VALUES (?1, ?2, ?3)", table),
+                params![row_key, position as i64, serde_json::to_string(value)?],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// The connection is synchronous; `block_in_place` keeps the executor free without
+// requiring the change closures to be `'static` as `spawn_blocking` would.
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn services(&self) -> Result<Vec<Service>> {
+        tokio::task::block_in_place(|| self.load(SERVICES))
+    }
+
+    async fn update_services(&self, change: Change<'_, Service>) -> Result<()> {
+        tokio::task::block_in_place(|| self.update(SERVICES, |s: &Service| s.id.as_str(), change))
+    }
+
+    async fn users(&self) -> Result<Vec<User>> {
+        tokio::task::block_in_place(|| self.load(USERS))
+    }
+
+    async fn update_users(&self, change: Change<'_, User>) -> Result<()> {
+        tokio::task::block_in_place(|| self.update(USERS, |u: &User| u.email.as_str(), change))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sqlite-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn user(email: &str, password: &str) -> User {
+        User {
+            email: email.to_string(),
+            password: password.to_string(),
+            totp: None,
+            sso: None,
+        }
+    }
+
+    fn emails(users: &[User]) -> Vec<&str> {
+        users.iter().map(|u| u.email.as_str()).collect()
+    }
+
+    fn write_users(dir: &Path, users: &[User]) {
+        persist::write_atomic(dir.join("users.json"), serde_json::to_string(users).unwrap().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn json_files_are_imported_once() {
+        let dir = scratch_dir();
+        write_users(&dir, &[user("a@example.com", "x"), user("b@example.com", "y")]);
+        let storage = SqliteStorage::open_blocking(dir.to_str().unwrap()).unwrap();
+        let users: Vec<User> = storage.load(USERS).unwrap();
+        assert_eq!(emails(&users), ["a@example.com", "b@example.com"]);
+        assert!(storage.load::<Service>(SERVICES).unwrap().is_empty());
+        drop(storage);
+
+        // The files stay, but a later change to them is not picked up again.
+        write_users(&dir, &[user("c@example.com", "z")]);
+        let reopened = SqliteStorage::open_blocking(dir.to_str().unwrap()).unwrap();
+        let users: Vec<User> = reopened.load(USERS).unwrap();
+        assert_eq!(emails(&users), ["a@example.com", "b@example.com"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn updates_round_trip_and_only_write_what_changed() {
+        let dir = scratch_dir();
+        let storage = SqliteStorage::open_blocking(dir.to_str().unwrap()).unwrap();
+        let key: fn(&User) -> &str = |u| u.email.as_str();
+        storage
+            .update(USERS, key, Box::new(|users: &mut Vec<User>| {
+                users.extend([user("a@example.com", "1"), user("b@example.com", "2"), user("c@example.com", "3")]);
+                Ok(())
+            }))
+            .unwrap();
+
+        let changes = || -> i64 {
+            storage.lock().unwrap().query_row("SELECT total_changes()", [], |row| row.get(0)).unwrap()
+        };
+        let before = changes();
+        storage
+            .update(USERS, key, Box::new(|users: &mut Vec<User>| {
+                users[2].password = "changed".to_string();
+                Ok(())
+            }))
+            .unwrap();
+        assert_eq!(changes() - before, 1);
+
+        let before = changes();
+        storage
+            .update(USERS, key, Box::new(|users: &mut Vec<User>| {
+                users.remove(0);
+                Ok(())
+            }))
+            .unwrap();
+        // The removed row, and the two that moved up a position.
+        assert_eq!(changes() - before, 3);
+
+        let users: Vec<User> = storage.load(USERS).unwrap();
+        assert_eq!(emails(&users), ["b@example.com", "c@example.com"]);
+        assert_eq!(users[1].password, "changed");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_changes_write_nothing() {
+        let dir = scratch_dir();
+        let storage = SqliteStorage::open_blocking(dir.to_str().unwrap()).unwrap();
+        let failed = storage.update(USERS, |u: &User| u.email.as_str(), Box::new(|users: &mut Vec<User>| {
+            users.push(user("a@example.com", "1"));
+            Err(AppError::Service("no".to_string()))
+        }));
+        assert!(failed.is_err());
+        assert!(storage.load::<User>(USERS).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use gotcha::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+use crate::service::models::Service;
+use crate::service::sqlite::SqliteStorage;
+use crate::service::user::User;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// A change to a stored collection, applied atomically by [`Storage`].
+pub type Change<'a, T> = Box<dyn FnOnce(&mut Vec<T>) -> Result<()> + Send + 'a>;
+
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// `services.json` and `users.json` in the data directory.
+    #[default]
+    Json,
+    /// `longshoreman.db` in the data directory.
+    Sqlite,
+}
+
+/// Where `ServiceManager` and `UserManager` keep their state.
+///
+/// Updates hand over a closure that edits the whole collection; the backend applies it
+/// to a fresh copy and commits it only if the closure succeeds, so managers can
+/// validate and mutate in one step regardless of how the data is actually stored.
+#[async_trait]
+pub trait Storage: Send + Sync + fmt::Debug {
+    async fn services(&self) -> Result<Vec<Service>>;
+    async fn update_services(&self, change: Change<'_, Service>) -> Result<()>;
+    async fn users(&self) -> Result<Vec<User>>;
+    async fn update_users(&self, change: Change<'_, User>) -> Result<()>;
+}
+
+impl dyn Storage {
+    /// [`Storage::update_services`] for closures that produce a value.
+    pub async fn service_transaction<R: Send>(
+        &self,
+        change: impl FnOnce(&mut Vec<Service>) -> Result<R> + Send,
+    ) -> Result<R> {
+        let mut output = None;
+        self.update_services(Box::new(|services| {
+            output = Some(change(services)?);
+            Ok(())
+        }))
+        .await?;
+        output.ok_or_else(|| AppError::Service("Transaction produced no result".to_string()))
+    }
+
+    /// [`Storage::update_users`] for closures that produce a value.
+    pub async fn user_transaction<R: Send>(
+        &self,
+        change: impl FnOnce(&mut Vec<User>) -> Result<R> + Send,
+    ) -> Result<R> {
+        let mut output = None;
+        self.update_users(Box::new(|users| {
+            output = Some(change(users)?);
+            Ok(())
+        }))
+        .await?;
+        output.ok_or_else(|| AppError::User("Transaction produced no result".to_string()))
+    }
+}
+
+/// Opens the configured backend for `data_dir`.
+pub async fn open(backend: StorageBackend, data_dir: &str) -> Result<Arc<dyn Storage>> {
+    Ok(match backend {
+        StorageBackend::Json => Arc::new(JsonStorage::open(data_dir).await?),
+        StorageBackend::Sqlite => Arc::new(SqliteStorage::open(data_dir).await?),
+    })
+}
+
+/// The original storage: one pretty-printed JSON file per collection.
+#[derive(Debug)]
+pub struct JsonStorage {
+    services: FsStruct<Vec<Service>>,
+    users: FsStruct<Vec<User>>,
+}
+
+impl JsonStorage {
+    pub async fn open(data_dir: &str) -> Result<Self> {
+        Ok(Self {
+            services: FsStruct::open(format!("{}/services.json", data_dir)).await?,
+            users: FsStruct::open(format!("{}/users.json", data_dir)).await?,
+        })
+    }
+
+    /// Like [`JsonStorage::open`], but starts from an empty service list instead of
+    /// failing when `services.json` cannot be read. The next write replaces the file.
+    pub async fn open_discarding_services(data_dir: &str) -> Result<Self> {
+        Ok(Self {
+            services: FsStruct::with_value(
+                format!("{}/services.json", data_dir),
+                Vec::new(),
+                crate::service::fs_struct::DEFAULT_DEBOUNCE,
+            ),
+            users: FsStruct::open(format!("{}/users.json", data_dir)).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn services(&self) -> Result<Vec<Service>> {
+        Ok(self.services.snapshot().await)
+    }
+
+    async fn update_services(&self, change: Change<'_, Service>) -> Result<()> {
+        self.services.transaction(change).await
+    }
+
+    async fn users(&self) -> Result<Vec<User>> {
+        Ok(self.users.snapshot().await)
+    }
+
+    async fn update_users(&self, change: Change<'_, User>) -> Result<()> {
+        self.users.transaction(change).await
+    }
+}
@@ -0,0 +1,151 @@
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::AppError;
+use crate::service::certificates::CertificateStore;
+use crate::service::proxy::{self, Forwarded, HttpClient, ProxyBody};
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// How long a client has to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `tls` in the config: serving the API over HTTPS.
+///
+/// Connections are decrypted here and passed on to the plain HTTP listener, which is
+/// then only allowed on a loopback `host` so tokens stay off the network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Put into the certificate generated while none is uploaded. Nothing is generated
+    /// when empty.
+    #[serde(default = "default_self_signed_names")]
+    pub self_signed_names: Vec<String>,
+    /// Certificates expiring within this many days are warned about, and the
+    /// self-signed one is renewed.
+    #[serde(default = "default_expiry_warning_days")]
+    pub expiry_warning_days: i64,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:3443".to_string()
+}
+
+fn default_self_signed_names() -> Vec<String> {
+    vec!["localhost".to_string()]
+}
+
+fn default_expiry_warning_days() -> i64 {
+    30
+}
+
+/// Terminates TLS for the API with the certificates of a [`CertificateStore`].
+pub struct TlsFront {
+    listen: String,
+    /// The plain listener, `host:port`.
+    upstream: String,
+    acceptor: TlsAcceptor,
+    client: HttpClient,
+}
+
+impl TlsFront {
+    /// Passes requests on to the API at `host:port`; an unspecified host such as
+    /// `0.0.0.0` is reached on the loopback address.
+    pub fn new(config: &TlsConfig, certificates: Arc<CertificateStore>, host: &str, port: u16) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| AppError::Service(format!("Invalid TLS settings: {}", e)))?
+            .with_no_client_auth()
+            .with_cert_resolver(certificates);
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let host = match host {
+            "0.0.0.0" | "" => "127.0.0.1",
+            "::" | "[::]" => "[::1]",
+            host => host,
+        };
+        Ok(Self {
+            listen: config.listen.clone(),
+            upstream: format!("{}:{}", host, port),
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            client: proxy::http_client(),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let listener = TcpListener::bind(&self.listen).await?;
+        tracing::info!("serving the API over TLS on {}", self.listen);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("TLS listener failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(self.clone().serve(stream, peer));
+        }
+    }
+
+    async fn serve(self: Arc<Self>, stream: TcpStream, peer: SocketAddr) {
+        let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                tracing::debug!("TLS handshake with {} timed out", peer);
+                return;
+            }
+        };
+        let service = service_fn(move |request| {
+            let front = self.clone();
+            async move { Ok::<_, Infallible>(front.forward(request, peer).await) }
+        });
+        let served = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades()
+            .await;
+        if let Err(e) = served {
+            tracing::debug!("TLS connection from {} ended: {}", peer, e);
+        }
+    }
+
+    async fn forward(&self, mut request: Request<Incoming>, peer: SocketAddr) -> Response<ProxyBody> {
+        // The API trusts hops appended by loopback, so a client-sent X-Forwarded-For
+        // naming a loopback address would hide the peer appended after it.
+        request.headers_mut().remove("x-forwarded-for");
+        let host = proxy::host_of(&request).unwrap_or_default();
+        let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let uri: Uri = match format!("http://{}{}", self.upstream, path).parse() {
+            Ok(uri) => uri,
+            Err(_) => return proxy::text_response(StatusCode::BAD_REQUEST, "Invalid request path".to_string()),
+        };
+        let forwarded = Forwarded {
+            client: peer.ip(),
+            host: &host,
+            proto: "https",
+        };
+        match proxy::relay(&self.client, request, uri, &forwarded).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("could not pass a TLS request on to the API: {}", e);
+                proxy::text_response(StatusCode::BAD_GATEWAY, "The API could not be reached".to_string())
+            }
+        }
+    }
+}
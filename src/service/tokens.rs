@@ -0,0 +1,300 @@
+use chrono::{DateTime, Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::service::fs_struct::FsStruct;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Marks API tokens, so they can be told apart from JWTs (and found by secret scanners).
+pub const TOKEN_PREFIX: &str = "lsm_";
+/// `last_used_at` is only written when it is at least this much out of date, so a busy
+/// token does not rewrite `tokens.json` on every request.
+const LAST_USED_RESOLUTION: Duration = Duration::minutes(1);
+
+/// What a token may do. Serialized as `"read"`, `"admin"`, `{"deploy": ["web"]}` or
+/// `{"secrets": ["db-password"]}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// List and inspect services.
+    Read,
+    /// Read, and create, change, start or stop the listed services (by name or id).
+    Deploy(Vec<String>),
+    /// Refer to the listed secrets in service specs. A container can read every secret
+    /// its spec refers to, so without this only admins may add references.
+    Secrets(Vec<String>),
+    /// Everything, including secrets, tokens and the audit log.
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredToken {
+    id: String,
+    name: String,
+    /// The user who created it; requests made with it act as this user.
+    owner: String,
+    scopes: Vec<Scope>,
+    /// SHA-256 of the token. The token itself is only shown when it is created.
+    token_hash: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<&StoredToken> for ApiToken {
+    fn from(token: &StoredToken) -> Self {
+        Self {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            owner: token.owner.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Never expires when not given.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once, when the token is created.
+#[derive(Debug, Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+/// Long-lived API tokens in `<data_dir>/tokens.json`.
+#[derive(Debug)]
+pub struct TokenManager {
+    tokens: FsStruct<Vec<StoredToken>>,
+}
+
+impl TokenManager {
+    pub async fn open(data_dir: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: FsStruct::open(format!("{}/tokens.json", data_dir)).await?,
+        })
+    }
+
+    pub async fn list(&self) -> Vec<ApiToken> {
+        self.tokens.snapshot().await.iter().map(ApiToken::from).collect()
+    }
+
+    pub async fn create(&self, owner: &str, request: CreateTokenRequest) -> Result<CreatedToken> {
+        if request.name.trim().is_empty() {
+            return Err(AppError::Service("A token needs a name".to_string()));
+        }
+        if request.scopes.is_empty() {
+            return Err(AppError::Service("A token needs at least one scope".to_string()));
+        }
+        if request.expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(AppError::Service("expires_at is in the past".to_string()));
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = format!("{}{}", TOKEN_PREFIX, hex::encode(bytes));
+        let stored = StoredToken {
+            id: Uuid::new_v4().to_string(),
+            name: request.name,
+            owner: owner.to_string(),
+            scopes: request.scopes,
+            token_hash: hash(&token),
+            created_at: Utc::now(),
+            expires_at: request.expires_at,
+            last_used_at: None,
+        };
+        let info = ApiToken::from(&stored);
+        self.tokens
+            .transaction(|tokens| {
+                if tokens.iter().any(|t| t.owner == stored.owner && t.name == stored.name) {
+                    return Err(AppError::Service(format!("A token named {} already exists", stored.name)));
+                }
+                tokens.push(stored);
+                Ok(())
+            })
+            .await?;
+        Ok(CreatedToken { info, token })
+    }
+
+    pub async fn revoke(&self, id: &str) -> Result<()> {
+        self.tokens
+            .transaction(|tokens| {
+                let before = tokens.len();
+                tokens.retain(|t| t.id != id);
+                if tokens.len() == before {
+                    return Err(AppError::Service("Token not found".to_string()));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// The token `token` belongs to, if it exists and has not expired. Notes the use.
+    pub async fn authenticate(&self, token: &str) -> Result<ApiToken> {
+        let token_hash = hash(token);
+        let found = self
+            .tokens
+            .snapshot()
+            .await
+            .into_iter()
+            .find(|t| t.token_hash == token_hash)
+            .ok_or_else(|| AppError::Auth("Invalid token".to_string()))?;
+        let now = Utc::now();
+        if found.expires_at.is_some_and(|at| at <= now) {
+            return Err(AppError::Auth("Token has expired".to_string()));
+        }
+
+        if found.last_used_at.is_none_or(|at| now - at >= LAST_USED_RESOLUTION) {
+            let recorded = self
+                .tokens
+                .transaction(|tokens| {
+                    if let Some(t) = tokens.iter_mut().find(|t| t.id == found.id) {
+                        t.last_used_at = Some(now);
+                    }
+                    Ok(())
+                })
+                .await;
+            // Not being able to note the use is no reason to turn the request away.
+            if let Err(e) = recorded {
+                tracing::warn!("failed to record use of token {}: {}", found.id, e);
+            }
+        }
+        Ok(ApiToken::from(&found))
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tokens-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn request(name: &str, scopes: Vec<Scope>) -> CreateTokenRequest {
+        CreateTokenRequest {
+            name: name.to_string(),
+            scopes,
+            expires_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_are_prefixed_and_only_their_hash_is_stored() {
+        let dir = scratch_dir();
+        let manager = TokenManager::open(dir.to_str().unwrap()).await.unwrap();
+        let created = manager.create("a@example.com", request("ci", vec![Scope::Read])).await.unwrap();
+        assert!(created.token.starts_with(TOKEN_PREFIX));
+        assert_eq!(created.token.len(), TOKEN_PREFIX.len() + 64);
+
+        let on_disk = std::fs::read_to_string(dir.join("tokens.json")).unwrap();
+        assert!(!on_disk.contains(&created.token[TOKEN_PREFIX.len()..]));
+        assert!(on_disk.contains(&hash(&created.token)));
+
+        let found = manager.authenticate(&created.token).await.unwrap();
+        assert_eq!((found.owner.as_str(), found.scopes), ("a@example.com", vec![Scope::Read]));
+        assert!(manager.authenticate(&format!("{}{}", TOKEN_PREFIX, "0".repeat(64))).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens_are_refused() {
+        let dir = scratch_dir();
+        let manager = TokenManager::open(dir.to_str().unwrap()).await.unwrap();
+        let mut past = request("old", vec![Scope::Read]);
+        past.expires_at = Some(Utc::now() - Duration::seconds(1));
+        assert!(manager.create("a@example.com", past).await.is_err());
+
+        let created = manager.create("a@example.com", request("ci", vec![Scope::Read])).await.unwrap();
+        manager
+            .tokens
+            .transaction(|tokens| {
+                tokens[0].expires_at = Some(Utc::now() - Duration::seconds(1));
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(manager.authenticate(&created.token).await.is_err());
+
+        let other = manager.create("a@example.com", request("deploy", vec![Scope::Read])).await.unwrap();
+        manager.revoke(&other.info.id).await.unwrap();
+        assert!(manager.authenticate(&other.token).await.is_err());
+        assert!(manager.revoke(&other.info.id).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn last_use_is_only_written_once_it_is_out_of_date() {
+        let dir = scratch_dir();
+        let manager = TokenManager::open(dir.to_str().unwrap()).await.unwrap();
+        let created = manager.create("a@example.com", request("ci", vec![Scope::Read])).await.unwrap();
+        let last_used = || async { manager.list().await[0].last_used_at };
+
+        manager.authenticate(&created.token).await.unwrap();
+        let first = last_used().await.unwrap();
+        manager.authenticate(&created.token).await.unwrap();
+        assert_eq!(last_used().await, Some(first));
+
+        let stale = first - LAST_USED_RESOLUTION;
+        manager
+            .tokens
+            .transaction(|tokens| {
+                tokens[0].last_used_at = Some(stale);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        manager.authenticate(&created.token).await.unwrap();
+        assert!(last_used().await.unwrap() > stale);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn names_are_unique_per_owner() {
+        let dir = scratch_dir();
+        let manager = TokenManager::open(dir.to_str().unwrap()).await.unwrap();
+        manager.create("a@example.com", request("ci", vec![Scope::Read])).await.unwrap();
+        assert!(manager.create("a@example.com", request("ci", vec![Scope::Admin])).await.is_err());
+        assert!(manager.create("b@example.com", request("ci", vec![Scope::Read])).await.is_ok());
+        assert!(manager.create("a@example.com", request(" ", vec![Scope::Read])).await.is_err());
+        assert!(manager.create("a@example.com", request("none", Vec::new())).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scopes_serialize_as_documented() {
+        let scopes = vec![Scope::Read, Scope::Admin, Scope::Deploy(vec!["web".to_string()])];
+        assert_eq!(serde_json::to_string(&scopes).unwrap(), r#"["read","admin",{"deploy":["web"]}]"#);
+    }
+}
@@ -0,0 +1,289 @@
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::error::AppError;
+
+type Result<T> = std::result::Result<T, AppError>;
+
+/// Shown as the account's issuer in authenticator apps.
+const ISSUER: &str = "Longshoreman";
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Codes from this many steps before or after the current one are accepted, for clocks
+/// that are a little off.
+const DRIFT: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// How long the second login step may take, and how many codes it may try.
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+const CHALLENGE_ATTEMPTS: u32 = 5;
+
+/// A user's authenticator, stored with the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpConfig {
+    /// Base32, as authenticator apps take it.
+    pub secret: String,
+    /// False until the user has proven their app works by entering a code; an
+    /// unconfirmed authenticator is not asked for at login.
+    pub confirmed: bool,
+    /// SHA-256 of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// The time step of the last accepted code. Codes of this step or earlier are
+    /// refused, so an observed code cannot be replayed.
+    #[serde(default)]
+    pub last_step: Option<u64>,
+}
+
+/// What an authenticator app needs to be set up.
+#[derive(Debug, Clone, Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    /// An `otpauth://` URI, for rendering as a QR code.
+    pub provisioning_uri: String,
+}
+
+impl TotpConfig {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 20];
+        OsRng.fill_bytes(&mut bytes);
+        let Secret::Encoded(secret) = Secret::Raw(bytes.to_vec()).to_encoded() else {
+            unreachable!("to_encoded always returns an encoded secret")
+        };
+        Self {
+            secret,
+            confirmed: false,
+            recovery_codes: Vec::new(),
+            last_step: None,
+        }
+    }
+
+    fn totp(&self, email: &str) -> Result<TOTP> {
+        let secret = Secret::Encoded(self.secret.clone())
+            .to_bytes()
+            .map_err(|e| AppError::Service(format!("Stored TOTP secret is invalid: {}", e)))?;
+        // The account name may not contain ':', which an email hardly ever does.
+        TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP, secret, Some(ISSUER.to_string()), email.replace(':', "_"))
+            .map_err(|e| AppError::Service(format!("Invalid TOTP parameters: {}", e)))
+    }
+
+    pub fn enrollment(&self, email: &str) -> Result<Enrollment> {
+        Ok(Enrollment {
+            secret: self.secret.clone(),
+            provisioning_uri: self.totp(email)?.get_url(),
+        })
+    }
+
+    /// Accepts `code` if it is valid for a step not used before, and remembers the step.
+    pub fn verify(&mut self, email: &str, code: &str) -> Result<bool> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        let totp = self.totp(email)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| AppError::Service(e.to_string()))?
+            .as_secs()
+            / STEP;
+        let step = (now.saturating_sub(DRIFT)..=now + DRIFT)
+            .filter(|step| self.last_step.is_none_or(|last| *step > last))
+            .find(|step| totp.check(&code, step * STEP));
+        if let Some(step) = step {
+            self.last_step = Some(step);
+        }
+        Ok(step.is_some())
+    }
+
+    /// Replaces the recovery codes with new ones, returned in the clear this once.
+    pub fn new_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let mut bytes = [0u8; 5];
+                OsRng.fill_bytes(&mut bytes);
+                let code = hex::encode(bytes);
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect();
+        self.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        codes
+    }
+
+    /// Accepts a recovery code once; it is gone afterwards.
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = hash_recovery_code(code);
+        let before = self.recovery_codes.len();
+        self.recovery_codes.retain(|c| *c != hash);
+        self.recovery_codes.len() < before
+    }
+}
+
+/// Case and dashes do not matter when a recovery code is typed in.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// What the second login step has to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeKind {
+    /// Enter a code from the enrolled authenticator, or a recovery code.
+    Verify,
+    /// The policy requires 2FA and the user has none yet: set it up, then confirm it.
+    Enroll,
+}
+
+#[derive(Debug)]
+struct Challenge {
+    email: String,
+    kind: ChallengeKind,
+    expires: Instant,
+    attempts: u32,
+}
+
+/// Logins that passed the password check and wait for the second factor. Kept in
+/// memory: a restart means logging in again.
+#[derive(Debug, Default)]
+pub struct MfaChallenges {
+    challenges: Mutex<HashMap<String, Challenge>>,
+}
+
+impl MfaChallenges {
+    /// Starts a second step for `email` and returns the token that continues it.
+    pub fn issue(&self, email: &str, kind: ChallengeKind) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let mut challenges = self.lock();
+        let now = Instant::now();
+        challenges.retain(|_, c| c.expires > now);
+        challenges.insert(
+            token.clone(),
+            Challenge {
+                email: email.to_string(),
+                kind,
+                expires: now + CHALLENGE_TTL,
+                attempts: 0,
+            },
+        );
+        token
+    }
+
+    /// The user and kind of a pending challenge.
+    pub fn get(&self, token: &str) -> Result<(String, ChallengeKind)> {
+        let mut challenges = self.lock();
+        match challenges.get(token) {
+            Some(c) if c.expires > Instant::now() => Ok((c.email.clone(), c.kind)),
+            Some(_) => {
+                challenges.remove(token);
+                Err(AppError::Auth("Login expired, sign in again".to_string()))
+            }
+            None => Err(AppError::Auth("Invalid or expired login".to_string())),
+        }
+    }
+
+    /// Counts a wrong code; the challenge is dropped once it has used all its attempts.
+    pub fn fail(&self, token: &str) {
+        let mut challenges = self.lock();
+        if let Some(c) = challenges.get_mut(token) {
+            c.attempts += 1;
+            if c.attempts >= CHALLENGE_ATTEMPTS {
+                challenges.remove(token);
+            }
+        }
+    }
+
+    pub fn complete(&self, token: &str) {
+        self.lock().remove(token);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Challenge>> {
+        self.challenges.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "someone@example.com";
+
+    fn current_step() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / STEP
+    }
+
+    fn code_at(config: &TotpConfig, step: u64) -> String {
+        config.totp(EMAIL).unwrap().generate(step * STEP)
+    }
+
+    #[test]
+    fn codes_within_the_drift_are_accepted() {
+        let now = current_step();
+        for step in [now - DRIFT, now, now + DRIFT] {
+            let mut config = TotpConfig::generate();
+            assert!(config.verify(EMAIL, &code_at(&config, step)).unwrap(), "step {}", step);
+        }
+    }
+
+    #[test]
+    fn codes_outside_the_drift_are_refused() {
+        let now = current_step();
+        for step in [now - DRIFT - 2, now + DRIFT + 2] {
+            let mut config = TotpConfig::generate();
+            assert!(!config.verify(EMAIL, &code_at(&config, step)).unwrap(), "step {}", step);
+        }
+    }
+
+    #[test]
+    fn a_code_cannot_be_replayed() {
+        let mut config = TotpConfig::generate();
+        let code = code_at(&config, current_step());
+        assert!(config.verify(EMAIL, &code).unwrap());
+        assert!(!config.verify(EMAIL, &code).unwrap());
+    }
+
+    #[test]
+    fn codes_older_than_the_last_accepted_one_are_refused() {
+        let now = current_step();
+        let mut config = TotpConfig::generate();
+        assert!(config.verify(EMAIL, &code_at(&config, now)).unwrap());
+        assert!(!config.verify(EMAIL, &code_at(&config, now - 1)).unwrap());
+        assert!(config.verify(EMAIL, &code_at(&config, now + 1)).unwrap());
+    }
+
+    #[test]
+    fn spaces_in_codes_are_ignored() {
+        let mut config = TotpConfig::generate();
+        let code = code_at(&config, current_step());
+        assert!(config.verify(EMAIL, &format!("{} {}", &code[..3], &code[3..])).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_work_once_in_any_case() {
+        let mut config = TotpConfig::generate();
+        let codes = config.new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(config.use_recovery_code(&codes[0].to_uppercase().replace('-', "")));
+        assert!(!config.use_recovery_code(&codes[0]));
+        assert!(config.use_recovery_code(&codes[1]));
+        assert!(!config.use_recovery_code("00000-00000"));
+    }
+
+    #[test]
+    fn challenges_are_dropped_after_too_many_attempts() {
+        let challenges = MfaChallenges::default();
+        let token = challenges.issue(EMAIL, ChallengeKind::Verify);
+        for _ in 0..CHALLENGE_ATTEMPTS - 1 {
+            challenges.fail(&token);
+        }
+        assert_eq!(challenges.get(&token).unwrap(), (EMAIL.to_string(), ChallengeKind::Verify));
+        challenges.fail(&token);
+        assert!(challenges.get(&token).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use bcrypt::{hash, verify, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
 use crate::error::AppError;
+use crate::service::storage::Storage;
+use crate::service::oidc::SsoIdentity;
+use crate::service::policy::AuthPolicy;
+use crate::service::totp::{ChallengeKind, Enrollment, TotpConfig};
+use std::sync::{Arc, OnceLock};
 
 
 type Result<T> = std::result::Result<T, AppError>;
@@ -11,6 +14,19 @@ type Result<T> = std::result::Result<T, AppError>;
 pub struct User {
     pub email: String,
     pub password: String,
+    /// Two-factor authentication, when the user has set it up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpConfig>,
+    /// The identity provider account this user signs in with. Users created by single
+    /// sign-on have no password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sso: Option<SsoLink>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct SsoLink {
+    pub issuer: String,
+    pub subject: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,9 +35,35 @@ pub struct LoginRequest {
     pub password: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Either the JWT, or when a second factor is due, the token to continue with at
+/// `/api/login/2fa`.
+#[derive(Debug, Default, Serialize)]
 pub struct LoginResponse {
-    pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa: Option<ChallengeKind>,
+    /// Set when the login enrolled a new authenticator; shown only this once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecondFactorRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaTokenRequest {
+    pub mfa_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,69 +72,284 @@ pub struct ChangePasswordRequest {
     pub new_password: String,
 }
 
+/// A hash of nothing in particular, at the cost real ones use.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash(b"longshoreman-dummy-password", DEFAULT_COST).unwrap_or_default())
+}
+
 #[derive(Debug)]
 pub struct UserManager {
-    users: Vec<User>,
-    file_path: String,
+    storage: Arc<dyn Storage>,
 }
 
 impl UserManager {
-    pub fn new(file_path: &str) -> Result<Self> {
-        let users = if Path::new(file_path).exists() {
-            let contents = fs::read_to_string(file_path)?;
-            serde_json::from_str(&contents)?
-        } else {
-            Vec::new()
-        };
-
-        Ok(Self {
-            users,
-            file_path: file_path.to_string(),
-        })
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
     }
 
-    pub fn save(&self) -> Result<()> {
-        let contents = serde_json::to_string_pretty(&self.users)?;
-        fs::write(&self.file_path, contents)?;
-        Ok(())
-    }
-
-    pub fn create_user(&mut self, email: &str, password: &str) -> Result<()> {
-        if self.users.iter().any(|u| u.email == email) {
-            return Err(AppError::User("User already exists".to_string()));
-        }
-
+    pub async fn create_user(&self, email: &str, password: &str) -> Result<()> {
         let password_hash = hash(password.as_bytes(), DEFAULT_COST)?;
-        self.users.push(User {
-            email: email.to_string(),
-            password: password_hash.to_string(),
-        });
-        self.save()?;
-        Ok(())
+        self.storage
+            .user_transaction(|users| {
+                if users.iter().any(|u| u.email == email) {
+                    return Err(AppError::User("User already exists".to_string()));
+                }
+                users.push(User {
+                    email: email.to_string(),
+                    password: password_hash,
+                    totp: None,
+                    sso: None,
+                });
+                Ok(())
+            })
+            .await
     }
 
-    pub fn verify_user(&self, email: &str, password: &str) -> Result<bool> {
-        if let Some(user) = self.users.iter().find(|u| u.email == email) {
-            Ok(verify(password.as_bytes(), &user.password)?)
-        } else {
-            Ok(false)
+    /// Takes as long for an unknown email as for a wrong password, so response times do
+    /// not give away which accounts exist.
+    pub async fn verify_user(&self, email: &str, password: &str) -> Result<bool> {
+        let users = self.storage.users().await?;
+        match users.iter().find(|u| u.email == email && !u.password.is_empty()) {
+            Some(user) => Ok(verify(password.as_bytes(), &user.password)?),
+            None => {
+                verify(password.as_bytes(), dummy_hash())?;
+                Ok(false)
+            }
         }
     }
 
-    pub fn change_password(&mut self, email: &str, old_password: &str, new_password: &str) -> Result<()> {
+    pub async fn change_password(
+        &self,
+        email: &str,
+        old_password: &str,
+        new_password: &str,
+        policy: &AuthPolicy,
+    ) -> Result<()> {
         // Find user and verify old password
-        let user = self.users.iter_mut()
+        let current_hash = self.storage.users().await?
+            .iter()
             .find(|u| u.email == email)
+            .map(|u| u.password.clone())
             .ok_or_else(|| AppError::User("User not found".to_string()))?;
+        if current_hash.is_empty() {
+            return Err(AppError::User("This user signs in with single sign-on and has no password".to_string()));
+        }
 
-        if !verify(old_password.as_bytes(), &user.password)? {
+        if !verify(old_password.as_bytes(), &current_hash)? {
             return Err(AppError::User("Invalid old password".to_string()));
         }
+        if new_password == old_password {
+            return Err(AppError::User("The new password must differ from the old one".to_string()));
+        }
+        policy.check_password(email, new_password)?;
 
-        // Hash new password and update
+        // Hash new password and update, unless it changed while we were hashing
         let new_password_hash = hash(new_password.as_bytes(), DEFAULT_COST)?;
-        user.password = new_password_hash;
-        self.save()?;
+        self.storage
+            .user_transaction(|users| {
+                let user = users
+                    .iter_mut()
+                    .find(|u| u.email == email && u.password == current_hash)
+                    .ok_or_else(|| AppError::User("Password was changed concurrently".to_string()))?;
+                user.password = new_password_hash;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Fails unless `email` is a user that signs in with a password.
+    pub async fn require_password_user(&self, email: &str) -> Result<()> {
+        let users = self.storage.users().await?;
+        match users.iter().find(|u| u.email == email) {
+            Some(user) if user.password.is_empty() => Err(AppError::User(
+                "This user signs in with single sign-on and has no password".to_string(),
+            )),
+            Some(_) => Ok(()),
+            None => Err(AppError::User("User not found".to_string())),
+        }
+    }
+
+    /// Checks a new password for a reset, which needs no old one, and returns its hash
+    /// for [`UserManager::set_password_hash`]. Nothing is changed, so a password that is
+    /// refused does not use up the reset token.
+    pub async fn check_reset_password(&self, email: &str, new_password: &str, policy: &AuthPolicy) -> Result<String> {
+        policy.check_password(email, new_password)?;
+        let current_hash = self
+            .storage
+            .users()
+            .await?
+            .iter()
+            .find(|u| u.email == email)
+            .map(|u| u.password.clone())
+            .ok_or_else(|| AppError::User("User not found".to_string()))?;
+        if current_hash.is_empty() {
+            return Err(AppError::User("This user signs in with single sign-on and has no password".to_string()));
+        }
+        if verify(new_password.as_bytes(), &current_hash)? {
+            return Err(AppError::User("The new password must differ from the old one".to_string()));
+        }
+        Ok(hash(new_password.as_bytes(), DEFAULT_COST)?)
+    }
+
+    /// Sets a password checked by [`UserManager::check_reset_password`].
+    pub async fn set_password_hash(&self, email: &str, password_hash: String) -> Result<()> {
+        self.storage
+            .user_transaction(|users| {
+                find_user(users, email)?.password = password_hash;
+                Ok(())
+            })
+            .await
+    }
+
+    /// The user a single sign-on login is for: the one linked to the provider account,
+    /// else an existing user with the same (verified) email, who is linked to it, else
+    /// a new user when `auto_provision` allows. Returns the user's email.
+    pub async fn sso_login(&self, identity: &SsoIdentity, auto_provision: bool) -> Result<String> {
+        self.storage
+            .user_transaction(|users| {
+                let linked = users.iter().position(|u| {
+                    u.sso
+                        .as_ref()
+                        .is_some_and(|l| l.issuer == identity.issuer && l.subject == identity.subject)
+                });
+                if let Some(i) = linked {
+                    return Ok(users[i].email.clone());
+                }
+
+                let link = SsoLink {
+                    issuer: identity.issuer.clone(),
+                    subject: identity.subject.clone(),
+                };
+                if let Some(user) = users.iter_mut().find(|u| u.email == identity.email) {
+                    if !identity.email_verified {
+                        return Err(AppError::Auth(format!(
+                            "The identity provider has not verified {}, so it cannot sign in as that user",
+                            identity.email
+                        )));
+                    }
+                    if user.sso.is_some() {
+                        return Err(AppError::Auth(format!(
+                            "{} is already linked to another identity provider account",
+                            identity.email
+                        )));
+                    }
+                    user.sso = Some(link);
+                    return Ok(user.email.clone());
+                }
+
+                if !auto_provision {
+                    return Err(AppError::Auth(format!("There is no user {}", identity.email)));
+                }
+                users.push(User {
+                    email: identity.email.clone(),
+                    password: String::new(),
+                    totp: None,
+                    sso: Some(link),
+                });
+                Ok(identity.email.clone())
+            })
+            .await
+    }
+
+    /// Whether `email` has a confirmed authenticator.
+    pub async fn has_totp(&self, email: &str) -> Result<bool> {
+        let users = self.storage.users().await?;
+        Ok(users
+            .iter()
+            .any(|u| u.email == email && u.totp.as_ref().is_some_and(|t| t.confirmed)))
+    }
+
+    /// Gives the user a new, unconfirmed authenticator secret, replacing any earlier
+    /// unconfirmed one. Refused while a confirmed one is in use.
+    pub async fn start_totp_enrollment(&self, email: &str) -> Result<Enrollment> {
+        let config = TotpConfig::generate();
+        let enrollment = config.enrollment(email)?;
+        self.storage
+            .user_transaction(|users| {
+                let user = find_user(users, email)?;
+                if user.totp.as_ref().is_some_and(|t| t.confirmed) {
+                    return Err(AppError::User("Two-factor authentication is already enabled".to_string()));
+                }
+                user.totp = Some(config);
+                Ok(())
+            })
+            .await?;
+        Ok(enrollment)
+    }
+
+    /// Turns on the pending authenticator once `code` shows it works. Returns the
+    /// recovery codes, which are not stored in the clear.
+    pub async fn confirm_totp(&self, email: &str, code: &str) -> Result<Vec<String>> {
+        self.storage
+            .user_transaction(|users| {
+                let user = find_user(users, email)?;
+                let totp = match user.totp.as_mut() {
+                    Some(totp) if !totp.confirmed => totp,
+                    Some(_) => return Err(AppError::User("Two-factor authentication is already enabled".to_string())),
+                    None => return Err(AppError::User("Start enrolling an authenticator first".to_string())),
+                };
+                if !totp.verify(email, code)? {
+                    return Err(AppError::Auth("Invalid authentication code".to_string()));
+                }
+                totp.confirmed = true;
+                Ok(totp.new_recovery_codes())
+            })
+            .await
+    }
+
+    /// Checks a code from the user's authenticator, or one of their recovery codes,
+    /// which is used up by it.
+    pub async fn verify_second_factor(&self, email: &str, code: &str) -> Result<bool> {
+        self.storage
+            .user_transaction(|users| {
+                let user = find_user(users, email)?;
+                let totp = user
+                    .totp
+                    .as_mut()
+                    .filter(|t| t.confirmed)
+                    .ok_or_else(|| AppError::User("Two-factor authentication is not enabled".to_string()))?;
+                Ok(totp.verify(email, code)? || totp.use_recovery_code(code))
+            })
+            .await
+    }
+
+    /// Removes the authenticator, after checking `code` against it.
+    pub async fn disable_totp(&self, email: &str, code: &str) -> Result<()> {
+        self.require_second_factor(email, code).await?;
+        self.storage
+            .user_transaction(|users| {
+                find_user(users, email)?.totp = None;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Replaces the recovery codes, after checking `code` against the authenticator.
+    pub async fn regenerate_recovery_codes(&self, email: &str, code: &str) -> Result<Vec<String>> {
+        self.require_second_factor(email, code).await?;
+        self.storage
+            .user_transaction(|users| {
+                let totp = find_user(users, email)?
+                    .totp
+                    .as_mut()
+                    .ok_or_else(|| AppError::User("Two-factor authentication is not enabled".to_string()))?;
+                Ok(totp.new_recovery_codes())
+            })
+            .await
+    }
+
+    async fn require_second_factor(&self, email: &str, code: &str) -> Result<()> {
+        if !self.verify_second_factor(email, code).await? {
+            return Err(AppError::Auth("Invalid authentication code".to_string()));
+        }
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+fn find_user<'a>(users: &'a mut [User], email: &str) -> Result<&'a mut User> {
+    users
+        .iter_mut()
+        .find(|u| u.email == email)
+        .ok_or_else(|| AppError::User("User not found".to_string()))
+}